@@ -0,0 +1,193 @@
+use crate::jukebox::Voice;
+use std::{collections::HashMap, time::Duration};
+
+/// Pipeline stage that merges voices to play fewer of them, e.g. the [Aggregator] or the
+/// [crate::quantizer::Quantizer].
+pub(crate) trait Aggregate {
+    /// Feed a voice produced by event of `kind`, push voices to play now into `out`.
+    fn process(&mut self, kind: &'static str, voice: Voice, out: &mut Vec<Voice>);
+
+    /// Push voices that are due at `now` into `out`, should be called regularly.
+    fn flush_expired(&mut self, now: Duration, out: &mut Vec<Voice>);
+
+    /// Get the number of voices that were not played on their own since the last call.
+    fn fetch_merged(&mut self) -> usize;
+}
+
+/// Pipeline stage that protects the mixer from probes sending more events than we can play.
+///
+/// Time is split into windows of fixed length, separately for each event kind. The first
+/// `threshold` events of a kind in a window are played as they are. Any events above that are
+/// only counted and once the window closes, they are replaced by a single voice in the middle of
+/// the window whose gain grows with their count. So a misbehaving probe makes a louder noise, but
+/// it doesn't saturate the mixer.
+///
+/// Events kept by probe-side sampling count as many events as their weight, both towards the
+/// threshold and in merged voices, and all voices follow the same [loudness()] law. So a sampled
+/// stream sounds as loud as the full stream it stands for.
+pub(crate) struct Aggregator {
+    window: Duration,
+    threshold: f32,
+    windows: HashMap<&'static str, Window>,
+    /// Number of voices merged into aggregated ones since the last [Aggregate::fetch_merged()].
+    merged: usize,
+}
+
+/// Gain of voices standing for many events is clamped to this range.
+const MIN_GAIN: f32 = 0.25;
+const MAX_GAIN: f32 = 2.0;
+
+/// Gain multiplier of a voice standing for `weight` events: the power of the voice grows with the
+/// number of events, as if they were played one by one, until [MAX_GAIN].
+fn loudness(weight: f32) -> f32 {
+    weight.sqrt().clamp(MIN_GAIN, MAX_GAIN)
+}
+
+struct Window {
+    index: u128,
+    /// Total weight of the voices played as they are.
+    played: f32,
+    /// The last voice that didn't fit into the threshold with the total weight of such voices, and
+    /// their number.
+    overflow: Option<(Voice, usize)>,
+}
+
+impl Aggregator {
+    pub(crate) fn new(window: Duration, threshold: usize) -> Self {
+        assert!(!window.is_zero(), "aggregation window must be positive");
+        let threshold = threshold.max(1) as f32;
+        Self { window, threshold, windows: HashMap::new(), merged: 0 }
+    }
+
+    fn close(&mut self, kind: &'static str) -> Option<Voice> {
+        let window = self.windows.remove(kind)?;
+        let (voice, count) = window.overflow?;
+        self.merged += count - 1;
+
+        let start = Duration::from_nanos((window.index * self.window.as_nanos()) as u64);
+        Some(Voice {
+            timestamp: start + self.window / 2,
+            gain: voice.gain * loudness(voice.weight),
+            ..voice
+        })
+    }
+}
+
+impl Aggregate for Aggregator {
+    fn process(&mut self, kind: &'static str, voice: Voice, out: &mut Vec<Voice>) {
+        let index = voice.timestamp.as_nanos() / self.window.as_nanos();
+        // Events are mostly monotonic, so an event in a different window closes the current one.
+        // Occasional out-of-order events just open a new window.
+        if self.windows.get(kind).is_some_and(|window| window.index != index) {
+            out.extend(self.close(kind));
+        }
+        let window = self.windows.entry(kind).or_insert_with(|| Window {
+            index,
+            played: 0.0,
+            overflow: None,
+        });
+
+        if window.played < self.threshold {
+            window.played += voice.weight;
+            let gain = voice.gain * loudness(voice.weight);
+            out.push(Voice { gain, ..voice });
+        } else {
+            let (weight, count) = window
+                .overflow
+                .as_ref()
+                .map_or((0.0, 0), |(overflow, count)| (overflow.weight, *count));
+            window.overflow = Some((Voice { weight: weight + voice.weight, ..voice }, count + 1));
+        }
+    }
+
+    /// Close windows that ended at least one window length before `now`, push their aggregated
+    /// voices into `out`.
+    fn flush_expired(&mut self, now: Duration, out: &mut Vec<Voice>) {
+        let current_index = now.as_nanos() / self.window.as_nanos();
+        let expired: Vec<_> = self
+            .windows
+            .iter()
+            .filter(|(_, window)| window.index + 1 < current_index)
+            .map(|(&kind, _)| kind)
+            .collect();
+
+        for kind in expired {
+            out.extend(self.close(kind));
+        }
+    }
+
+    fn fetch_merged(&mut self) -> usize {
+        std::mem::take(&mut self.merged)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn voice(timestamp_ms: u64) -> Voice {
+        Voice::new("TestTick", "click".parse().unwrap(), Duration::from_millis(timestamp_ms))
+    }
+
+    #[test]
+    fn passes_through_events_below_threshold() {
+        let mut aggregator = Aggregator::new(Duration::from_millis(10), 3);
+        let mut out = Vec::new();
+        for timestamp_ms in [1, 2, 3, 11, 12, 13] {
+            aggregator.process("TestTick", voice(timestamp_ms), &mut out);
+        }
+        aggregator.flush_expired(Duration::from_secs(1), &mut out);
+        assert_eq!(out.len(), 6);
+        assert!(out.iter().all(|voice| voice.gain == 1.0));
+    }
+
+    #[test]
+    fn aggregates_bursts_into_single_voice() {
+        let mut aggregator = Aggregator::new(Duration::from_millis(10), 2);
+        let mut out = Vec::new();
+        for _ in 0..10 {
+            aggregator.process("TestTick", voice(21), &mut out);
+        }
+        aggregator.process("StdoutWrite", voice(22), &mut out);
+        assert_eq!(out.len(), 3);
+
+        // Window 20..30 ms is still open at 35 ms, closed at 40 ms.
+        aggregator.flush_expired(Duration::from_millis(35), &mut out);
+        assert_eq!(out.len(), 3);
+        aggregator.flush_expired(Duration::from_millis(40), &mut out);
+        assert_eq!(out.len(), 4);
+
+        assert_eq!(out[3].timestamp, Duration::from_millis(25));
+        assert_eq!(out[3].gain, MAX_GAIN);
+        assert_eq!(aggregator.fetch_merged(), 7);
+        assert_eq!(aggregator.fetch_merged(), 0);
+    }
+
+    #[test]
+    fn later_event_closes_window() {
+        let mut aggregator = Aggregator::new(Duration::from_millis(10), 1);
+        let mut out = Vec::new();
+        for timestamp_ms in [1, 2, 3, 4, 5, 15] {
+            aggregator.process("TestTick", voice(timestamp_ms), &mut out);
+        }
+        let timestamps: Vec<_> = out.iter().map(|voice| voice.timestamp.as_millis()).collect();
+        assert_eq!(timestamps, [1, 5, 15]);
+        assert_eq!(out[1].gain, 2.0);
+    }
+
+    #[test]
+    fn sampled_streams_are_as_loud_as_full_ones() {
+        // 100 events in a window over a threshold of 20, as they are and sampled 1-in-4.
+        let power = |weight, count| {
+            let mut aggregator = Aggregator::new(Duration::from_millis(10), 20);
+            let mut out = Vec::new();
+            for _ in 0..count {
+                aggregator.process("TestTick", Voice { weight, ..voice(1) }, &mut out);
+            }
+            aggregator.flush_expired(Duration::from_secs(1), &mut out);
+            out.iter().map(|voice| voice.gain * voice.gain).sum::<f32>()
+        };
+        assert_eq!(power(1.0, 100), 24.0);
+        assert_eq!(power(4.0, 25), 24.0);
+    }
+}
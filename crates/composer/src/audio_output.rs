@@ -1,39 +1,112 @@
+use crate::{
+    dashboard::report,
+    effects::{Chain, Effects},
+    histogram::{AtomicHistogram, Histogram},
+    mixer::{Mixer, SampleBank, VoiceDescriptor, BLOCK_FRAMES},
+    spsc::{self, Consumer, Producer},
+};
 use composer_api::util::current_timestamp;
 use cpal::{
     traits::{DeviceTrait, HostTrait},
     OutputCallbackInfo, OutputStreamTimestamp, SampleFormat,
 };
 use eyre::{bail, eyre, Result};
-use rodio::{
-    dynamic_mixer::{DynamicMixer, DynamicMixerController},
-    Source,
-};
 use std::{
+    cell::RefCell,
     sync::{
-        atomic::{AtomicU64, Ordering},
-        mpsc::{channel, Receiver, Sender, TryRecvError},
+        atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering},
         Arc,
     },
+    thread,
     time::Duration,
 };
 
 pub(crate) struct AudioOutput {
-    source_tx: Sender<TimedSource>,
+    command_tx: Producer<Command>,
+    garbage_rx: RefCell<Consumer<Garbage>>,
+    sample_rate: u32,
+    channels: usize,
     play_delay: Duration,
-    too_early_plays: Arc<AtomicU64>,
+    counters: Arc<Counters>,
+    meters: Meters,
     _stream: cpal::Stream,
 }
 
+/// Live measurements of the audio output. Cheap to clone, can be sent to other threads.
+#[derive(Clone)]
+pub(crate) struct Meters {
+    active_voices: Arc<AtomicUsize>,
+    /// Peak absolute sample value of each channel, as `f32` bits.
+    peaks: Arc<[AtomicU32]>,
+}
+
+impl Meters {
+    fn new(channels: u16) -> Self {
+        let peaks = (0..channels).map(|_| AtomicU32::new(0)).collect();
+        Self { active_voices: Arc::default(), peaks }
+    }
+
+    /// Meters of no audio output at all, with no channels and no voices.
+    pub(crate) fn silent() -> Self {
+        Self::new(0)
+    }
+
+    /// Number of voices scheduled or playing in the mixer.
+    pub(crate) fn active_voices(&self) -> usize {
+        self.active_voices.load(Ordering::Relaxed)
+    }
+
+    /// Get the peak level of each channel since the last call of this method, 1.0 is full scale.
+    pub(crate) fn fetch_peaks(&self) -> Vec<f32> {
+        self.peaks.iter().map(|peak| f32::from_bits(peak.swap(0, Ordering::Relaxed))).collect()
+    }
+
+    fn record_peaks(&self, data: &[f32]) {
+        for (channel, peak) in self.peaks.iter().enumerate() {
+            let max = data
+                .iter()
+                .skip(channel)
+                .step_by(self.peaks.len())
+                .fold(0f32, |max, sample| max.max(sample.abs()));
+            // Bit patterns of non-negative floats are ordered the same as the floats themselves.
+            peak.fetch_max(max.to_bits(), Ordering::Relaxed);
+        }
+    }
+}
+
 /// Abstraction to actually produce sound using the [AudioOutput::play()] method.
 /// Uses `cpal` and `rodio` behind the curtains. Great care is taken to position played samples
-/// precisely in time so that sound superposition works well even at high frequencies.
+/// precisely in time so that sound superposition works well even at high frequencies, and the
+/// audio thread never allocates, locks or frees memory, see [Mixer].
 /// Playback stops when this struct is dropped.
 impl AudioOutput {
-    pub(crate) fn new(play_delay: Duration) -> Result<Self> {
+    /// Most commands waiting for the audio thread.
+    const COMMANDS: usize = 4096;
+    const GARBAGE: usize = 16;
+    /// Most voices scheduled or playing at once.
+    pub(crate) const VOICES: usize = 4096;
+
+    /// Open the default output device, with given number of `channels` if set.
+    pub(crate) fn new(play_delay: Duration, channels: Option<u16>) -> Result<Self> {
         let cpal_device = cpal::default_host()
             .default_output_device()
             .ok_or_else(|| eyre!("no cpal audio output device found"))?;
-        let supported_config = cpal_device.default_output_config()?;
+        let default_config = cpal_device.default_output_config()?;
+        let supported_config = match channels {
+            Some(channels) if channels != default_config.channels() => {
+                let range = cpal_device
+                    .supported_output_configs()?
+                    .filter(|range| range.sample_format() == SampleFormat::F32)
+                    .find(|range| range.channels() == channels)
+                    .ok_or_else(|| eyre!("audio device does not support {channels} channels"))?;
+                // Prefer the default sample rate if the device supports it with more channels.
+                let sample_rate = default_config
+                    .sample_rate()
+                    .clamp(range.min_sample_rate(), range.max_sample_rate());
+                range.with_sample_rate(sample_rate)
+            },
+            _ => default_config,
+        };
         let stream_config = supported_config.config();
         println!(
             "Using audio device '{}', supported config {:?}, stream config {:?}.",
@@ -45,73 +118,169 @@ impl AudioOutput {
             bail!("Only F32 sample format supported for now.");
         }
 
-        let (mixer_controller, mixer) =
-            rodio::dynamic_mixer::mixer::<f32>(stream_config.channels, stream_config.sample_rate.0);
-
-        let (source_tx, source_rx) = channel();
+        let sample_rate = stream_config.sample_rate.0;
+        let channels = stream_config.channels;
+        let chain = Effects::default().chain(sample_rate, channels as usize, BLOCK_FRAMES);
+        let mixer = Mixer::new(sample_rate, channels, Self::VOICES, Arc::new([]), Box::new(chain));
+        let (command_tx, command_rx) = spsc::channel(Self::COMMANDS);
+        let (garbage_tx, garbage_rx) = spsc::channel(Self::GARBAGE);
 
-        let too_early_plays = Arc::default();
+        let counters = Arc::default();
+        let meters = Meters::new(stream_config.channels);
 
-        // The mixer_controller can be shared between threads, but we want to precisely control
-        // when we add new sources w.r.t. the audio callback, so we move it to the audio thread and
-        // use a mpsc channel to send new sources to the audio thread.
+        // The mixer lives in the audio thread, voices and configuration are sent to it through a
+        // lock-free queue, and replaced configuration is sent back to be freed here.
         let mut audio_callback =
-            AudioCallback::new(mixer_controller, mixer, source_rx, &too_early_plays);
+            AudioCallback::new(mixer, sample_rate, command_rx, garbage_tx, &counters, &meters);
         let _stream = cpal_device.build_output_stream::<f32, _, _>(
             &stream_config,
             move |data_out, info| audio_callback.fill_data(data_out, info),
-            |err| eprintln!("Got cpal stream error callback: {err}."),
+            |err| report(format!("Got cpal stream error callback: {err}.")),
             None,
         )?;
 
-        Ok(Self { source_tx, play_delay, too_early_plays, _stream })
+        let channels = channels as usize;
+        Ok(Self {
+            command_tx,
+            garbage_rx: RefCell::new(garbage_rx),
+            sample_rate,
+            channels,
+            play_delay,
+            counters,
+            meters,
+            _stream,
+        })
+    }
+
+    /// Play a voice at its timestamp plus the play delay. The voice is dropped if there are too
+    /// many, see [Self::fetch_dropped_voices()].
+    pub(crate) fn play(&self, descriptor: VoiceDescriptor) {
+        self.collect_garbage();
+        let descriptor =
+            VoiceDescriptor { timestamp: descriptor.timestamp + self.play_delay, ..descriptor };
+        if self.command_tx.push(Command::Play(descriptor)).is_err() {
+            self.counters.dropped_voices.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Replace the samples voices played from now on refer to, see
+    /// [crate::jukebox::Jukebox::bank()].
+    pub(crate) fn set_samples(&self, samples: SampleBank) {
+        self.send(Command::SetSamples(samples));
+    }
+
+    /// Replace the effects of event kinds and of the mix.
+    pub(crate) fn set_effects(&self, effects: &Effects) {
+        // Allocate the effects here so that the audio thread does not have to.
+        let chain = effects.chain(self.sample_rate, self.channels, BLOCK_FRAMES);
+        self.send(Command::SetChain(Box::new(chain)));
     }
 
-    pub(crate) fn play<S>(&self, source: S, timestamp: Duration)
-    where
-        S: Source<Item = f32> + Send + 'static,
-    {
-        let play_at_timestamp = timestamp + self.play_delay;
+    /// Send a command that must not be dropped, waiting for the audio thread to make room.
+    fn send(&self, mut command: Command) {
+        self.collect_garbage();
+        while let Err(rejected) = self.command_tx.push(command) {
+            command = rejected;
+            thread::sleep(Duration::from_millis(1));
+            self.collect_garbage();
+        }
+    }
 
-        // TODO(Matej): we are in fact double-boxing because DynamicMixerController internally adds
-        // another box. But we need a sized type to send it through threads. We could make this
-        // method non-generic, but that would be less flexible, so just accept it for now.
-        let source = Box::new(source);
+    /// Drop what the audio thread replaced, so that it does not have to.
+    fn collect_garbage(&self) {
+        while let Some(garbage) = self.garbage_rx.borrow_mut().pop() {
+            match garbage {
+                Garbage::Samples(samples) => drop(samples),
+                Garbage::Chain(chain) => drop(chain),
+            }
+        }
+    }
 
-        self.source_tx
-            .send(TimedSource { source, play_at_timestamp })
-            .expect("source receiver should be still alive");
+    /// Get the number of voices dropped since the last call of this method, because there were
+    /// too many at once.
+    pub(crate) fn fetch_dropped_voices(&self) -> u64 {
+        self.counters.dropped_voices.swap(0, Ordering::SeqCst)
     }
 
     /// Get "too early plays" counter since the last call of this method.
     pub(crate) fn fetch_too_early_plays(&self) -> u64 {
-        self.too_early_plays.swap(0, Ordering::SeqCst)
+        self.counters.too_early_plays.swap(0, Ordering::SeqCst)
     }
+
+    /// Get the mean time from event timestamps to their playback since the last call of this
+    /// method. It is the play delay, unless voices arrive too late to be played on time.
+    pub(crate) fn fetch_effective_delay(&self) -> Duration {
+        let plays = self.counters.plays.swap(0, Ordering::SeqCst);
+        let lateness_nanos = self.counters.lateness_nanos.swap(0, Ordering::SeqCst);
+        match lateness_nanos.checked_div(plays) {
+            Some(mean_lateness_nanos) => {
+                self.play_delay + Duration::from_nanos(mean_lateness_nanos)
+            },
+            None => self.play_delay,
+        }
+    }
+
+    /// Get the histogram of scheduling slack since the last call of this method: how long
+    /// before the buffer they are played in voices arrive to the audio thread, negative if late.
+    pub(crate) fn fetch_slack(&self) -> Histogram {
+        self.counters.slack.take()
+    }
+
+    pub(crate) fn meters(&self) -> &Meters {
+        &self.meters
+    }
+}
+
+/// Messages to the audio thread. Replacements of samples and effects go through the same queue as
+/// voices, so that voices are always played with the configuration they were created for.
+// Voices are nearly all commands, boxing them would mean an allocation for each.
+#[allow(clippy::large_enum_variant)]
+enum Command {
+    Play(VoiceDescriptor),
+    SetSamples(SampleBank),
+    SetChain(Box<Chain>),
+}
+
+/// What the audio thread replaced, sent back to be freed outside of it.
+enum Garbage {
+    Samples(SampleBank),
+    Chain(Box<Chain>),
 }
 
-/// An f32 [rodio::source::Source] with UNIX timestamp of desired play time attached.
-struct TimedSource {
-    source: Box<dyn Source<Item = f32> + Send + 'static>,
-    play_at_timestamp: Duration,
+/// Counters updated by the audio callback.
+#[derive(Default)]
+struct Counters {
+    too_early_plays: AtomicU64,
+    plays: AtomicU64,
+    /// Total time by which voices were played later than scheduled, in nanoseconds.
+    lateness_nanos: AtomicU64,
+    /// Voices not played because the command queue or the mixer was full.
+    dropped_voices: AtomicU64,
+    slack: AtomicHistogram,
 }
 
 /// A sort of manual implementation of the closure used as cpal audio data callback, for tidiness.
 struct AudioCallback {
-    mixer_controller: Arc<DynamicMixerController<f32>>,
-    mixer: DynamicMixer<f32>,
-    source_rx: Receiver<TimedSource>,
-    too_early_plays: Arc<AtomicU64>,
+    mixer: Mixer,
+    sample_rate: u32,
+    command_rx: Consumer<Command>,
+    garbage_tx: Producer<Garbage>,
+    counters: Arc<Counters>,
+    meters: Meters,
 }
 
 impl AudioCallback {
     fn new(
-        mixer_controller: Arc<DynamicMixerController<f32>>,
-        mixer: DynamicMixer<f32>,
-        source_rx: Receiver<TimedSource>,
-        too_early_plays: &Arc<AtomicU64>,
+        mixer: Mixer,
+        sample_rate: u32,
+        command_rx: Consumer<Command>,
+        garbage_tx: Producer<Garbage>,
+        counters: &Arc<Counters>,
+        meters: &Meters,
     ) -> Self {
-        let too_early_plays = Arc::clone(too_early_plays);
-        Self { mixer_controller, mixer, source_rx, too_early_plays }
+        let counters = Arc::clone(counters);
+        let meters = meters.clone();
+        Self { mixer, sample_rate, command_rx, garbage_tx, counters, meters }
     }
 
     fn fill_data(&mut self, data_out: &mut [f32], info: &OutputCallbackInfo) {
@@ -124,25 +293,187 @@ impl AudioCallback {
             playback.duration_since(&callback).expect("playback shouldn't be planned in past");
         // ...and by adding it to current unix timestamp we get a unix timestamp of the instant the buffer will be played.
         let playback_unix_timestamp = now + playback_delay;
+        self.render(data_out, playback_unix_timestamp);
+    }
 
-        // Add possible new sources to the list
+    /// Handle received commands and render the buffer played at `playback_unix_timestamp`.
+    fn render(&mut self, data_out: &mut [f32], playback_unix_timestamp: Duration) {
         loop {
-            match self.source_rx.try_recv() {
-                Ok(timed_source) => {
-                    let delay = timed_source
-                        .play_at_timestamp
-                        .checked_sub(playback_unix_timestamp)
-                        .unwrap_or_else(|| {
-                            self.too_early_plays.fetch_add(1, Ordering::SeqCst);
-                            Duration::ZERO
-                        });
-                    self.mixer_controller.add(timed_source.source.delay(delay));
+            match self.command_rx.peek() {
+                None => break,
+                // Replacing produces garbage, wait until there is room to send it back.
+                Some(Command::SetSamples(_) | Command::SetChain(_))
+                    if self.garbage_tx.is_full() =>
+                {
+                    break
+                },
+                Some(_) => {},
+            }
+            match self.command_rx.pop().expect("peeked command should be there") {
+                Command::Play(descriptor) => self.schedule(descriptor, playback_unix_timestamp),
+                Command::SetSamples(samples) => {
+                    if let Some(replaced) = self.mixer.set_samples(samples) {
+                        self.throw_away(Garbage::Samples(replaced));
+                    }
+                },
+                Command::SetChain(chain) => {
+                    let replaced = self.mixer.set_chain(chain);
+                    self.throw_away(Garbage::Chain(replaced));
                 },
-                Err(TryRecvError::Empty) => break,
-                Err(TryRecvError::Disconnected) => panic!("source sender should be still alive"),
+            }
+        }
+        if !self.garbage_tx.is_full() {
+            if let Some(retired) = self.mixer.collect_garbage() {
+                self.throw_away(Garbage::Samples(retired));
+            }
+        }
+
+        self.mixer.render(data_out);
+        self.meters.active_voices.store(self.mixer.voices(), Ordering::Relaxed);
+        self.meters.record_peaks(data_out);
+    }
+
+    /// Put the voice on the timeline of the mixer, the first frame of the buffer being rendered
+    /// is played at `playback_unix_timestamp`.
+    fn schedule(&mut self, descriptor: VoiceDescriptor, playback_unix_timestamp: Duration) {
+        self.counters.plays.fetch_add(1, Ordering::SeqCst);
+        self.counters.slack.record(descriptor.timestamp, playback_unix_timestamp);
+        let delay =
+            descriptor.timestamp.checked_sub(playback_unix_timestamp).unwrap_or_else(|| {
+                self.counters.too_early_plays.fetch_add(1, Ordering::SeqCst);
+                let lateness = playback_unix_timestamp - descriptor.timestamp;
+                self.counters
+                    .lateness_nanos
+                    .fetch_add(lateness.as_nanos() as u64, Ordering::SeqCst);
+                Duration::ZERO
+            });
+        let start_frame =
+            self.mixer.frame() + (delay.as_secs_f64() * self.sample_rate as f64).round() as u64;
+        if !self.mixer.schedule(descriptor, start_frame) {
+            self.counters.dropped_voices.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn throw_away(&self, garbage: Garbage) {
+        // Callers check there is room, so the garbage is never dropped here.
+        if self.garbage_tx.push(garbage).is_err() {
+            unreachable!("garbage queue should have room");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{config::SoundConfig, jukebox::Voice};
+    use std::{
+        alloc::{GlobalAlloc, Layout, System},
+        cell::Cell,
+        path::Path,
+    };
+
+    /// Counts allocations and deallocations of threads that ask for it, see [allocations()].
+    struct CountingAllocator;
+
+    thread_local! {
+        static COUNTING: Cell<bool> = const { Cell::new(false) };
+        static COUNT: Cell<usize> = const { Cell::new(0) };
+    }
+
+    fn count() {
+        // Fails only while the thread is being torn down, when nothing is counted anyway.
+        let _ = COUNTING.try_with(|counting| {
+            if counting.get() {
+                COUNT.with(|count| count.set(count.get() + 1));
+            }
+        });
+    }
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            count();
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            count();
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    /// Number of allocations and deallocations by the current thread while running `f`.
+    fn allocations(f: impl FnOnce()) -> usize {
+        COUNT.with(|count| count.set(0));
+        COUNTING.with(|counting| counting.set(true));
+        f();
+        COUNTING.with(|counting| counting.set(false));
+        COUNT.with(Cell::get)
+    }
+
+    #[test]
+    fn renders_without_allocations() {
+        const SAMPLE_RATE: u32 = 48_000;
+        let SoundConfig { mut jukebox, effects, .. } =
+            SoundConfig::load(Path::new("sounds.toml")).unwrap();
+        let chain = || Box::new(effects.chain(SAMPLE_RATE, 2, BLOCK_FRAMES));
+        let mixer = Mixer::new(SAMPLE_RATE, 2, 64, Arc::new([]), chain());
+        let (command_tx, command_rx) = spsc::channel(256);
+        let (garbage_tx, mut garbage_rx) = spsc::channel(16);
+        let meters = Meters::new(2);
+        let mut callback = AudioCallback::new(
+            mixer,
+            SAMPLE_RATE,
+            command_rx,
+            garbage_tx,
+            &Arc::default(),
+            &meters,
+        );
+
+        // Voices of kinds with and without effects, some spanning a reload of the configuration.
+        let start = Duration::from_secs(1000);
+        let mut commands = vec![Command::SetSamples(jukebox.bank()), Command::SetChain(chain())];
+        for millis in (0..500).step_by(5) {
+            if millis == 250 {
+                commands.push(Command::SetSamples(jukebox.bank()));
+                commands.push(Command::SetChain(chain()));
+            }
+            for (kind, sample) in [("TestTick", "clack"), ("StdoutWrite", "click")] {
+                let timestamp = start + Duration::from_millis(millis);
+                let voice =
+                    Voice { gain: 0.1, ..Voice::new(kind, sample.parse().unwrap(), timestamp) };
+                let descriptor = jukebox.descriptor(&voice, &effects, None).unwrap();
+                commands.push(Command::Play(descriptor));
             }
         }
 
-        data_out.iter_mut().for_each(|d| *d = self.mixer.next().unwrap_or(0f32))
+        let mut data = vec![0.0; 512 * 2];
+        let buffer_duration = Duration::from_secs_f64(512.0 / SAMPLE_RATE as f64);
+        let mut peak = 0f32;
+        let allocations = allocations(|| {
+            let mut commands = commands.drain(..);
+            // A second of audio, voices are sent in batches like the main loop does.
+            for buffer in 0..100 {
+                for command in commands.by_ref().take(4) {
+                    assert!(command_tx.push(command).is_ok());
+                }
+                callback.render(&mut data, start + buffer_duration * buffer);
+                peak = data.iter().fold(peak, |peak, sample| peak.max(sample.abs()));
+            }
+        });
+        assert_eq!(allocations, 0);
+        assert!(peak > 0.01, "{peak}");
+        assert!(commands.is_empty());
+
+        // The replaced empty bank and first effects, and after the reload the first bank and the
+        // second effects came back to be freed outside of the audio thread.
+        let mut garbage = 0;
+        while garbage_rx.pop().is_some() {
+            garbage += 1;
+        }
+        assert_eq!(garbage, 4);
+        assert_eq!(meters.active_voices(), 0);
     }
 }
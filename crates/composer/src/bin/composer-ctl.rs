@@ -0,0 +1,38 @@
+#![warn(clippy::all, clippy::clone_on_ref_ptr)]
+
+use clap::Parser;
+use composer_api::DEFAULT_CONTROL_ADDRESS;
+use eyre::{Context, Result};
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::TcpStream,
+};
+
+/// Send a command to a running composer started with `--control`, print its response. Run with
+/// `help` as the command to list available commands, e.g. `composer-ctl mute kind=TestTick`.
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Address of the composer control channel.
+    #[arg(short, long, default_value = DEFAULT_CONTROL_ADDRESS)]
+    address: String,
+
+    /// The command and its arguments.
+    #[arg(required = true)]
+    command: Vec<String>,
+}
+
+fn main() -> Result<()> {
+    color_eyre::install()?;
+
+    let args = Args::parse();
+    let mut stream = TcpStream::connect(&args.address)
+        .with_context(|| format!("connecting to composer control channel at {}", args.address))?;
+    writeln!(stream, "{}", args.command.join(" "))?;
+
+    let mut response = String::new();
+    BufReader::new(stream).read_line(&mut response)?;
+    print!("{response}");
+
+    Ok(())
+}
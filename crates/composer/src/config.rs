@@ -0,0 +1,243 @@
+use crate::{
+    effects::{Effects, EffectsConfig},
+    jukebox::{Jukebox, Sample, SampleConfig},
+    midi::{MidiConfig, MidiMapping},
+    music::{Music, MusicConfig},
+    spatial::{Spatial, SpatialConfig},
+    Message,
+};
+use composer_api::catalogue;
+use eyre::{bail, ensure, Context, Result};
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::{Path, PathBuf},
+    sync::mpsc::Sender,
+    thread,
+    time::{Duration, SystemTime},
+};
+
+/// Format of the sound configuration file, see `sounds.toml` for an example.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    /// Directory with sample files, relative to the configuration file.
+    #[serde(default)]
+    sample_dir: PathBuf,
+    /// Sample names and their files, or pools of files, within `sample_dir`.
+    samples: BTreeMap<String, SampleConfig>,
+    /// Event kind names and the sample to play for them.
+    #[serde(default)]
+    mapping: BTreeMap<String, String>,
+    /// Musical mode, samples are pitched to notes of a scale when present.
+    music: Option<MusicConfig>,
+    /// Channels and notes of event kinds for MIDI export.
+    midi: Option<MidiConfig>,
+    /// Effects of event kinds and of the mixed output, everything is played dry when missing.
+    #[serde(default)]
+    effects: EffectsConfig,
+    /// Speaker layout and directions of sources for spatial panning, stereo when missing.
+    spatial: Option<SpatialConfig>,
+}
+
+/// Which sample to play for events of each kind.
+#[derive(Debug, Default)]
+pub(crate) struct Mapping {
+    kinds: HashMap<String, Sample>,
+}
+
+impl Mapping {
+    /// Sample for the event kind, `None` if events of the kind should not be played.
+    pub(crate) fn sample_for(&self, kind: &str) -> Option<&Sample> {
+        self.kinds.get(kind)
+    }
+}
+
+/// The sound configuration loaded and validated, ready to be used.
+pub(crate) struct SoundConfig {
+    pub(crate) jukebox: Jukebox,
+    pub(crate) mapping: Mapping,
+    pub(crate) music: Option<Music>,
+    pub(crate) midi: Option<MidiMapping>,
+    pub(crate) effects: Effects,
+    pub(crate) spatial: Option<Spatial>,
+}
+
+impl SoundConfig {
+    /// Load the configuration file at `path` and all samples it refers to.
+    pub(crate) fn load(path: &Path) -> Result<Self> {
+        let config_file = Self::parse(path)?;
+        let sample_dir = path.parent().unwrap_or(Path::new(".")).join(&config_file.sample_dir);
+
+        let mut kinds = HashMap::new();
+        for (kind, sample) in config_file.mapping {
+            check_kind(&kind)?;
+            if !config_file.samples.contains_key(&sample) {
+                bail!("event kind {kind} is mapped to undefined sample {sample:?}");
+            }
+            kinds.insert(kind, sample.parse()?);
+        }
+
+        let samples = config_file
+            .samples
+            .into_iter()
+            .map(|(name, config)| Ok((name.parse()?, config)))
+            .collect::<Result<Vec<_>>>()?;
+        let jukebox = Jukebox::new(samples, &sample_dir)?;
+        let music = config_file.music.map(Music::new).transpose()?;
+        let midi = config_file.midi.map(MidiMapping::new).transpose()?;
+        let effects = Effects::new(config_file.effects)?;
+        let spatial = config_file.spatial.map(Spatial::new).transpose()?;
+
+        Ok(Self { jukebox, mapping: Mapping { kinds }, music, midi, effects, spatial })
+    }
+
+    fn parse(path: &Path) -> Result<ConfigFile> {
+        let contents = fs::read_to_string(path).with_context(|| format!("reading {path:?}"))?;
+        toml::from_str(&contents).with_context(|| format!("parsing {path:?}"))
+    }
+}
+
+/// Fail unless `kind` is a name from the [catalogue] of event kinds, so that typos in the
+/// configuration don't silently mute events.
+pub(crate) fn check_kind(kind: &str) -> Result<()> {
+    ensure!(
+        catalogue::find(kind).is_some(),
+        "unknown event kind {kind:?}, expected one of {} (see --list-event-kinds)",
+        catalogue::names()
+    );
+    Ok(())
+}
+
+/// Fail unless `label` tells events of `kind` apart according to the [catalogue], like `Error`
+/// of `Log`.
+pub(crate) fn check_label(kind: &str, label: &str) -> Result<()> {
+    let labels = catalogue::find(kind).map_or(&[][..], |info| info.labels);
+    ensure!(!labels.is_empty(), "event kind {kind} has no labels like {label:?}");
+    ensure!(
+        labels.contains(&label),
+        "unknown label {label:?} of event kind {kind}, expected one of {}",
+        labels.join(", ")
+    );
+    Ok(())
+}
+
+/// Spawn a thread that checks the configuration file and the sample directory for changes
+/// periodically. On change, it loads the configuration and sends the result to the main thread.
+pub(crate) fn spawn_watcher(path: PathBuf, message_tx: Sender<Message>) {
+    const POLL_EVERY: Duration = Duration::from_millis(500);
+
+    thread::spawn(move || {
+        let mut last_fingerprint = fingerprint(&path);
+        loop {
+            thread::sleep(POLL_EVERY);
+
+            let current_fingerprint = fingerprint(&path);
+            if current_fingerprint == last_fingerprint {
+                continue;
+            }
+            last_fingerprint = current_fingerprint;
+
+            let result = SoundConfig::load(&path).map(Box::new);
+            if message_tx.send(Message::Reload(result)).is_err() {
+                break;
+            }
+        }
+    });
+}
+
+/// Modification times and sizes of the configuration file and files in the sample directory, so
+/// that we can detect their changes. Errors are part of the fingerprint, as missing or unreadable
+/// files are a change too.
+fn fingerprint(path: &Path) -> Vec<(PathBuf, Option<(SystemTime, u64)>)> {
+    let file_fingerprint = |path: PathBuf| {
+        let metadata = fs::metadata(&path).and_then(|m| Ok((m.modified()?, m.len()))).ok();
+        (path, metadata)
+    };
+
+    let mut fingerprint = vec![file_fingerprint(path.to_path_buf())];
+    if let Ok(config_file) = SoundConfig::parse(path) {
+        let sample_dir = path.parent().unwrap_or(Path::new(".")).join(config_file.sample_dir);
+        if let Ok(entries) = fs::read_dir(&sample_dir) {
+            let mut files: Vec<_> = entries.flatten().map(|entry| entry.path()).collect();
+            files.sort();
+            fingerprint.extend(files.into_iter().map(file_fingerprint));
+        }
+    }
+    fingerprint
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Load a configuration from `contents`, in which `SAMPLE_DIR` stands for the directory of
+    /// the shipped samples.
+    pub(crate) fn load_from(contents: &str) -> Result<SoundConfig> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "composer-config-test-{}-{}.toml",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let sample_dir = fs::canonicalize("src/sound_samples")?;
+        fs::write(&path, contents.replace("SAMPLE_DIR", sample_dir.to_str().unwrap()))?;
+        SoundConfig::load(&path)
+    }
+
+    #[test]
+    fn loads_default_config() {
+        let config = SoundConfig::load(Path::new("sounds.toml")).unwrap();
+        assert_eq!(config.mapping.sample_for("TestTick"), Some(&"clack".parse().unwrap()));
+        assert_eq!(config.mapping.sample_for("Log"), None);
+        assert!(config.jukebox.contains(&"click".parse().unwrap()));
+        assert!(config.music.is_some());
+        assert!(config.effects.for_kind("FileSystemRead").is_some());
+        assert!(config.effects.for_kind("StderrWrite").is_none());
+    }
+
+    #[test]
+    fn rejects_invalid_configs() {
+        assert!(
+            load_from("sample_dir = \"SAMPLE_DIR\"\n[samples]\nclick = \"missing.wav\"").is_err()
+        );
+        assert!(load_from(
+            "sample_dir = \"SAMPLE_DIR\"\n[samples]\nclick = \"click.wav\"\n[mapping]\nTestTick = \"clack\""
+        )
+        .is_err());
+        assert!(load_from("[samples]\nclick = \"click.wav\"\n[oops]").is_err());
+        assert!(load_from(
+            "sample_dir = \"SAMPLE_DIR\"\n[samples]\nclick = \"click.wav\"\n[music]\nkey = \"C\"\nscale = \"blues\""
+        )
+        .is_err());
+
+        // Typos in event kinds are caught, labels are only allowed for musical degrees.
+        let typo = load_from(
+            "sample_dir = \"SAMPLE_DIR\"\n[samples]\nclick = \"click.wav\"\n[mapping]\nTestTik = \"click\""
+        );
+        assert!(format!("{:?}", typo.err().unwrap()).contains("unknown event kind \"TestTik\""));
+        assert!(load_from(
+            "sample_dir = \"SAMPLE_DIR\"\n[samples]\nclick = \"click.wav\"\n[mapping]\n\"Log.Error\" = \"click\""
+        )
+        .is_err());
+        assert!(load_from(
+            "sample_dir = \"SAMPLE_DIR\"\n[samples]\nclick = \"click.wav\"\n[music]\nkey = \"C\"\nscale = \"major\"\n[music.degrees]\n\"Log.Error\" = 2"
+        )
+        .is_ok());
+        for degree in ["\"TestTick.Foo\"", "\"Log.Fatal\""] {
+            assert!(load_from(&format!(
+                "sample_dir = \"SAMPLE_DIR\"\n[samples]\nclick = \"click.wav\"\n[music]\nkey = \"C\"\nscale = \"major\"\n[music.degrees]\n{degree} = 2"
+            ))
+            .is_err());
+        }
+
+        // Mistakes in sample pools are pointed out.
+        let pool = load_from(
+            "sample_dir = \"SAMPLE_DIR\"\n[samples]\nclick = { files = [\"click.wav\"], pitch_jiter = 0.1 }"
+        );
+        assert!(format!("{:?}", pool.err().unwrap()).contains("unknown field `pitch_jiter`"));
+        assert!(load_from("sample_dir = \"SAMPLE_DIR\"\n[samples]\nclick = 1").is_err());
+    }
+}
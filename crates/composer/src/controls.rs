@@ -0,0 +1,337 @@
+use crate::{
+    config::check_kind,
+    dashboard::{report, report_error},
+    jukebox::{Sample, Voice},
+    spatial::Sources,
+    Message,
+};
+use eyre::{bail, eyre, Context, Result};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    str::FromStr,
+    sync::mpsc::{self, Sender},
+    thread,
+};
+
+/// What a control command applies to: events of a kind, or events from sources with an address,
+/// in a network or with an address prefix. Written as `kind=<EventKind>` or `source=<sources>`,
+/// e.g. `source=10.0.1.7`, `source=10.0.1.0/24` or `source=10.0.1.`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Target {
+    Kind(String),
+    Source(Sources),
+}
+
+impl Target {
+    fn matches(&self, kind: &str, source: &str) -> bool {
+        match self {
+            Self::Kind(name) => name == kind,
+            Self::Source(sources) => sources.contains(source),
+        }
+    }
+}
+
+impl FromStr for Target {
+    type Err = eyre::Report;
+
+    fn from_str(target: &str) -> Result<Self> {
+        match target.split_once('=') {
+            Some(("kind", name)) => {
+                check_kind(name)?;
+                Ok(Self::Kind(name.to_string()))
+            },
+            Some(("source", sources)) => Ok(Self::Source(sources.parse()?)),
+            _ => bail!("target should be kind=<name> or source=<address>, got {target:?}"),
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Kind(name) => write!(f, "kind={name}"),
+            Self::Source(sources) => write!(f, "source={sources}"),
+        }
+    }
+}
+
+/// A line of the control protocol, see [Command::HELP].
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Command {
+    Mute(Target),
+    Unmute(Target),
+    Solo(Target),
+    Unsolo(Target),
+    Gain(Target, f32),
+    Sound(Target, Sample),
+    Reset,
+    Status,
+    /// Statistics of the last reporting period, as JSON if `json` is set.
+    Stats {
+        json: bool,
+    },
+    Help,
+}
+
+impl Command {
+    pub(crate) const HELP: &'static str = "commands: mute <target>, unmute <target>, \
+        solo <target>, unsolo <target>, gain <target> <factor>, sound <target> <sample>, reset, \
+        status, stats [json], help; target is kind=<EventKind> or source=<address>, where the \
+        address can also be a network like 10.0.1.0/24 or a prefix like 10.0.1.";
+}
+
+impl FromStr for Command {
+    type Err = eyre::Report;
+
+    fn from_str(line: &str) -> Result<Self> {
+        let words: Vec<_> = line.split_whitespace().collect();
+        let command = match words[..] {
+            ["mute", target] => Self::Mute(target.parse()?),
+            ["unmute", target] => Self::Unmute(target.parse()?),
+            ["solo", target] => Self::Solo(target.parse()?),
+            ["unsolo", target] => Self::Unsolo(target.parse()?),
+            ["gain", target, gain] => {
+                let gain = gain
+                    .parse()
+                    .ok()
+                    .filter(|gain: &f32| gain.is_finite() && *gain >= 0.0)
+                    .ok_or_else(|| eyre!("invalid gain {gain:?}, must be a factor of 0 or more"))?;
+                Self::Gain(target.parse()?, gain)
+            },
+            ["sound", target, sample] => Self::Sound(target.parse()?, sample.parse()?),
+            ["reset"] => Self::Reset,
+            ["status"] => Self::Status,
+            ["stats"] => Self::Stats { json: false },
+            ["stats", "json"] => Self::Stats { json: true },
+            ["help"] => Self::Help,
+            _ => bail!("invalid command {line:?}, try help"),
+        };
+        Ok(command)
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+struct Rule {
+    muted: bool,
+    solo: bool,
+    gain: Option<f32>,
+    sample: Option<Sample>,
+}
+
+/// Live adjustments of the mix by event kind or source, changed through the control channel.
+#[derive(Debug, Default)]
+pub(crate) struct Controls {
+    rules: BTreeMap<Target, Rule>,
+}
+
+impl Controls {
+    /// Apply a command that changes the controls, return a textual response. [Command::Stats]
+    /// is not handled here as it needs the composer statistics.
+    pub(crate) fn apply(&mut self, command: Command) -> String {
+        match command {
+            Command::Mute(target) => self.update(target, |rule| rule.muted = true),
+            Command::Unmute(target) => self.update(target, |rule| rule.muted = false),
+            Command::Solo(target) => self.update(target, |rule| rule.solo = true),
+            Command::Unsolo(target) => self.update(target, |rule| rule.solo = false),
+            Command::Gain(target, gain) => {
+                self.update(target, |rule| rule.gain = (gain != 1.0).then_some(gain))
+            },
+            Command::Sound(target, sample) => {
+                self.update(target, |rule| rule.sample = Some(sample))
+            },
+            Command::Reset => {
+                self.rules.clear();
+                "ok".to_string()
+            },
+            Command::Status => self.to_string(),
+            Command::Stats { .. } => "stats are not available".to_string(),
+            Command::Help => Command::HELP.to_string(),
+        }
+    }
+
+    /// Event kinds with rules and whether they are muted and soloed.
+    pub(crate) fn kinds(&self) -> impl Iterator<Item = (&str, bool, bool)> {
+        self.rules.iter().filter_map(|(target, rule)| match target {
+            Target::Kind(kind) => Some((kind.as_str(), rule.muted, rule.solo)),
+            Target::Source(_) => None,
+        })
+    }
+
+    /// Samples played by the rules instead of the configured ones.
+    pub(crate) fn samples(&self) -> impl Iterator<Item = &Sample> {
+        self.rules.values().filter_map(|rule| rule.sample.as_ref())
+    }
+
+    fn update(&mut self, target: Target, update: impl FnOnce(&mut Rule)) -> String {
+        let rule = self.rules.entry(target.clone()).or_default();
+        update(rule);
+        // Keep only rules that do something so that the fast path in adjust() applies.
+        if *rule == Rule::default() {
+            self.rules.remove(&target);
+        }
+        "ok".to_string()
+    }
+
+    /// Adjust a voice of an event of given `kind` from `source` according to the rules. Returns
+    /// `None` if it should not be played at all.
+    pub(crate) fn adjust(&self, kind: &str, source: &str, mut voice: Voice) -> Option<Voice> {
+        if self.rules.is_empty() {
+            return Some(voice);
+        }
+
+        let mut matching = self.rules.iter().filter(|(target, _)| target.matches(kind, source));
+        let any_solo = self.rules.values().any(|rule| rule.solo);
+        if any_solo && !matching.clone().any(|(_, rule)| rule.solo) {
+            return None;
+        }
+
+        for (_, rule) in &mut matching {
+            if rule.muted {
+                return None;
+            }
+            voice.gain *= rule.gain.unwrap_or(1.0);
+            if let Some(sample) = &rule.sample {
+                voice.sample = sample.clone();
+            }
+        }
+        Some(voice)
+    }
+}
+
+impl fmt::Display for Controls {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.rules.is_empty() {
+            return write!(f, "no controls active");
+        }
+
+        let rules = self.rules.iter().map(|(target, rule)| {
+            let mut flags = Vec::new();
+            if rule.muted {
+                flags.push("muted".to_string());
+            }
+            if rule.solo {
+                flags.push("solo".to_string());
+            }
+            if let Some(gain) = rule.gain {
+                flags.push(format!("gain {gain}"));
+            }
+            if let Some(sample) = &rule.sample {
+                flags.push(format!("sound {sample}"));
+            }
+            format!("{target}: {}", flags.join(" "))
+        });
+        write!(f, "{}", rules.collect::<Vec<_>>().join("; "))
+    }
+}
+
+/// Spawn a thread accepting control connections on `address`. Each line received is parsed as a
+/// [Command] and sent to the main thread through `message_tx`, along with a channel for the
+/// response line that is written back to the client.
+pub(crate) fn spawn_server(address: &str, message_tx: Sender<Message>) -> Result<SocketAddr> {
+    let listener = TcpListener::bind(address).with_context(|| format!("binding {address}"))?;
+    let local_address = listener.local_addr()?;
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let message_tx = message_tx.clone();
+            match stream {
+                Ok(stream) => {
+                    thread::spawn(move || {
+                        if let Err(err) = serve_client(stream, message_tx) {
+                            report_error("Control connection failed:", &err);
+                        }
+                    });
+                },
+                Err(err) => report(format!("Could not accept control connection: {err}.")),
+            }
+        }
+    });
+    Ok(local_address)
+}
+
+fn serve_client(stream: TcpStream, message_tx: Sender<Message>) -> Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let response = match line.parse() {
+            Ok(command) => {
+                let (response_tx, response_rx) = mpsc::channel();
+                message_tx.send(Message::Control(command, response_tx))?;
+                response_rx.recv()?
+            },
+            Err(err) => format!("error: {err}"),
+        };
+        writeln!(writer, "{response}")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    fn voice() -> Voice {
+        Voice::new("TestTick", "click".parse().unwrap(), Duration::ZERO)
+    }
+
+    fn apply(controls: &mut Controls, line: &str) {
+        assert_eq!(controls.apply(line.parse().unwrap()), "ok");
+    }
+
+    #[test]
+    fn mutes_and_solos() {
+        let mut controls = Controls::default();
+        apply(&mut controls, "mute kind=TestTick");
+        assert_eq!(controls.adjust("TestTick", "udp://127.0.0.1:1", voice()), None);
+        assert!(controls.adjust("FileSystemRead", "udp://127.0.0.1:1", voice()).is_some());
+
+        apply(&mut controls, "solo source=10.0.0.1");
+        assert_eq!(controls.kinds().collect::<Vec<_>>(), [("TestTick", true, false)]);
+        assert_eq!(controls.adjust("FileSystemRead", "udp://127.0.0.1:1", voice()), None);
+        assert!(controls.adjust("FileSystemRead", "udp://10.0.0.1:5000", voice()).is_some());
+        // Addresses are matched as a whole, not as strings.
+        assert_eq!(controls.adjust("FileSystemRead", "udp://10.0.0.10:5000", voice()), None);
+        assert_eq!(controls.adjust("FileSystemRead", "unix:///tmp/composer.sock", voice()), None);
+        assert_eq!(controls.adjust("TestTick", "udp://10.0.0.1:5000", voice()), None);
+
+        apply(&mut controls, "reset");
+        assert!(controls.adjust("TestTick", "udp://127.0.0.1:1", voice()).is_some());
+    }
+
+    #[test]
+    fn changes_gain_and_sound() {
+        let mut controls = Controls::default();
+        apply(&mut controls, "gain kind=TestTick 0.5");
+        apply(&mut controls, "sound kind=TestTick clack");
+        let voice = controls.adjust("TestTick", "udp://127.0.0.1:1", voice()).unwrap();
+        assert_eq!((voice.gain, voice.sample), (0.5, "clack".parse().unwrap()));
+        assert_eq!(controls.to_string(), "kind=TestTick: gain 0.5 sound clack");
+        assert_eq!(controls.samples().collect::<Vec<_>>(), [&"clack".parse().unwrap()]);
+    }
+
+    #[test]
+    fn rejects_invalid_commands() {
+        for line in [
+            "",
+            "mute",
+            "mute TestTick",
+            "mute kind=Tick",
+            "mute source=udp://10.0.0.1",
+            "mute source=10.0.0.0/33",
+            "gain kind=TestTick loud",
+            "gain kind=TestTick NaN",
+            "gain kind=TestTick inf",
+            "gain kind=TestTick -0.5",
+            "sound kind=A",
+        ] {
+            assert!(line.parse::<Command>().is_err(), "{line:?}");
+        }
+    }
+}
@@ -0,0 +1,431 @@
+//! Terminal dashboard showing what the composer receives and plays, enabled by `--tui`.
+
+use crate::{
+    audio_output::Meters,
+    controls::{Command, Controls, Target},
+    stats::Snapshot,
+    Message,
+};
+use composer_api::catalogue;
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use eyre::Result;
+use ratatui::{
+    layout::{Constraint, Layout, Rect},
+    style::{Color, Style, Stylize},
+    text::Line,
+    widgets::{Block, Gauge, Paragraph, Row, Table, TableState},
+    DefaultTerminal, Frame,
+};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::{
+        mpsc::{self, Receiver, Sender, TryRecvError},
+        Mutex, PoisonError,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Number of reporting periods shown in the rate history of each event kind.
+const HISTORY_LENGTH: usize = 30;
+/// Sources are listed for this long after their last event.
+const SOURCE_TIMEOUT: Duration = Duration::from_secs(10);
+const REDRAW_EVERY: Duration = Duration::from_millis(100);
+/// Level meters show this range of decibels below full scale.
+const METER_RANGE_DB: f32 = 60.0;
+/// How much of the displayed peak level remains after each redraw, so that peaks are visible.
+const PEAK_DECAY: f32 = 0.8;
+
+const SPARKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+const HELP: &str = "↑/↓ select kind, m mute, s solo, r reset controls, q quit";
+
+enum Update {
+    Snapshot(Snapshot),
+    Log(String),
+    /// Event kinds with control rules and whether they are muted and soloed.
+    Controls(Vec<(String, bool, bool)>),
+}
+
+/// Where [report()] sends lines while the dashboard runs.
+static REPORTS: Mutex<Option<Sender<Update>>> = Mutex::new(None);
+
+/// Print a line to stderr, or show it in the dashboard while it runs, as printing would break the
+/// drawing. Can be called from any thread.
+pub(crate) fn report(line: String) {
+    match &*REPORTS.lock().unwrap_or_else(PoisonError::into_inner) {
+        Some(update_tx) => {
+            let _ = update_tx.send(Update::Log(line));
+        },
+        None => eprintln!("{line}"),
+    }
+}
+
+/// Report an error after a line of context, see [report()]. The dashboard shows it on one line.
+pub(crate) fn report_error(line: &str, err: &eyre::Report) {
+    match &*REPORTS.lock().unwrap_or_else(PoisonError::into_inner) {
+        Some(update_tx) => {
+            let _ = update_tx.send(Update::Log(format!("{line} {err:#}")));
+        },
+        None => eprintln!("{line} {err:?}"),
+    }
+}
+
+/// Handle of the dashboard, which runs in its own thread and takes over the terminal. The
+/// terminal is restored when the handle is dropped.
+pub(crate) struct Dashboard {
+    update_tx: Sender<Update>,
+}
+
+impl Dashboard {
+    /// Start drawing the dashboard. Key presses are sent to the main thread as [Message]s.
+    pub(crate) fn start(meters: Meters, message_tx: Sender<Message>) -> Result<Self> {
+        let (update_tx, update_rx) = mpsc::channel();
+        let terminal = ratatui::try_init()?;
+        *REPORTS.lock().unwrap_or_else(PoisonError::into_inner) = Some(update_tx.clone());
+        thread::spawn(move || {
+            let quit_tx = message_tx.clone();
+            if let Err(err) = State::new(meters, message_tx).run(terminal, update_rx) {
+                ratatui::restore();
+                eprintln!("Dashboard failed: {err:?}");
+            }
+            let _ = quit_tx.send(Message::Quit);
+        });
+        Ok(Self { update_tx })
+    }
+
+    pub(crate) fn update(&self, snapshot: Snapshot) {
+        // The dashboard thread only stops when quitting, in which case we don't care.
+        let _ = self.update_tx.send(Update::Snapshot(snapshot));
+    }
+
+    /// Show a line of text in place of printing it, which would break the drawing.
+    pub(crate) fn log(&self, line: String) {
+        let _ = self.update_tx.send(Update::Log(line));
+    }
+
+    /// Show which event kinds are muted and soloed, should be called whenever controls change.
+    pub(crate) fn show_controls(&self, controls: &Controls) {
+        let kinds = controls.kinds().map(|(kind, muted, solo)| (kind.to_string(), muted, solo));
+        let _ = self.update_tx.send(Update::Controls(kinds.collect()));
+    }
+}
+
+impl Drop for Dashboard {
+    fn drop(&mut self) {
+        *REPORTS.lock().unwrap_or_else(PoisonError::into_inner) = None;
+        ratatui::restore();
+    }
+}
+
+#[derive(Default)]
+struct KindState {
+    /// Events per second over the last reporting periods, the most recent last.
+    history: VecDeque<f64>,
+    /// Whether the kind is muted and soloed by [Controls], changed through the dashboard or
+    /// `composer-ctl`.
+    muted: bool,
+    solo: bool,
+}
+
+struct SourceState {
+    rate: f64,
+    last_seen: Instant,
+}
+
+struct State {
+    meters: Meters,
+    message_tx: Sender<Message>,
+    last: Snapshot,
+    kinds: BTreeMap<&'static str, KindState>,
+    sources: BTreeMap<String, SourceState>,
+    peaks: Vec<f32>,
+    log: String,
+    table: TableState,
+}
+
+impl State {
+    fn new(meters: Meters, message_tx: Sender<Message>) -> Self {
+        Self {
+            meters,
+            message_tx,
+            last: Snapshot::default(),
+            kinds: BTreeMap::new(),
+            sources: BTreeMap::new(),
+            peaks: Vec::new(),
+            log: String::new(),
+            table: TableState::new().with_selected(0),
+        }
+    }
+
+    fn run(mut self, mut terminal: DefaultTerminal, update_rx: Receiver<Update>) -> Result<()> {
+        loop {
+            loop {
+                match update_rx.try_recv() {
+                    Ok(Update::Snapshot(snapshot)) => self.update(snapshot),
+                    Ok(Update::Log(line)) => self.log = line,
+                    Ok(Update::Controls(kinds)) => self.update_controls(kinds),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => return Ok(()),
+                }
+            }
+            self.update_peaks();
+            terminal.draw(|frame| self.draw(frame))?;
+
+            if event::poll(REDRAW_EVERY)? {
+                if let Event::Key(key) = event::read()? {
+                    if key.kind == KeyEventKind::Press && !self.handle_key(key) {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    fn update(&mut self, snapshot: Snapshot) {
+        let seconds = snapshot.period.as_secs_f64();
+        for &kind in snapshot.kinds.keys() {
+            self.kinds.entry(kind).or_default();
+        }
+        for (kind, state) in &mut self.kinds {
+            let count = snapshot.kinds.get(kind).copied().unwrap_or(0);
+            state.history.push_back(count as f64 / seconds);
+            if state.history.len() > HISTORY_LENGTH {
+                state.history.pop_front();
+            }
+        }
+
+        let now = Instant::now();
+        for (source, &count) in &snapshot.sources {
+            let state = SourceState { rate: count as f64 / seconds, last_seen: now };
+            self.sources.insert(source.clone(), state);
+        }
+        self.sources.retain(|source, state| {
+            if !snapshot.sources.contains_key(source) {
+                state.rate = 0.0;
+            }
+            now.duration_since(state.last_seen) < SOURCE_TIMEOUT
+        });
+
+        self.last = snapshot;
+    }
+
+    fn update_controls(&mut self, kinds: Vec<(String, bool, bool)>) {
+        for state in self.kinds.values_mut() {
+            (state.muted, state.solo) = (false, false);
+        }
+        for (kind, muted, solo) in kinds {
+            // Rules for kinds not seen yet are shown too, names are from the catalogue.
+            let Some(info) = catalogue::find(&kind) else {
+                continue;
+            };
+            let state = self.kinds.entry(info.name).or_default();
+            (state.muted, state.solo) = (muted, solo);
+        }
+    }
+
+    fn update_peaks(&mut self) {
+        let peaks = self.meters.fetch_peaks();
+        self.peaks.resize(peaks.len(), 0.0);
+        for (displayed, peak) in self.peaks.iter_mut().zip(peaks) {
+            *displayed = peak.max(*displayed * PEAK_DECAY);
+        }
+    }
+
+    /// Handle a key press, returns false if the dashboard should quit.
+    fn handle_key(&mut self, key: KeyEvent) -> bool {
+        let selected = self.table.selected().and_then(|index| self.kinds.iter().nth(index));
+        let command = match (key.code, selected) {
+            (KeyCode::Char('q') | KeyCode::Esc, _) => return false,
+            (KeyCode::Char('c'), _) if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                return false
+            },
+            (KeyCode::Up | KeyCode::Char('k'), _) => {
+                self.table.select_previous();
+                return true;
+            },
+            (KeyCode::Down | KeyCode::Char('j'), _) => {
+                self.table.select_next();
+                return true;
+            },
+            (KeyCode::Char('m'), Some((kind, state))) => {
+                let target = Target::Kind(kind.to_string());
+                match state.muted {
+                    true => Command::Unmute(target),
+                    false => Command::Mute(target),
+                }
+            },
+            (KeyCode::Char('s'), Some((kind, state))) => {
+                let target = Target::Kind(kind.to_string());
+                match state.solo {
+                    true => Command::Unsolo(target),
+                    false => Command::Solo(target),
+                }
+            },
+            (KeyCode::Char('r'), _) => Command::Reset,
+            _ => return true,
+        };
+
+        // Nobody waits for the response, the new state comes back through show_controls().
+        let (response_tx, _) = mpsc::channel();
+        self.message_tx.send(Message::Control(command, response_tx)).is_ok()
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [header_area, body_area, footer_area] =
+            Layout::vertical([Constraint::Length(3), Constraint::Fill(1), Constraint::Length(1)])
+                .areas(frame.area());
+        let [kinds_area, side_area] =
+            Layout::horizontal([Constraint::Percentage(60), Constraint::Percentage(40)])
+                .areas(body_area);
+        let meters_height = self.peaks.len() as u16 + 2;
+        let [sources_area, meters_area] =
+            Layout::vertical([Constraint::Fill(1), Constraint::Length(meters_height)])
+                .areas(side_area);
+
+        frame.render_widget(self.header(), header_area);
+        self.draw_kinds(frame, kinds_area);
+        frame.render_widget(self.sources(), sources_area);
+        self.draw_meters(frame, meters_area);
+
+        let footer = if self.log.is_empty() { HELP } else { &self.log };
+        frame.render_widget(Line::from(footer).dark_gray(), footer_area);
+    }
+
+    fn header(&self) -> Paragraph<'static> {
+        let last = &self.last;
+        let sum = |count: fn(&_) -> usize| last.listeners.iter().map(count).sum::<usize>();
+        let events: usize = last.kinds.values().sum();
+        let seconds = last.period.as_secs_f64().max(f64::EPSILON);
+        let text = format!(
+            "{:.0} events/s, {} voices playing, {} merged, {} dropped, {} too early plays, {} \
+             datagrams rejected, {} invalid",
+            events as f64 / seconds,
+            self.meters.active_voices(),
+            last.merged_voices,
+            last.dropped_voices,
+            last.too_early_plays,
+            sum(|listener| listener.rejected),
+            sum(|listener| listener.decode_errors),
+        );
+        Paragraph::new(text).block(Block::bordered().title(" composer "))
+    }
+
+    fn draw_kinds(&mut self, frame: &mut Frame, area: Rect) {
+        let rows = self.kinds.iter().map(|(kind, state)| {
+            let rate = state.history.back().copied().unwrap_or(0.0);
+            let flags = match (state.muted, state.solo) {
+                (true, true) => "muted solo",
+                (true, false) => "muted",
+                (false, true) => "solo",
+                (false, false) => "",
+            };
+            let row = Row::new([
+                kind.to_string(),
+                format!("{rate:.0}/s"),
+                sparkline(&state.history),
+                flags.to_string(),
+            ]);
+            if state.muted {
+                row.dark_gray()
+            } else if state.solo {
+                row.yellow()
+            } else {
+                row
+            }
+        });
+        let widths = [
+            Constraint::Fill(1),
+            Constraint::Length(8),
+            Constraint::Length(HISTORY_LENGTH as u16),
+            Constraint::Length(10),
+        ];
+        // Describe the selected kind below the table.
+        let selected = self.table.selected().and_then(|index| self.kinds.keys().nth(index));
+        let description = selected
+            .and_then(|kind| catalogue::find(kind))
+            .map_or(String::new(), |info| format!(" {} ", info.description));
+        let table = Table::new(rows, widths)
+            .header(Row::new(["kind", "rate", "history", ""]).bold())
+            .row_highlight_style(Style::new().reversed())
+            .block(Block::bordered().title(" event kinds ").title_bottom(description));
+        frame.render_stateful_widget(table, area, &mut self.table);
+    }
+
+    fn sources(&self) -> Table<'static> {
+        let rows = self.sources.iter().map(|(source, state)| {
+            let row = Row::new([source.clone(), format!("{:.0}/s", state.rate)]);
+            if state.rate == 0.0 {
+                row.dark_gray()
+            } else {
+                row
+            }
+        });
+        Table::new(rows, [Constraint::Fill(1), Constraint::Length(8)])
+            .block(Block::bordered().title(" sources "))
+    }
+
+    fn draw_meters(&self, frame: &mut Frame, area: Rect) {
+        let block = Block::bordered().title(" output level ");
+        let inner = block.inner(area);
+        frame.render_widget(block, area);
+
+        let rows = Layout::vertical(vec![Constraint::Length(1); self.peaks.len()]).split(inner);
+        for (&peak, &row) in self.peaks.iter().zip(rows.iter()) {
+            let db = 20.0 * peak.log10();
+            let ratio = ((db + METER_RANGE_DB) / METER_RANGE_DB).clamp(0.0, 1.0);
+            let color = if peak >= 1.0 { Color::Red } else { Color::Green };
+            let label = if db > -METER_RANGE_DB { format!("{db:.0} dB") } else { String::new() };
+            let gauge = Gauge::default().ratio(ratio.into()).label(label).gauge_style(color);
+            frame.render_widget(gauge, row);
+        }
+    }
+}
+
+/// Render the values as a line of bars scaled to their maximum, zeros are blank.
+fn sparkline(values: &VecDeque<f64>) -> String {
+    let max = values.iter().copied().fold(0.0, f64::max);
+    values
+        .iter()
+        .map(|&value| {
+            if value > 0.0 {
+                SPARKS[(value / max * (SPARKS.len() - 1) as f64).round() as usize]
+            } else {
+                ' '
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn renders_sparkline() {
+        assert_eq!(sparkline(&VecDeque::from([0.0, 1.0, 3.5, 7.0])), " ▂▅█");
+        assert_eq!(sparkline(&VecDeque::new()), "");
+    }
+
+    #[test]
+    fn toggles_controls_as_they_are() {
+        let (message_tx, message_rx) = mpsc::channel();
+        let mut state = State::new(Meters::silent(), message_tx);
+        let mut controls = Controls::default();
+        controls.apply("mute kind=TestTick".parse().unwrap());
+        controls.apply("solo source=10.0.0.1".parse().unwrap());
+        let kinds = controls.kinds().map(|(kind, muted, solo)| (kind.to_string(), muted, solo));
+        state.update_controls(kinds.collect());
+        assert!(state.kinds["TestTick"].muted && !state.kinds["TestTick"].solo);
+
+        // Muted by composer-ctl, so pressing m unmutes.
+        assert!(state.handle_key(KeyEvent::from(KeyCode::Char('m'))));
+        let Ok(Message::Control(command, _)) = message_rx.try_recv() else {
+            panic!("dashboard should send a control command");
+        };
+        assert_eq!(command, Command::Unmute(Target::Kind("TestTick".to_string())));
+        // Nothing changes until the controls come back.
+        assert!(state.kinds["TestTick"].muted);
+        state.update_controls(Vec::new());
+        assert!(!state.kinds["TestTick"].muted);
+    }
+}
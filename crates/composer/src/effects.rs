@@ -0,0 +1,486 @@
+//! Effects applied to voices of each event kind and to the mixed output, so that some events can
+//! sit far away in the background while others stand out dry and up front.
+//!
+//! Each voice goes through the filters configured for its kind and is mixed into the [Bus] of its
+//! kind, which echoes it and sends it to the reverb by its `reverb_send` amount. The dry mix and the
+//! reverb then go through the master filters, see [Master].
+
+use crate::config::check_kind;
+use eyre::{ensure, Result};
+use serde::Deserialize;
+use std::{collections::BTreeMap, f32::consts::PI};
+
+/// The `[effects]` section of the sound configuration, see `sounds.toml`.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct EffectsConfig {
+    #[serde(default)]
+    master: MasterConfig,
+    /// Effects of each event kind, kinds not listed are played dry.
+    #[serde(default)]
+    kinds: BTreeMap<String, KindConfig>,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct MasterConfig {
+    low_pass_hz: Option<f32>,
+    high_pass_hz: Option<f32>,
+    #[serde(default)]
+    reverb: ReverbConfig,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct ReverbConfig {
+    /// From 0.0 to 1.0, larger rooms ring longer.
+    room_size: f32,
+    /// From 0.0 to 1.0, how fast high frequencies die out.
+    damping: f32,
+}
+
+impl Default for ReverbConfig {
+    fn default() -> Self {
+        Self { room_size: 0.7, damping: 0.4 }
+    }
+}
+
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct KindConfig {
+    pub(crate) low_pass_hz: Option<f32>,
+    pub(crate) high_pass_hz: Option<f32>,
+    echo: Option<EchoConfig>,
+    /// Amount of the voice sent to the reverb bus, from 0.0 (dry) to 1.0.
+    #[serde(default)]
+    reverb_send: f32,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct EchoConfig {
+    delay_ms: u64,
+    /// Gain of each repetition relative to the previous one, from 0.0 to below 1.0.
+    feedback: f32,
+}
+
+impl KindConfig {
+    /// Add a low-pass filter at `hz`, or lower the cutoff of the existing one to it.
+    pub(crate) fn with_low_pass(self, hz: f32) -> Self {
+        let low_pass_hz = self.low_pass_hz.map_or(hz, |existing| existing.min(hz));
+        Self { low_pass_hz: Some(low_pass_hz), ..self }
+    }
+}
+
+/// Validated [EffectsConfig].
+#[derive(Debug, Default)]
+pub(crate) struct Effects {
+    master: MasterConfig,
+    kinds: BTreeMap<String, KindConfig>,
+}
+
+impl Effects {
+    pub(crate) fn new(config: EffectsConfig) -> Result<Self> {
+        let EffectsConfig { master, kinds } = config;
+        validate_filters("master", master.low_pass_hz, master.high_pass_hz)?;
+        let ReverbConfig { room_size, damping } = master.reverb;
+        ensure!((0.0..=1.0).contains(&room_size), "reverb room_size must be from 0 to 1");
+        ensure!((0.0..=1.0).contains(&damping), "reverb damping must be from 0 to 1");
+
+        for (kind, config) in &kinds {
+            check_kind(kind)?;
+            validate_filters(kind, config.low_pass_hz, config.high_pass_hz)?;
+            ensure!(
+                (0.0..=1.0).contains(&config.reverb_send),
+                "reverb_send of {kind} must be from 0 to 1"
+            );
+            if let Some(echo) = &config.echo {
+                ensure!(
+                    (1..=MAX_ECHO_DELAY_MS).contains(&echo.delay_ms),
+                    "echo delay_ms of {kind} must be from 1 to {MAX_ECHO_DELAY_MS}"
+                );
+                ensure!(
+                    (0.0..1.0).contains(&echo.feedback),
+                    "echo feedback of {kind} must be from 0 to below 1"
+                );
+            }
+        }
+        Ok(Self { master, kinds })
+    }
+
+    /// Effects of voices of an event kind, `None` if they should be played dry.
+    pub(crate) fn for_kind(&self, kind: &str) -> Option<&KindConfig> {
+        self.kinds.get(kind)
+    }
+
+    /// Create the effects of a mix, `block_frames` is the most frames the mixer renders at once.
+    pub(crate) fn chain(&self, sample_rate: u32, channels: usize, block_frames: usize) -> Chain {
+        let buses = self
+            .kinds
+            .iter()
+            .map(|(kind, config)| Bus {
+                kind: kind.clone(),
+                echo_config: config.echo.clone(),
+                echo: config.echo.as_ref().map(|echo| Echo::new(echo, sample_rate, channels)),
+                reverb_send: config.reverb_send,
+                buffer: vec![0.0; block_frames * channels],
+            })
+            .collect();
+        Chain { buses, master: Master::new(&self.master, sample_rate, channels) }
+    }
+}
+
+fn validate_filters(name: &str, low_pass_hz: Option<f32>, high_pass_hz: Option<f32>) -> Result<()> {
+    for frequency in [low_pass_hz, high_pass_hz].into_iter().flatten() {
+        ensure!(frequency > 0.0, "filter frequencies of {name} must be positive");
+    }
+    Ok(())
+}
+
+/// Effects of the mix of the audio output or of a render: a bus with the echo of each event kind
+/// with effects, and the master. Voices of the kind mix into its bus, and the bus into the dry mix
+/// and the reverb send.
+pub(crate) struct Chain {
+    pub(crate) buses: Vec<Bus>,
+    pub(crate) master: Master,
+}
+
+impl Chain {
+    /// Take over the state of the echoes and of the master of `previous` where their
+    /// configuration is the same, so that their tails ring on across a reload. Swaps rather than
+    /// copies, so it is real-time safe, and `previous` gets the fresh state.
+    pub(crate) fn carry_over(&mut self, previous: &mut Chain) {
+        for bus in &mut self.buses {
+            let same = previous.buses.iter_mut().find(|previous| previous.kind == bus.kind);
+            if let Some(previous) = same.filter(|previous| previous.echo_config == bus.echo_config)
+            {
+                std::mem::swap(&mut bus.echo, &mut previous.echo);
+            }
+        }
+        if self.master.config == previous.master.config {
+            std::mem::swap(&mut self.master, &mut previous.master);
+        }
+    }
+
+    /// Index of the bus of an event kind, `None` if its voices play dry.
+    pub(crate) fn bus(&self, kind: &str) -> Option<usize> {
+        self.buses.iter().position(|bus| bus.kind == kind)
+    }
+
+    /// Number of interleaved samples until echoes of the buses die out after their voices end.
+    pub(crate) fn tail(&self) -> usize {
+        self.buses.iter().filter_map(|bus| bus.echo.as_ref()).map(Echo::tail).max().unwrap_or(0)
+    }
+}
+
+pub(crate) struct Bus {
+    kind: String,
+    echo_config: Option<EchoConfig>,
+    echo: Option<Echo>,
+    reverb_send: f32,
+    /// Interleaved block of the voices of the bus, so that the mixer does not allocate it.
+    pub(crate) buffer: Vec<f32>,
+}
+
+impl Bus {
+    /// Apply the echo to the block in the buffer, add it to `dry` and `send` and clear it.
+    pub(crate) fn mix_into(&mut self, dry: &mut [f32], send: &mut [f32]) {
+        for ((sample, dry), send) in self.buffer.iter_mut().zip(dry).zip(send) {
+            let mut output = std::mem::take(sample);
+            if let Some(echo) = &mut self.echo {
+                output = echo.process(output);
+            }
+            *dry += output;
+            *send += output * self.reverb_send;
+        }
+    }
+}
+
+/// Effects of the audio output, applied to the mixed voices in the audio callback.
+pub(crate) struct Master {
+    config: MasterConfig,
+    filters: Filters,
+    reverb: Reverb,
+}
+
+impl Master {
+    pub(crate) fn new(config: &MasterConfig, sample_rate: u32, channels: usize) -> Self {
+        Self {
+            config: config.clone(),
+            filters: Filters::new(config.low_pass_hz, config.high_pass_hz, sample_rate, channels),
+            reverb: Reverb::new(&config.reverb, sample_rate, channels),
+        }
+    }
+
+    /// Output sample of a channel given its sample of the dry mix and of the reverb bus.
+    pub(crate) fn process(&mut self, channel: usize, dry: f32, reverb_send: f32) -> f32 {
+        self.filters.process(channel, dry + self.reverb.process(channel, reverb_send))
+    }
+}
+
+/// Optional low-pass and high-pass filters with state for each channel.
+#[derive(Clone)]
+struct Filters {
+    low_pass: Option<Biquad>,
+    high_pass: Option<Biquad>,
+    state: Vec<[[f32; 4]; 2]>,
+}
+
+impl Filters {
+    fn new(
+        low_pass_hz: Option<f32>,
+        high_pass_hz: Option<f32>,
+        sample_rate: u32,
+        channels: usize,
+    ) -> Self {
+        let low_pass = low_pass_hz.map(|hz| Biquad::new(FilterType::LowPass, hz, sample_rate));
+        let high_pass = high_pass_hz.map(|hz| Biquad::new(FilterType::HighPass, hz, sample_rate));
+        Self { low_pass, high_pass, state: vec![[[0.0; 4]; 2]; channels] }
+    }
+
+    fn process(&mut self, channel: usize, sample: f32) -> f32 {
+        filter(self.low_pass, self.high_pass, &mut self.state[channel], sample)
+    }
+}
+
+/// Optional low-pass and high-pass filters of a single voice. Mono and without allocations
+/// unlike [Filters], so that voices can start in the audio thread.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct VoiceFilters {
+    low_pass: Option<Biquad>,
+    high_pass: Option<Biquad>,
+    state: [[f32; 4]; 2],
+}
+
+impl VoiceFilters {
+    pub(crate) fn new(
+        low_pass_hz: Option<f32>,
+        high_pass_hz: Option<f32>,
+        sample_rate: u32,
+    ) -> Self {
+        let low_pass = low_pass_hz.map(|hz| Biquad::new(FilterType::LowPass, hz, sample_rate));
+        let high_pass = high_pass_hz.map(|hz| Biquad::new(FilterType::HighPass, hz, sample_rate));
+        Self { low_pass, high_pass, state: [[0.0; 4]; 2] }
+    }
+
+    pub(crate) fn process(&mut self, sample: f32) -> f32 {
+        filter(self.low_pass, self.high_pass, &mut self.state, sample)
+    }
+}
+
+fn filter(
+    low_pass: Option<Biquad>,
+    high_pass: Option<Biquad>,
+    state: &mut [[f32; 4]; 2],
+    mut sample: f32,
+) -> f32 {
+    for (filter, state) in [low_pass, high_pass].iter().zip(state) {
+        if let Some(filter) = filter {
+            sample = filter.process(state, sample);
+        }
+    }
+    sample
+}
+
+enum FilterType {
+    LowPass,
+    HighPass,
+}
+
+/// Second order Butterworth filter, coefficients from the Audio EQ Cookbook.
+#[derive(Clone, Copy)]
+struct Biquad {
+    /// Feed-forward and feedback coefficients, normalized.
+    b: [f32; 3],
+    a: [f32; 2],
+}
+
+impl Biquad {
+    fn new(filter_type: FilterType, frequency: f32, sample_rate: u32) -> Self {
+        // Frequencies at or above Nyquist make the filter unstable.
+        let frequency = frequency.min(sample_rate as f32 * 0.49);
+        let omega = 2.0 * PI * frequency / sample_rate as f32;
+        let alpha = omega.sin() / (2.0 * std::f32::consts::FRAC_1_SQRT_2);
+        let cos = omega.cos();
+        let b = match filter_type {
+            FilterType::LowPass => [(1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0],
+            FilterType::HighPass => [(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0],
+        };
+        let a0 = 1.0 + alpha;
+        Self { b: b.map(|b| b / a0), a: [-2.0 * cos / a0, (1.0 - alpha) / a0] }
+    }
+
+    /// Filter `input` given the last two inputs and outputs in `state`.
+    fn process(&self, state: &mut [f32; 4], input: f32) -> f32 {
+        let [x1, x2, y1, y2] = *state;
+        let output =
+            self.b[0] * input + self.b[1] * x1 + self.b[2] * x2 - self.a[0] * y1 - self.a[1] * y2;
+        *state = [input, x1, output, y1];
+        output
+    }
+}
+
+/// Feedback delay line, each repetition is quieter by the feedback.
+#[derive(Clone)]
+struct Echo {
+    /// Interleaved samples of the last `delay` worth of frames.
+    line: Vec<f32>,
+    position: usize,
+    feedback: f32,
+}
+
+/// Echoes quieter than this relative to the original are not rendered after the voices end.
+const ECHO_SILENCE: f32 = 0.001;
+/// Longest echo delay, bounds the memory of the delay lines.
+const MAX_ECHO_DELAY_MS: u64 = 10_000;
+
+impl Echo {
+    fn new(config: &EchoConfig, sample_rate: u32, channels: usize) -> Self {
+        let frames = (config.delay_ms as usize * sample_rate as usize / 1000).max(1);
+        Self { line: vec![0.0; frames * channels], position: 0, feedback: config.feedback }
+    }
+
+    /// Number of samples until the echoes die out.
+    fn tail(&self) -> usize {
+        let repetitions = match self.feedback {
+            0.0 => 1.0,
+            feedback => (ECHO_SILENCE.ln() / feedback.ln()).ceil(),
+        };
+        self.line.len() * repetitions as usize
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        let delayed = self.line[self.position];
+        self.line[self.position] = (input + delayed) * self.feedback;
+        self.position = (self.position + 1) % self.line.len();
+        input + delayed
+    }
+}
+
+/// Freeverb-style reverb: parallel damped comb filters followed by serial all-pass filters, with
+/// slightly different delays for each channel to make the sound wide.
+struct Reverb {
+    channels: Vec<ReverbChannel>,
+    feedback: f32,
+    damping: f32,
+}
+
+struct ReverbChannel {
+    combs: Vec<(DelayLine, f32)>,
+    all_passes: Vec<DelayLine>,
+}
+
+struct DelayLine {
+    buffer: Vec<f32>,
+    position: usize,
+}
+
+impl DelayLine {
+    fn new(length: usize) -> Self {
+        Self { buffer: vec![0.0; length.max(1)], position: 0 }
+    }
+
+    /// Replace the oldest sample by `next` and return it.
+    fn swap(&mut self, next: impl FnOnce(f32) -> f32) -> f32 {
+        let oldest = self.buffer[self.position];
+        self.buffer[self.position] = next(oldest);
+        self.position = (self.position + 1) % self.buffer.len();
+        oldest
+    }
+}
+
+/// Comb and all-pass delays of the original Freeverb, in samples at 44.1 kHz.
+const COMB_DELAYS: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+const ALL_PASS_DELAYS: [usize; 4] = [556, 441, 341, 225];
+/// Delays of each next channel are longer by this many samples.
+const STEREO_SPREAD: usize = 23;
+/// Input is attenuated to keep the sum of the combs in range, and the output is boosted back.
+const REVERB_INPUT_GAIN: f32 = 0.015;
+const REVERB_OUTPUT_GAIN: f32 = 3.0;
+
+impl Reverb {
+    fn new(config: &ReverbConfig, sample_rate: u32, channels: usize) -> Self {
+        let scale = |delay: usize| delay * sample_rate as usize / 44_100;
+        let channels = (0..channels)
+            .map(|channel| ReverbChannel {
+                combs: COMB_DELAYS
+                    .iter()
+                    .map(|&delay| (DelayLine::new(scale(delay + channel * STEREO_SPREAD)), 0.0))
+                    .collect(),
+                all_passes: ALL_PASS_DELAYS
+                    .iter()
+                    .map(|&delay| DelayLine::new(scale(delay + channel * STEREO_SPREAD)))
+                    .collect(),
+            })
+            .collect();
+        Self { channels, feedback: config.room_size * 0.28 + 0.7, damping: config.damping * 0.4 }
+    }
+
+    fn process(&mut self, channel: usize, input: f32) -> f32 {
+        let Self { channels, feedback, damping } = self;
+        let ReverbChannel { combs, all_passes } = &mut channels[channel];
+        let input = input * REVERB_INPUT_GAIN;
+
+        let mut output = 0.0;
+        for (line, filtered) in combs {
+            output += line.swap(|oldest| {
+                *filtered = oldest * (1.0 - *damping) + *filtered * *damping;
+                input + *filtered * *feedback
+            });
+        }
+        for line in all_passes {
+            let mut buffered = 0.0;
+            line.swap(|oldest| {
+                buffered = oldest;
+                output + oldest * 0.5
+            });
+            output = buffered - output;
+        }
+        output * REVERB_OUTPUT_GAIN
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn validates_effects() {
+        let echo = EchoConfig { delay_ms: 100, feedback: 0.5 };
+        let config = KindConfig { echo: Some(echo), ..Default::default() };
+        let effects = EffectsConfig {
+            kinds: [
+                ("StdoutWrite".to_string(), Default::default()),
+                ("TestTick".to_string(), config),
+            ]
+            .into(),
+            ..Default::default()
+        };
+        let effects = Effects::new(effects).unwrap();
+        assert!(effects.for_kind("TestTick").is_some_and(|config| config.echo.is_some()));
+        assert!(effects.for_kind("StderrWrite").is_none());
+        let chain = effects.chain(1000, 2, 256);
+        assert_eq!((chain.buses.len(), chain.buses[1].buffer.len()), (2, 512));
+        assert_eq!((chain.bus("TestTick"), chain.bus("StderrWrite")), (Some(1), None));
+
+        let reverb = KindConfig { reverb_send: 2.0, ..Default::default() };
+        let effects = EffectsConfig {
+            kinds: [("TestTick".to_string(), reverb)].into(),
+            ..Default::default()
+        };
+        assert!(Effects::new(effects).is_err());
+
+        for delay_ms in [0, MAX_ECHO_DELAY_MS + 1, u64::MAX] {
+            let echo = EchoConfig { delay_ms, feedback: 0.5 };
+            let effects = EffectsConfig {
+                kinds: [(
+                    "TestTick".to_string(),
+                    KindConfig { echo: Some(echo), ..Default::default() },
+                )]
+                .into(),
+                ..Default::default()
+            };
+            assert!(Effects::new(effects).is_err(), "{delay_ms}");
+        }
+    }
+}
@@ -0,0 +1,164 @@
+use serde::Serialize;
+use std::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// Buckets grow exponentially, this many of them per doubling of the magnitude. Percentiles are
+/// therefore accurate to about 5%.
+const BUCKETS_PER_OCTAVE: f64 = 8.0;
+/// Number of buckets for each sign: magnitudes from 1 µs to over an hour.
+const HALF: usize = 32 * BUCKETS_PER_OCTAVE as usize;
+
+/// Histogram of signed time differences, like the slack of a voice before its scheduled play
+/// time or the latency of an event over the network. Both positive and negative differences
+/// are common: voices can be late and probe clocks can be ahead of ours.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Histogram {
+    /// Counts of values in buckets ordered by value, negative ones first.
+    counts: Box<[u64]>,
+}
+
+/// Percentiles of a [Histogram], in milliseconds.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub(crate) struct Summary {
+    pub(crate) count: u64,
+    pub(crate) p50_ms: f64,
+    pub(crate) p90_ms: f64,
+    pub(crate) p99_ms: f64,
+    pub(crate) max_ms: f64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self { counts: vec![0; 2 * HALF].into() }
+    }
+}
+
+impl Histogram {
+    /// Record the difference `value - reference`.
+    pub(crate) fn record(&mut self, value: Duration, reference: Duration) {
+        self.counts[bucket(value, reference)] += 1;
+    }
+
+    pub(crate) fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Value in milliseconds below which `quantile` (0.0 to 1.0) of the recorded values are,
+    /// `None` if nothing was recorded.
+    pub(crate) fn quantile(&self, quantile: f64) -> Option<f64> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+
+        let rank = ((quantile * count as f64).ceil() as u64).clamp(1, count);
+        let mut seen = 0;
+        let index = self.counts.iter().position(|&bucket_count| {
+            seen += bucket_count;
+            seen >= rank
+        })?;
+        Some(bucket_value_ms(index))
+    }
+
+    pub(crate) fn summary(&self) -> Option<Summary> {
+        Some(Summary {
+            count: self.count(),
+            p50_ms: self.quantile(0.5)?,
+            p90_ms: self.quantile(0.9)?,
+            p99_ms: self.quantile(0.99)?,
+            max_ms: self.quantile(1.0)?,
+        })
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "p50 {:.1} ms, p90 {:.1} ms, p99 {:.1} ms, max {:.1} ms of {} events",
+            self.p50_ms, self.p90_ms, self.p99_ms, self.max_ms, self.count
+        )
+    }
+}
+
+/// A [Histogram] that can be recorded to from one thread, like the audio callback, without
+/// locking or allocating, and taken from another one.
+pub(crate) struct AtomicHistogram {
+    counts: Box<[AtomicU64]>,
+}
+
+impl Default for AtomicHistogram {
+    fn default() -> Self {
+        Self { counts: (0..2 * HALF).map(|_| AtomicU64::new(0)).collect() }
+    }
+}
+
+impl AtomicHistogram {
+    pub(crate) fn record(&self, value: Duration, reference: Duration) {
+        self.counts[bucket(value, reference)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Get the values recorded since the last call of this method.
+    pub(crate) fn take(&self) -> Histogram {
+        Histogram {
+            counts: self.counts.iter().map(|count| count.swap(0, Ordering::Relaxed)).collect(),
+        }
+    }
+}
+
+fn bucket(value: Duration, reference: Duration) -> usize {
+    let (magnitude, negative) = match value.checked_sub(reference) {
+        Some(difference) => (difference, false),
+        None => (reference - value, true),
+    };
+    let micros = magnitude.as_secs_f64() * 1e6;
+    let magnitude_bucket = ((micros.max(1.0).log2() * BUCKETS_PER_OCTAVE) as usize).min(HALF - 1);
+    if negative {
+        HALF - 1 - magnitude_bucket
+    } else {
+        HALF + magnitude_bucket
+    }
+}
+
+/// Representative value of a bucket, the geometric middle of its bounds.
+fn bucket_value_ms(index: usize) -> f64 {
+    let (magnitude_bucket, sign) =
+        if index >= HALF { (index - HALF, 1.0) } else { (HALF - 1 - index, -1.0) };
+    let micros = ((magnitude_bucket as f64 + 0.5) / BUCKETS_PER_OCTAVE).exp2();
+    sign * micros / 1000.0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn computes_quantiles_of_signed_differences() {
+        let mut histogram = Histogram::default();
+        assert_eq!(histogram.summary(), None);
+
+        let reference = Duration::from_secs(100);
+        for millis in 1..=100 {
+            histogram.record(reference + Duration::from_millis(millis), reference);
+        }
+        histogram.record(reference - Duration::from_millis(30), reference);
+
+        let close = |actual: Option<f64>, expected: f64| {
+            let actual = actual.unwrap();
+            assert!((actual - expected).abs() <= expected.abs() * 0.05, "{actual} vs {expected}");
+        };
+        close(histogram.quantile(0.0), -30.0);
+        close(histogram.quantile(0.5), 50.0);
+        close(histogram.quantile(0.99), 99.0);
+        close(histogram.quantile(1.0), 100.0);
+        assert_eq!(histogram.count(), 101);
+
+        let atomic = AtomicHistogram::default();
+        atomic.record(reference, reference + Duration::from_millis(30));
+        close(atomic.take().quantile(0.5), -30.0);
+        assert_eq!(atomic.take().count(), 0);
+    }
+}
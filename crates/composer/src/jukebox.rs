@@ -1,60 +1,429 @@
-use crate::audio_output::AudioOutput;
-use eyre::{Context, Result};
-use rodio::{
-    source::{Buffered, SamplesConverter},
-    Decoder, Source,
+use crate::{
+    dashboard::report,
+    effects::Effects,
+    mixer::{SampleBank, SampleData, VoiceDescriptor, MAX_CHANNELS},
+    spatial::Spatial,
+};
+use composer_api::{util::Rng, EventKind};
+use eyre::{ensure, Context, Result};
+use rodio::{Decoder, Source};
+use serde::{
+    de::{self, value::MapAccessDeserializer, MapAccess, Visitor},
+    Deserialize, Deserializer,
+};
+use std::{
+    collections::HashMap,
+    f32::consts::FRAC_PI_2,
+    fmt,
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
 };
-use std::{collections::HashMap, fs::File, io::BufReader, path::Path, time::Duration};
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
-pub(crate) enum Sample {
-    Click,
-    Clack,
-}
+/// Name of a sample defined in the sound configuration, see [crate::config].
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub(crate) struct Sample(Arc<str>);
 
 impl Sample {
-    const ALL: &[Sample] = &[Self::Click, Sample::Clack];
+    pub(crate) fn name(&self) -> &str {
+        &self.0
+    }
+}
 
-    fn filename(&self) -> &'static Path {
-        Path::new(match self {
-            Sample::Click => "click.wav",
-            Sample::Clack => "clack.wav",
-        })
+/// A request to play a sample at given time.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Voice {
+    /// Kind of the event the voice represents, see [composer_api::EventKind::name()].
+    pub(crate) kind: &'static str,
+    pub(crate) sample: Sample,
+    /// UNIX timestamp of the event the voice represents.
+    pub(crate) timestamp: Duration,
+    /// Amplitude multiplier, 1.0 plays the sample as recorded.
+    pub(crate) gain: f32,
+    /// Playback speed multiplier, also changes the pitch. 1.0 plays the sample as recorded.
+    pub(crate) rate: f32,
+    /// Stereo position from -1.0 (left) to 1.0 (right).
+    pub(crate) pan: f32,
+    /// Azimuth of the source of the voice in radians clockwise from the front, for spatial
+    /// panning, see [crate::spatial].
+    pub(crate) direction: Option<f32>,
+    /// Play at most this much of the sample, fading out at the end, all of it if `None`.
+    pub(crate) truncate: Option<Duration>,
+    /// Brightness of the sound as the cutoff of a low-pass filter, unfiltered if `None`.
+    pub(crate) low_pass_hz: Option<f32>,
+    /// Length of the write the voice represents, if any.
+    pub(crate) length: Option<usize>,
+    /// Distinguishing value of the event, like the level of log events, see [label()].
+    pub(crate) label: Option<String>,
+    /// Number of events the voice stands for, see [composer_api::Event::weight].
+    pub(crate) weight: f32,
+}
+
+impl Voice {
+    pub(crate) fn new(kind: &'static str, sample: Sample, timestamp: Duration) -> Self {
+        Self {
+            kind,
+            sample,
+            timestamp,
+            gain: 1.0,
+            rate: 1.0,
+            pan: 0.0,
+            direction: None,
+            truncate: None,
+            low_pass_hz: None,
+            length: None,
+            label: None,
+            weight: 1.0,
+        }
+    }
+
+    /// Create a voice for `event`, keeping its attributes and deriving playback parameters from
+    /// them: the bigger a write, the louder, lower, longer and darker it sounds. Writes to stdout
+    /// are panned to the left, writes to stderr to the right. Log statistics stand for all the
+    /// records they count.
+    pub(crate) fn for_event(event: &EventKind, sample: Sample, timestamp: Duration) -> Self {
+        let mut voice = Self { label: label(event), ..Self::new(event.name(), sample, timestamp) };
+        let (length, pan) = match event {
+            EventKind::StdoutWrite { length } => (*length, -WRITE_PAN),
+            EventKind::StderrWrite { length } => (*length, WRITE_PAN),
+            EventKind::LogStats(stats) => {
+                let records = [
+                    stats.error_records,
+                    stats.warn_records,
+                    stats.info_records,
+                    stats.debug_records,
+                    stats.trace_records,
+                ];
+                voice.weight = records.iter().map(|&records| records as f32).sum();
+                return voice;
+            },
+            EventKind::TestTick
+            | EventKind::FileSystemRead
+            | EventKind::FileSystemWrite
+            | EventKind::Log { .. } => return voice,
+        };
+
+        // From 0.0 for a single byte to 1.0 for the biggest writes, growing with each doubling.
+        let size = ((length.max(1) as f32).log2() / MAX_WRITE_LENGTH_LOG2).min(1.0);
+        voice.length = Some(length);
+        voice.pan = pan;
+        voice.gain = MIN_WRITE_GAIN + (1.0 - MIN_WRITE_GAIN) * size;
+        voice.rate = (-size).exp2();
+        voice.truncate = Some(MIN_WRITE_DURATION.mul_f32(MAX_WRITE_DURATION_RATIO.powf(size)));
+        voice.low_pass_hz = Some(MAX_WRITE_LOW_PASS_HZ / MAX_WRITE_LOW_PASS_RATIO.powf(size));
+        voice
     }
 }
 
-type Buffer = Buffered<SamplesConverter<Decoder<BufReader<File>>, f32>>;
+/// Writes of 64 KiB and more sound the same.
+const MAX_WRITE_LENGTH_LOG2: f32 = 16.0;
+/// A single byte write plays at this gain, the biggest ones at 1.0 and an octave lower.
+const MIN_WRITE_GAIN: f32 = 0.4;
+/// A single byte write plays at most this long, the biggest ones this many times longer.
+const MIN_WRITE_DURATION: Duration = Duration::from_millis(30);
+const MAX_WRITE_DURATION_RATIO: f32 = 16.0;
+/// Low-pass cutoff of a single byte write, lowered by this ratio for the biggest ones.
+const MAX_WRITE_LOW_PASS_HZ: f32 = 18_000.0;
+const MAX_WRITE_LOW_PASS_RATIO: f32 = 12.0;
+/// How far writes to stdout and stderr are panned from the center.
+const WRITE_PAN: f32 = 0.5;
 
-/// Records (samples) are loaded to the jukebox once, and then in can quickly play any of them.
-pub struct Jukebox {
-    samples: HashMap<Sample, Buffer>,
+/// A distinguishing value of events of some kinds, like the level of log events.
+pub(crate) fn label(kind: &EventKind) -> Option<String> {
+    match kind {
+        EventKind::Log { level } => Some(level.name().to_string()),
+        _ => None,
+    }
 }
 
-impl Jukebox {
-    pub(crate) fn new() -> Result<Self> {
-        let base_path = Path::new("src/sound_samples");
-        let samples = Sample::ALL
+impl FromStr for Sample {
+    type Err = eyre::Report;
+
+    fn from_str(name: &str) -> Result<Self> {
+        ensure!(!name.is_empty(), "sample name must not be empty");
+        Ok(Self(name.into()))
+    }
+}
+
+impl fmt::Display for Sample {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// A sample in the `[samples]` section of the sound configuration: a single file, or a pool of
+/// variations to avoid the artificial sound of the very same sample repeating rapidly.
+#[derive(Debug)]
+pub(crate) enum SampleConfig {
+    File(PathBuf),
+    Pool(PoolConfig),
+}
+
+/// Tells the variants apart by the type of the value rather than by trying them in turn like
+/// `#[serde(untagged)]` does, so that errors in a pool like unknown fields are reported as such.
+impl<'de> Deserialize<'de> for SampleConfig {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct SampleConfigVisitor;
+
+        impl<'de> Visitor<'de> for SampleConfigVisitor {
+            type Value = SampleConfig;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a file name or a table with files")
+            }
+
+            fn visit_str<E: de::Error>(self, file: &str) -> Result<Self::Value, E> {
+                Ok(SampleConfig::File(file.into()))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+                PoolConfig::deserialize(MapAccessDeserializer::new(map)).map(SampleConfig::Pool)
+            }
+        }
+
+        deserializer.deserialize_any(SampleConfigVisitor)
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct PoolConfig {
+    /// Variations of the sample, played in turns.
+    files: Vec<PathBuf>,
+    /// Playback rate of each play is randomly changed by up to this fraction, e.g. 0.02.
+    #[serde(default)]
+    pitch_jitter: f32,
+    /// Gain of each play is randomly changed by up to this fraction.
+    #[serde(default)]
+    gain_jitter: f32,
+    /// Each play randomly skips up to this much of the start of the sample.
+    #[serde(default)]
+    offset_jitter_ms: u64,
+    /// Seed of the random jitter, so that plays sound the same every time. Derived from the
+    /// sample name if not given.
+    seed: Option<u64>,
+}
+
+/// Loaded variations of a sample, see [PoolConfig].
+struct Pool {
+    /// Indices of the variations in the [SampleBank].
+    buffers: Vec<usize>,
+    /// Index of the buffer to play next.
+    next: usize,
+    pitch_jitter: f32,
+    gain_jitter: f32,
+    offset_jitter: Duration,
+    rng: Rng,
+}
+
+/// Changes of a voice for a play of a [Pool].
+#[derive(Debug, PartialEq)]
+struct Variation {
+    buffer: usize,
+    gain: f32,
+    rate: f32,
+    offset: Duration,
+}
+
+impl Pool {
+    /// Load the variations of a sample, appending them to `bank`.
+    fn load(
+        sample: &Sample,
+        config: SampleConfig,
+        sample_dir: &Path,
+        bank: &mut Vec<SampleData>,
+    ) -> Result<Self> {
+        let config = match config {
+            SampleConfig::File(file) => PoolConfig { files: vec![file], ..Default::default() },
+            SampleConfig::Pool(config) => config,
+        };
+        ensure!(!config.files.is_empty(), "sample {sample} must have at least one file");
+        for jitter in [config.pitch_jitter, config.gain_jitter] {
+            ensure!((0.0..1.0).contains(&jitter), "jitter of sample {sample} must be from 0 to 1");
+        }
+
+        let buffers = config
+            .files
             .iter()
-            .map(|&sample| -> Result<(Sample, Buffer)> {
-                let path = base_path.join(sample.filename());
+            .map(|file| {
+                let path = sample_dir.join(file);
                 let file =
                     BufReader::new(File::open(&path).with_context(|| format!("opening {path:?}"))?);
                 let source = Decoder::new(file).with_context(|| format!("decoding {path:?}"))?;
+                bank.push(SampleData::new(source.convert_samples()));
+                Ok(bank.len() - 1)
+            })
+            .collect::<Result<_>>()?;
+        let seed = config.seed.unwrap_or_else(|| fnv1a(sample.name().as_bytes()));
+        Ok(Self {
+            buffers,
+            next: 0,
+            pitch_jitter: config.pitch_jitter,
+            gain_jitter: config.gain_jitter,
+            offset_jitter: Duration::from_millis(config.offset_jitter_ms),
+            rng: Rng::new(seed),
+        })
+    }
 
-                Ok((sample, source.convert_samples().buffered()))
+    /// Pick the variation for the next play: the next buffer in turn and random jitter.
+    fn vary(&mut self) -> Variation {
+        let buffer = self.next;
+        self.next = (self.next + 1) % self.buffers.len();
+        Variation {
+            buffer,
+            gain: 1.0 + self.gain_jitter * self.rng.symmetric(),
+            rate: 1.0 + self.pitch_jitter * self.rng.symmetric(),
+            offset: self.offset_jitter.mul_f32(self.rng.symmetric().abs()),
+        }
+    }
+}
+
+/// FNV-1a hash, stable across runs and platforms unlike the standard library hashers.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Records (samples) are loaded to the jukebox once, and then in can quickly play any of them.
+pub struct Jukebox {
+    samples: HashMap<Sample, Pool>,
+    bank: SampleBank,
+}
+
+impl Jukebox {
+    /// Load and decode sample files, relative to `sample_dir`.
+    pub(crate) fn new(
+        samples: impl IntoIterator<Item = (Sample, SampleConfig)>,
+        sample_dir: &Path,
+    ) -> Result<Self> {
+        let mut bank = Vec::new();
+        let samples = samples
+            .into_iter()
+            .map(|(sample, config)| {
+                Ok((sample.clone(), Pool::load(&sample, config, sample_dir, &mut bank)?))
             })
             .collect::<Result<_>>()
             .context("loading records")?;
 
-        Ok(Self { samples })
+        Ok(Self { samples, bank: bank.into() })
     }
 
-    pub(crate) fn play(&self, audio_output: &AudioOutput, sample: Sample, timestamp: Duration) {
-        let buffer = self
-            .samples
-            .get(&sample)
-            .expect("programmer error, all possible samples should be loaded");
+    /// Decoded samples the descriptors of voices refer to, see [Self::descriptor()].
+    pub(crate) fn bank(&self) -> SampleBank {
+        SampleBank::clone(&self.bank)
+    }
+
+    pub(crate) fn contains(&self, sample: &Sample) -> bool {
+        self.samples.contains_key(sample)
+    }
+
+    /// Describe how to play a voice with the effects of its kind, panned to the speakers of
+    /// `spatial` if given or to stereo otherwise. `None` if the sample of the voice is not loaded.
+    pub(crate) fn descriptor(
+        &mut self,
+        voice: &Voice,
+        effects: &Effects,
+        spatial: Option<&Spatial>,
+    ) -> Option<VoiceDescriptor> {
+        let Some(pool) = self.samples.get_mut(&voice.sample) else {
+            // Can happen for voices created just before a configuration reload.
+            report(format!("Sample {} is not loaded, not playing it.", voice.sample));
+            return None;
+        };
+        let variation = pool.vary();
+
+        let mut channel_gains = [0.0; MAX_CHANNELS];
+        match spatial {
+            // Voices of sources without a direction are panned within the front half.
+            Some(spatial) => {
+                let gains = spatial.gains(voice.direction.unwrap_or(voice.pan * FRAC_PI_2));
+                channel_gains[..gains.len()].copy_from_slice(&gains);
+            },
+            // Balance rather than a pan law, so that centered voices play as loud as recorded.
+            None => channel_gains[..2]
+                .copy_from_slice(&[(1.0 - voice.pan).min(1.0), (1.0 + voice.pan).min(1.0)]),
+        }
+
+        let mut config = effects.for_kind(voice.kind).cloned().unwrap_or_default();
+        if let Some(low_pass_hz) = voice.low_pass_hz {
+            config = config.with_low_pass(low_pass_hz);
+        }
+        Some(VoiceDescriptor {
+            sample: pool.buffers[variation.buffer],
+            timestamp: voice.timestamp,
+            offset: variation.offset.as_secs_f32(),
+            duration: voice.truncate.map_or(f32::INFINITY, |truncate| truncate.as_secs_f32()),
+            gain: voice.gain * variation.gain,
+            rate: voice.rate * variation.rate,
+            channel_gains,
+            low_pass_hz: config.low_pass_hz,
+            high_pass_hz: config.high_pass_hz,
+            kind: voice.kind,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn modulates_writes_by_length() {
+        let voice = |kind| Voice::for_event(&kind, "click".parse().unwrap(), Duration::ZERO);
+        let small = voice(EventKind::StdoutWrite { length: 1 });
+        let big = voice(EventKind::StderrWrite { length: 64 * 1024 });
+
+        assert_eq!((small.gain, small.rate, small.pan), (MIN_WRITE_GAIN, 1.0, -WRITE_PAN));
+        assert_eq!((big.gain, big.rate, big.pan), (1.0, 0.5, WRITE_PAN));
+        let millis = |voice: &Voice| (voice.truncate.unwrap().as_secs_f32() * 1000.0).round();
+        assert_eq!((millis(&small), millis(&big)), (30.0, 480.0));
+        assert!(big.low_pass_hz.unwrap() < small.low_pass_hz.unwrap() / 10.0);
+        assert_eq!(big.length, Some(64 * 1024));
+
+        let tick = voice(EventKind::TestTick);
+        assert_eq!(tick, Voice::new("TestTick", "click".parse().unwrap(), Duration::ZERO));
+    }
+
+    #[test]
+    fn varies_pooled_samples() {
+        let config = || {
+            SampleConfig::Pool(PoolConfig {
+                files: vec!["click.wav".into(), "clack.wav".into()],
+                pitch_jitter: 0.05,
+                gain_jitter: 0.1,
+                offset_jitter_ms: 10,
+                seed: None,
+            })
+        };
+        let sample = "click".parse().unwrap();
+        let load = || {
+            Pool::load(&sample, config(), Path::new("src/sound_samples"), &mut Vec::new()).unwrap()
+        };
+        let mut pool = load();
+
+        let variations: Vec<_> = (0..100).map(|_| pool.vary()).collect();
+        assert_eq!(variations[0].buffer, 0);
+        assert_eq!(variations[1].buffer, 1);
+        assert_eq!(variations[2].buffer, 0);
+        for Variation { gain, rate, offset, .. } in &variations {
+            assert!((0.9..=1.1).contains(gain), "{gain}");
+            assert!((0.95..=1.05).contains(rate), "{rate}");
+            assert!(*offset <= Duration::from_millis(10));
+        }
+        assert!(variations.windows(2).all(|pair| pair[0].gain != pair[1].gain));
+        // Seeded, plays sound the same every time.
+        assert_eq!(load().vary(), variations[0]);
 
-        audio_output.play(buffer.clone(), timestamp);
+        let single = SampleConfig::File("click.wav".into());
+        let mut bank = Vec::new();
+        let mut pool =
+            Pool::load(&sample, single, Path::new("src/sound_samples"), &mut bank).unwrap();
+        assert_eq!((pool.buffers.as_slice(), bank.len()), ([0].as_slice(), 1));
+        let expected = Variation { buffer: 0, gain: 1.0, rate: 1.0, offset: Duration::ZERO };
+        assert_eq!(pool.vary(), expected);
     }
 }
@@ -0,0 +1,231 @@
+use crate::{dashboard::report, jukebox::Sample, Message};
+use composer_api::{
+    auth::SharedKey,
+    encoding::{self, FrameReader},
+    util::current_timestamp,
+    Packet,
+};
+use eyre::{bail, eyre, Context, Result};
+use std::{
+    fmt,
+    io::{BufReader, Read},
+    net::{IpAddr, SocketAddr, TcpListener, UdpSocket},
+    str::FromStr,
+    sync::mpsc::Sender,
+    thread,
+    time::Duration,
+};
+
+/// Where to listen for incoming events, parsed from `--listen` arguments of the form
+/// `<protocol>://<address>[,sample=<sample>]`, e.g. `udp://[::]:8888`, `tcp://0.0.0.0:8889` or
+/// `unix:///tmp/composer.sock,sample=clack`. A plain `<address>` means UDP.
+#[derive(Debug, Clone)]
+pub(crate) struct ListenerSpec {
+    endpoint: Endpoint,
+    /// Play this sample for all events received through the listener, regardless of their kind.
+    pub(crate) sample: Option<Sample>,
+}
+
+#[derive(Debug, Clone)]
+enum Endpoint {
+    Udp(String),
+    Tcp(String),
+    #[cfg(unix)]
+    Unix(std::path::PathBuf),
+}
+
+impl FromStr for ListenerSpec {
+    type Err = eyre::Report;
+
+    fn from_str(spec: &str) -> Result<Self> {
+        let mut parts = spec.split(',');
+        let endpoint = parts.next().unwrap_or_default();
+        let endpoint = match endpoint.split_once("://") {
+            Some(("udp", address)) => Endpoint::Udp(address.to_string()),
+            Some(("tcp", address)) => Endpoint::Tcp(address.to_string()),
+            #[cfg(unix)]
+            Some(("unix", path)) => Endpoint::Unix(path.into()),
+            Some((protocol, _)) => bail!("unsupported protocol {protocol:?}"),
+            None => Endpoint::Udp(endpoint.to_string()),
+        };
+
+        let mut sample = None;
+        for option in parts {
+            match option.split_once('=') {
+                Some(("sample", name)) => sample = Some(name.parse()?),
+                _ => bail!("unknown listener option {option:?}"),
+            }
+        }
+
+        Ok(Self { endpoint, sample })
+    }
+}
+
+impl fmt::Display for ListenerSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.endpoint {
+            Endpoint::Udp(address) => write!(f, "udp://{address}"),
+            Endpoint::Tcp(address) => write!(f, "tcp://{address}"),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => write!(f, "unix://{}", path.display()),
+        }
+    }
+}
+
+/// A datagram received by one of the listeners.
+pub(crate) struct Incoming {
+    /// Index of the listener in the order they were spawned.
+    pub(crate) listener: usize,
+    /// Address of the sender like `udp://127.0.0.1:5432`, or the listener itself if unknown.
+    pub(crate) source: String,
+    /// Time of arrival as the duration since UNIX epoch.
+    pub(crate) arrival: Duration,
+    /// The decoded packet and the size of the datagram in bytes.
+    pub(crate) result: Result<(Packet, usize)>,
+}
+
+/// IP address of the sender of a datagram from its [Incoming::source], `None` for Unix sockets.
+pub(crate) fn source_ip(source: &str) -> Option<IpAddr> {
+    let (_scheme, address) = source.split_once("://")?;
+    address.parse::<SocketAddr>().ok().map(|address| address.ip().to_canonical())
+}
+
+/// Bind the endpoint of `spec` and spawn thread(s) that receive and decode datagrams and send them
+/// to `message_tx` tagged with `index`. Returns the actual address the listener is bound to.
+/// When `key` is given, datagrams not signed with it are reported as
+/// [composer_api::auth::Unauthenticated] errors.
+pub(crate) fn spawn(
+    index: usize,
+    spec: &ListenerSpec,
+    key: Option<SharedKey>,
+    message_tx: Sender<Message>,
+) -> Result<String> {
+    let decoder = Decoder { index, key, message_tx, name: spec.to_string() };
+    match &spec.endpoint {
+        Endpoint::Udp(address) => {
+            let socket = UdpSocket::bind(address).with_context(|| format!("binding {spec}"))?;
+            let local_address = format!("udp://{}", socket.local_addr()?);
+            thread::spawn(move || decoder.serve_udp(socket));
+            Ok(local_address)
+        },
+        Endpoint::Tcp(address) => {
+            let listener = TcpListener::bind(address).with_context(|| format!("binding {spec}"))?;
+            let local_address = format!("tcp://{}", listener.local_addr()?);
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    match stream.and_then(|stream| Ok((stream.peer_addr()?, stream))) {
+                        Ok((peer, stream)) => decoder.spawn_stream(stream, format!("tcp://{peer}")),
+                        Err(err) => report(format!("Could not accept connection: {err}.")),
+                    }
+                }
+            });
+            Ok(local_address)
+        },
+        #[cfg(unix)]
+        Endpoint::Unix(path) => {
+            use std::os::unix::{fs::FileTypeExt, net::UnixListener};
+
+            // Remove a stale socket left behind by a previous run.
+            if std::fs::metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+                std::fs::remove_file(path)?;
+            }
+            let listener = UnixListener::bind(path).with_context(|| format!("binding {spec}"))?;
+            // Clients of Unix sockets are usually unnamed, identify them by the listener.
+            let source = spec.to_string();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    match stream {
+                        Ok(stream) => decoder.spawn_stream(stream, source.clone()),
+                        Err(err) => report(format!("Could not accept connection: {err}.")),
+                    }
+                }
+            });
+            Ok(spec.to_string())
+        },
+    }
+}
+
+#[derive(Clone)]
+struct Decoder {
+    index: usize,
+    key: Option<SharedKey>,
+    message_tx: Sender<Message>,
+    /// Name of the listener, for errors without a known source.
+    name: String,
+}
+
+impl Decoder {
+    fn decode(&self, datagram: &[u8]) -> Result<(Packet, usize)> {
+        let payload = match &self.key {
+            Some(key) => key.verify(datagram)?,
+            None => datagram,
+        };
+        Ok((encoding::decode(payload)?, datagram.len()))
+    }
+
+    /// Send the result to the main thread, returns false when it is not listening anymore.
+    fn send(&self, source: String, result: Result<(Packet, usize)>) -> bool {
+        let arrival = current_timestamp();
+        let incoming = Incoming { listener: self.index, source, arrival, result };
+        self.message_tx.send(Message::Incoming(incoming)).is_ok()
+    }
+
+    fn serve_udp(self, socket: UdpSocket) {
+        // Size up to max normal network packet size
+        let mut buf = [0; 1500];
+        loop {
+            let sent = match socket.recv_from(&mut buf) {
+                Ok((number_of_bytes, peer)) => {
+                    self.send(format!("udp://{peer}"), self.decode(&buf[..number_of_bytes]))
+                },
+                Err(err) => self.send(self.name.clone(), Err(eyre!(err))),
+            };
+            if !sent {
+                break;
+            }
+        }
+    }
+
+    fn spawn_stream(&self, stream: impl Read + Send + 'static, source: String) {
+        let decoder = self.clone();
+        thread::spawn(move || {
+            let mut reader = FrameReader::new(BufReader::new(stream));
+            loop {
+                let result = match reader.read_frame() {
+                    Ok(Some(datagram)) => decoder.decode(&datagram),
+                    Ok(None) => break,
+                    // The stream is out of sync after a read error, report it and hang up.
+                    Err(err) => {
+                        decoder.send(source, Err(err));
+                        break;
+                    },
+                };
+                if !decoder.send(source.clone(), result) {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_listener_specs() {
+        let spec: ListenerSpec = "udp://[::]:8888".parse().unwrap();
+        assert!(matches!(spec.endpoint, Endpoint::Udp(ref address) if address == "[::]:8888"));
+        assert_eq!(spec.sample, None);
+
+        let spec: ListenerSpec = "tcp://0.0.0.0:8889,sample=clack".parse().unwrap();
+        assert!(matches!(spec.endpoint, Endpoint::Tcp(_)));
+        assert_eq!(spec.sample, Some("clack".parse().unwrap()));
+
+        let spec: ListenerSpec = "localhost:8888".parse().unwrap();
+        assert_eq!(spec.to_string(), "udp://localhost:8888");
+
+        assert!("sctp://localhost:1".parse::<ListenerSpec>().is_err());
+        assert!("udp://localhost:1,volume=11".parse::<ListenerSpec>().is_err());
+    }
+}
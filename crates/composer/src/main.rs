@@ -1,109 +1,523 @@
 #![warn(clippy::all, clippy::clone_on_ref_ptr)]
 
 use crate::{
-    audio_output::AudioOutput,
-    jukebox::{Jukebox, Sample},
+    aggregator::{Aggregate, Aggregator},
+    audio_output::{AudioOutput, Meters},
+    config::SoundConfig,
+    controls::{Command, Controls},
+    dashboard::{report_error, Dashboard},
+    effects::Effects,
+    jukebox::{Jukebox, Voice},
+    listener::{Incoming, ListenerSpec},
+    midi::{MidiExport, MidiMapping},
+    osc::OscExport,
+    pipeline::{Mapping, Pipeline, Sink},
+    quantizer::Quantizer,
+    recorder::Recorder,
+    render::WavRender,
+    spatial::Spatial,
+    stats::Stats,
 };
 use clap::Parser;
-use composer_api::{util::current_timestamp, EventKind, Packet, DEFAULT_SERVER_ADDRESS};
-use eyre::{Context, Result};
+use composer_api::{
+    auth::{SharedKey, Unauthenticated},
+    capture::Record,
+    catalogue::CATALOGUE,
+    util::current_timestamp,
+    DEFAULT_CONTROL_ADDRESS, DEFAULT_SERVER_ADDRESS,
+};
+use eyre::{bail, ensure, Context, Result};
 use std::{
-    net::UdpSocket,
-    time::{Duration, Instant},
+    path::PathBuf,
+    sync::{
+        mpsc::{self, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    time::Duration,
 };
 
+/// Where `--metrics` is served when given without an address.
+const DEFAULT_METRICS_ADDRESS: &str = "localhost:8891";
+
+mod aggregator;
 mod audio_output;
+mod config;
+mod controls;
+mod dashboard;
+mod effects;
+mod histogram;
 mod jukebox;
+mod listener;
+mod metrics;
+mod midi;
+mod mixer;
+mod music;
+mod osc;
+mod pipeline;
+mod quantizer;
+mod recorder;
+mod render;
+mod spatial;
+mod spsc;
+mod stats;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// the address to listen on for incoming events
+    /// the UDP address to listen on for incoming events
     address: Option<String>,
 
+    /// Additional endpoints to listen on, can be given multiple times. Format is
+    /// `<udp|tcp|unix>://<address>[,sample=<name>]`, where `sample` overrides the sound of
+    /// all events received on the endpoint.
+    #[arg(short, long)]
+    listen: Vec<ListenerSpec>,
+
     /// Delay event timestamps by this amount during playback. Should be larger than audio buffer
     /// period time plus the sound card latency.
     #[arg(short, long, default_value_t = 200)]
     delay_ms: u64,
+
+    /// Only accept packets signed with the pre-shared key stored in this file.
+    #[arg(short, long)]
+    key_file: Option<PathBuf>,
+
+    /// Length of the time window in which events of each kind are counted for aggregation.
+    #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+    aggregate_window_ms: u64,
+
+    /// Events of a kind above this count per window are aggregated into a single louder sound.
+    #[arg(long, default_value_t = 20)]
+    aggregate_threshold: usize,
+
+    /// Rhythmic mode: quantize events to a beat grid of this tempo and play all events of a kind
+    /// in a grid slot as a single hit, louder with more events. Replaces aggregation.
+    #[arg(long)]
+    tempo_bpm: Option<f64>,
+
+    /// Number of grid slots per beat in the rhythmic mode, 4 means sixteenth notes.
+    #[arg(long, default_value_t = 4, requires = "tempo_bpm")]
+    subdivision: u32,
+
+    /// Write played notes to this Standard MIDI File, see the `[midi]` section of the sound
+    /// configuration.
+    #[arg(long)]
+    midi_out: Option<PathBuf>,
+
+    /// Send played notes to a virtual MIDI port, where available.
+    #[arg(long)]
+    midi_port: bool,
+
+    /// Forward played voices as Open Sound Control bundles to this UDP address, e.g. to render
+    /// them in SuperCollider, Max or Pure Data.
+    #[arg(long)]
+    osc_out: Option<String>,
+
+    /// Render played voices to this WAV file, with a channel for each speaker of the spatial
+    /// layout of the sound configuration, or in stereo.
+    #[arg(long)]
+    render: Option<PathBuf>,
+
+    /// Don't play any sound, useful when notes or OSC messages are rendered elsewhere.
+    #[arg(long)]
+    no_audio: bool,
+
+    /// Append every received packet to this capture file, for later analysis or replay.
+    #[arg(long)]
+    record: Option<PathBuf>,
+
+    /// Rotate the capture file once it grows over this many megabytes.
+    #[arg(long, requires = "record")]
+    record_rotate_size_mb: Option<u64>,
+
+    /// Rotate the capture file after this many seconds.
+    #[arg(long, requires = "record")]
+    record_rotate_interval_s: Option<u64>,
+
+    /// Sound configuration file mapping event kinds to samples. It is reloaded on change.
+    #[arg(short, long, default_value = "sounds.toml")]
+    config: PathBuf,
+
+    /// Accept control commands (mute, solo, gain...) on this TCP address, see `composer-ctl`.
+    /// Defaults to the local-only address when given without a value.
+    #[arg(long, num_args = 0..=1, default_missing_value = DEFAULT_CONTROL_ADDRESS)]
+    control: Option<String>,
+
+    /// Show a live dashboard in the terminal instead of printing statistics. Event kinds can be
+    /// muted and soloed from the dashboard.
+    #[arg(long)]
+    tui: bool,
+
+    /// Print statistics as JSON lines instead of text.
+    #[arg(long, conflicts_with = "tui")]
+    stats_json: bool,
+
+    /// Serve statistics in the Prometheus text format at `http://<address>/metrics`. Defaults
+    /// to a local-only address when given without a value.
+    #[arg(long, num_args = 0..=1, default_missing_value = DEFAULT_METRICS_ADDRESS)]
+    metrics: Option<String>,
+
+    /// Print the catalogue of event kinds with their fields, typical rates and probes as JSON
+    /// lines, and exit.
+    #[arg(long)]
+    list_event_kinds: bool,
+}
+
+/// Messages sent to the main thread by listener and control threads.
+pub(crate) enum Message {
+    Incoming(Incoming),
+    /// A control command and a channel for the response line.
+    Control(Command, Sender<String>),
+    /// The sound configuration has changed and was reloaded.
+    Reload(Result<Box<SoundConfig>>),
+    /// The user asked to quit through the dashboard.
+    Quit,
 }
 
 fn main() -> Result<()> {
     color_eyre::install()?;
 
     let args = Args::parse();
+    if args.list_event_kinds {
+        for info in CATALOGUE {
+            println!("{}", serde_json::to_string(info)?);
+        }
+        return Ok(());
+    }
+
+    // Checked before anything starts, invalid options shouldn't leave output behind.
+    let aggregator = aggregator(&args)?;
+    let key = args.key_file.map(SharedKey::from_file).transpose()?;
+
+    let mut listeners = args.listen;
+    if listeners.is_empty() || args.address.is_some() {
+        let address = args.address.as_deref().unwrap_or(DEFAULT_SERVER_ADDRESS);
+        listeners.insert(0, address.parse()?);
+    }
+    let (message_tx, message_rx) = mpsc::channel();
+    let mut listener_names = Vec::new();
+    for (index, spec) in listeners.iter().enumerate() {
+        let name = listener::spawn(index, spec, key.clone(), message_tx.clone())?;
+        println!("Listening on {name}");
+        listener_names.push(name);
+    }
+    if let Some(address) = args.control {
+        let address = controls::spawn_server(&address, message_tx.clone())?;
+        println!("Accepting control commands on {address}");
+    }
+    let metrics = args
+        .metrics
+        .map(|address| -> Result<_> {
+            let metrics = Arc::new(Mutex::new(String::new()));
+            let address = metrics::spawn_server(&address, Arc::clone(&metrics))?;
+            println!("Serving metrics at http://{address}/metrics");
+            Ok(metrics)
+        })
+        .transpose()?;
+
+    let play_delay = Duration::from_millis(args.delay_ms);
+    let SoundConfig { mut jukebox, mut mapping, mut music, mut midi, mut effects, mut spatial } =
+        SoundConfig::load(&args.config).context("loading sound configuration")?;
+    // The speakers of the spatial layout are the output channels, their number can't change.
+    let output_channels = spatial.as_ref().map(Spatial::channels);
+    let audio_output =
+        (!args.no_audio).then(|| AudioOutput::new(play_delay, output_channels)).transpose()?;
+    if let Some(audio_output) = &audio_output {
+        audio_output.set_samples(jukebox.bank());
+        audio_output.set_effects(&effects);
+    }
+    let mut render = args
+        .render
+        .map(|path| -> Result<_> {
+            let mut render = WavRender::new(path, output_channels.unwrap_or(2))?;
+            render.set_samples(jukebox.bank());
+            render.set_effects(&effects);
+            Ok(render)
+        })
+        .transpose()?;
+    let osc_export = args
+        .osc_out
+        .map(|address| -> Result<_> {
+            let osc_export = OscExport::new(&address, play_delay)?;
+            println!("Sending OSC bundles to {address}");
+            Ok(osc_export)
+        })
+        .transpose()?;
 
-    let socket = UdpSocket::bind(args.address.as_deref().unwrap_or(DEFAULT_SERVER_ADDRESS))?;
-    println!("Listening on {}", socket.local_addr()?);
+    let mut midi_export = None;
+    if args.midi_out.is_some() || args.midi_port {
+        if midi.is_none() {
+            bail!("MIDI export needs a [midi] section in {:?}", args.config);
+        }
+        midi_export = Some(MidiExport::new(args.midi_out, args.midi_port, play_delay)?);
+    }
+    for spec in &listeners {
+        if let Some(sample) = spec.sample.as_ref().filter(|sample| !jukebox.contains(sample)) {
+            bail!("listener {spec} uses sample {sample} not defined in {:?}", args.config);
+        }
+    }
+    config::spawn_watcher(args.config.clone(), message_tx.clone());
+    let dashboard = args
+        .tui
+        .then(|| {
+            let meters = audio_output.as_ref().map(|output| output.meters().clone());
+            Dashboard::start(meters.unwrap_or_else(Meters::silent), message_tx.clone())
+        })
+        .transpose()?;
+    drop(message_tx);
+    let aggregate_window = Duration::from_millis(args.aggregate_window_ms);
+    let mut pipeline = Pipeline::new(aggregator);
 
-    let audio_output = AudioOutput::new(Duration::from_millis(args.delay_ms))?;
+    let mut recorder = args
+        .record
+        .map(|path| {
+            let max_bytes = args.record_rotate_size_mb.map(|megabytes| megabytes * 1_000_000);
+            let max_age = args.record_rotate_interval_s.map(Duration::from_secs);
+            Recorder::new(path, max_bytes, max_age)
+        })
+        .transpose()?;
 
-    let jukebox = Jukebox::new().context("creating jukebox")?;
-    let mut stats = Stats { since: Instant::now(), events: 0, total_bytes: 0 };
+    let mut stats = Stats::new(listener_names);
+    let mut controls = Controls::default();
     loop {
-        match handle_datagram(&socket, &audio_output, &jukebox) {
-            Ok(bytes_received) => stats.record_event(bytes_received, &audio_output),
-            Err(err) => eprintln!("Could not process datagram. Ignoring and continuing. {:?}", err),
+        // Wake up regularly even if no datagrams arrive to flush aggregated voices.
+        let incoming = match message_rx.recv_timeout(aggregate_window) {
+            Ok(Message::Incoming(incoming)) => Ok(incoming),
+            Ok(Message::Control(command, response_tx)) => {
+                let response = match command {
+                    Command::Stats { json } => stats.last_report(json),
+                    Command::Sound(_, sample) if !jukebox.contains(&sample) => {
+                        format!("error: unknown sample {sample}")
+                    },
+                    command => {
+                        let response = controls.apply(command);
+                        if let Some(dashboard) = &dashboard {
+                            dashboard.show_controls(&controls);
+                        }
+                        response
+                    },
+                };
+                // The client may have hung up in the meantime, nothing to do about it.
+                let _ = response_tx.send(response);
+                continue;
+            },
+            Ok(Message::Reload(config)) => {
+                let checked = config.and_then(|config| {
+                    let running = Running { output_channels, midi_export: midi_export.is_some() };
+                    check_reload(&config, &running, &listeners, &controls)?;
+                    Ok(config)
+                });
+                let config = match checked {
+                    Ok(config) => config,
+                    Err(err) => {
+                        let line = "Could not reload sound configuration, keeping the old one.";
+                        report_error(line, &err);
+                        continue;
+                    },
+                };
+                let line = format!("Sound configuration {:?} reloaded.", args.config);
+                match &dashboard {
+                    Some(dashboard) => dashboard.log(line),
+                    None => println!("{line}"),
+                }
+                SoundConfig { jukebox, mapping, music, midi, effects, spatial } = *config;
+                if let Some(audio_output) = &audio_output {
+                    audio_output.set_samples(jukebox.bank());
+                    audio_output.set_effects(&effects);
+                }
+                if let Some(render) = &mut render {
+                    render.set_samples(jukebox.bank());
+                    render.set_effects(&effects);
+                }
+                continue;
+            },
+            Ok(Message::Quit) => break,
+            Err(err) => Err(err),
+        };
+
+        match incoming {
+            Ok(Incoming { listener, source, arrival, result: Ok((packet, bytes_received)) }) => {
+                let record = Record { arrival, source, packet };
+                stats.record_packet(listener, &record, bytes_received);
+                if let Some(recorder) = &mut recorder {
+                    if let Err(err) = recorder.record(&record) {
+                        report_error("Could not record packet.", &err);
+                    }
+                }
+
+                let sample_override = listeners[listener].sample.as_ref();
+                let mapping = sample_override.map_or(Mapping::Kinds(&mapping), Mapping::All);
+                pipeline.process(record, mapping, music.as_ref(), spatial.as_ref(), &controls);
+            },
+            Ok(Incoming { listener, result: Err(err), .. }) if err.is::<Unauthenticated>() => {
+                stats.record_rejected(listener)
+            },
+            Ok(Incoming { listener, source, result: Err(err), .. }) => {
+                stats.record_decode_error(listener);
+                // The dashboard shows the count of invalid datagrams instead.
+                if dashboard.is_none() {
+                    eprintln!(
+                        "Could not process datagram from {source}. Ignoring and continuing. {err:?}"
+                    );
+                }
+            },
+            Err(RecvTimeoutError::Timeout) => {},
+            Err(RecvTimeoutError::Disconnected) => bail!("all listeners have stopped"),
+        }
+        if let Some(recorder) = &mut recorder {
+            if let Err(err) = recorder.flush_if_due() {
+                report_error("Could not flush capture file.", &err);
+            }
+        }
+
+        if let Some(midi_export) = &mut midi_export {
+            if let Err(err) = midi_export.write_if_due() {
+                report_error("Could not write MIDI file.", &err);
+            }
+        }
+
+        if let Some(render) = &mut render {
+            if let Err(err) = render.write_if_due() {
+                report_error("Could not write rendered WAV file.", &err);
+            }
+        }
+
+        let mut outputs = Outputs {
+            jukebox: &mut jukebox,
+            effects: &effects,
+            spatial: spatial.as_ref(),
+            audio_output: audio_output.as_ref(),
+            render: render.as_mut(),
+            midi: midi_export.as_mut().zip(midi.as_ref()),
+            osc_export: osc_export.as_ref(),
+        };
+        pipeline.schedule(current_timestamp(), &mut outputs);
+        if let Some(snapshot) = stats.report_if_due(audio_output.as_ref(), pipeline.aggregator()) {
+            match &dashboard {
+                Some(dashboard) => dashboard.update(snapshot.clone()),
+                None if args.stats_json => println!("{}", snapshot.to_json()),
+                None => snapshot.print(),
+            }
+            if let Some(metrics) = &metrics {
+                *metrics.lock().expect("metrics lock should not be poisoned") = stats.prometheus();
+            }
         }
     }
+    Ok(())
 }
 
-/// Block until next datagram is received and handle it. Returns its size in bytes.
-fn handle_datagram(
-    socket: &UdpSocket,
-    audio_output: &AudioOutput,
-    jukebox: &Jukebox,
-) -> Result<usize> {
-    // Size up to max normal network packet size
-    let mut buf = [0; 1500];
-    let (number_of_bytes, _) = socket.recv_from(&mut buf)?;
-
-    let packet: Packet = bincode::deserialize(&buf[..number_of_bytes])?;
-
-    for event in packet.events {
-        let sample = match event.kind {
-            EventKind::TestTick => Sample::Clack,
-
-            // TODO(Matej): add different sounds for these, and vary some their quality based on length.
-            EventKind::StderrWrite { length: _ }
-            | EventKind::StdoutWrite { length: _ }
-            | EventKind::FileSystemWrite
-            | EventKind::FileSystemRead => Sample::Click,
-            // TODO(Pablo): Play a sound that scales with the number of reports.
-            EventKind::Log { level: _ } => todo!(),
-            EventKind::LogStats(_) => todo!(),
-        };
-        let timestamp = event.timestamp.unwrap_or_else(current_timestamp);
-        jukebox.play(audio_output, sample, timestamp);
+/// What a reloaded sound configuration must stay compatible with.
+struct Running {
+    /// Number of output channels the audio output and the render were opened with.
+    output_channels: Option<u16>,
+    midi_export: bool,
+}
+
+/// Check that a reloaded configuration can replace the running one: it has the same speakers and
+/// defines the samples listeners and control rules play, so that they don't go silent.
+fn check_reload(
+    config: &SoundConfig,
+    running: &Running,
+    listeners: &[ListenerSpec],
+    controls: &Controls,
+) -> Result<()> {
+    ensure!(
+        config.spatial.as_ref().map(Spatial::channels) == running.output_channels,
+        "changing the number of speakers needs a restart"
+    );
+    ensure!(config.midi.is_some() || !running.midi_export, "MIDI export needs a [midi] section");
+    for spec in listeners {
+        if let Some(sample) = spec.sample.as_ref().filter(|sample| !config.jukebox.contains(sample))
+        {
+            bail!("listener {spec} uses sample {sample} which is no longer defined");
+        }
     }
+    if let Some(sample) = controls.samples().find(|sample| !config.jukebox.contains(sample)) {
+        bail!("control rules use sample {sample} which is no longer defined, reset them first");
+    }
+    Ok(())
+}
 
-    Ok(number_of_bytes)
+/// Where voices are rendered: the sound card and the exports enabled on the command line.
+struct Outputs<'a> {
+    jukebox: &'a mut Jukebox,
+    effects: &'a Effects,
+    spatial: Option<&'a Spatial>,
+    audio_output: Option<&'a AudioOutput>,
+    render: Option<&'a mut WavRender>,
+    midi: Option<(&'a mut MidiExport, &'a MidiMapping)>,
+    osc_export: Option<&'a OscExport>,
 }
 
-struct Stats {
-    since: Instant,
-    events: usize,
-    total_bytes: usize,
+/// The quantizer of the rhythmic mode if a tempo is given, the aggregator otherwise.
+fn aggregator(args: &Args) -> Result<Box<dyn Aggregate>> {
+    let Some(tempo_bpm) = args.tempo_bpm else {
+        let window = Duration::from_millis(args.aggregate_window_ms);
+        return Ok(Box::new(Aggregator::new(window, args.aggregate_threshold)));
+    };
+    ensure!(
+        Quantizer::slot(tempo_bpm, args.subdivision).is_some(),
+        "tempo {tempo_bpm} with subdivision {} should be positive and give grid slots of at \
+            least {:?}",
+        args.subdivision,
+        Quantizer::MIN_SLOT
+    );
+    Ok(Box::new(Quantizer::new(tempo_bpm, args.subdivision)))
 }
 
-impl Stats {
-    const REPORT_EVERY: Duration = Duration::from_secs(1);
+impl Sink for Outputs<'_> {
+    fn play(&mut self, voice: Voice) {
+        if let Some((midi_export, midi)) = &mut self.midi {
+            if let Err(err) = midi_export.export(midi, &voice) {
+                report_error("Could not write MIDI file.", &err);
+            }
+        }
+        if let Some(osc_export) = self.osc_export {
+            if let Err(err) = osc_export.export(&voice) {
+                report_error("Could not send OSC bundle.", &err);
+            }
+        }
+        if self.audio_output.is_none() && self.render.is_none() {
+            return;
+        }
+        let Some(descriptor) = self.jukebox.descriptor(&voice, self.effects, self.spatial) else {
+            return;
+        };
+        if let Some(render) = &mut self.render {
+            if let Err(err) = render.add(descriptor, current_timestamp()) {
+                report_error("Could not write rendered WAV file.", &err);
+            }
+        }
+        if let Some(audio_output) = self.audio_output {
+            audio_output.play(descriptor);
+        }
+    }
+}
 
-    fn record_event(&mut self, bytes_received: usize, audio_output: &AudioOutput) {
-        self.events += 1;
-        self.total_bytes += bytes_received;
+#[cfg(test)]
+mod test {
+    use super::*;
 
-        let elapsed = self.since.elapsed();
-        if elapsed >= Self::REPORT_EVERY {
-            println!(
-                "Received {} events ({} bytes) in last {elapsed:.2?}, {} too early plays.",
-                self.events,
-                self.total_bytes,
-                audio_output.fetch_too_early_plays(),
-            );
+    fn parse(args: &[&str]) -> Result<Args> {
+        Ok(Args::try_parse_from([&["composer"], args].concat())?)
+    }
 
-            self.since = Instant::now();
-            self.events = 0;
-            self.total_bytes = 0;
+    #[test]
+    fn rejects_invalid_tempos() {
+        for tempo in ["120", "900"] {
+            assert!(aggregator(&parse(&["--tempo-bpm", tempo]).unwrap()).is_ok(), "{tempo}");
+        }
+        for tempo in ["nan", "inf", "-inf", "0", "-120", "1e9"] {
+            let args = parse(&[&format!("--tempo-bpm={tempo}")]).unwrap();
+            assert!(aggregator(&args).is_err(), "{tempo}");
         }
+        let args = parse(&["--tempo-bpm", "120", "--subdivision", "0"]).unwrap();
+        assert!(aggregator(&args).is_err());
+    }
+
+    #[test]
+    fn rejects_empty_aggregation_window() {
+        assert!(aggregator(&parse(&["--aggregate-window-ms", "1"]).unwrap()).is_ok());
+        assert!(parse(&["--aggregate-window-ms", "0"]).is_err());
     }
 }
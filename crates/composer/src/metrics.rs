@@ -0,0 +1,82 @@
+use crate::dashboard::report_error;
+use eyre::{Context, Result};
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+/// Spawn a thread serving `metrics`, a Prometheus text exposition kept up to date by the main
+/// thread, over HTTP at `/metrics` on `address`.
+pub(crate) fn spawn_server(address: &str, metrics: Arc<Mutex<String>>) -> Result<SocketAddr> {
+    let listener = TcpListener::bind(address).with_context(|| format!("binding {address}"))?;
+    let local_address = listener.local_addr()?;
+    thread::spawn(move || {
+        // Scrapes are rare and quick, serve them one by one.
+        for stream in listener.incoming() {
+            let result = stream.map_err(Into::into).and_then(|stream| serve(stream, &metrics));
+            if let Err(err) = result {
+                report_error("Could not serve metrics:", &err);
+            }
+        }
+    });
+    Ok(local_address)
+}
+
+fn serve(stream: TcpStream, metrics: &Mutex<String>) -> Result<()> {
+    // Don't let a stuck client block other scrapes.
+    stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+    let mut writer = stream.try_clone()?;
+
+    let mut lines = BufReader::new(stream).lines();
+    let request_line = lines.next().transpose()?.unwrap_or_default();
+    // Skip the headers, we don't need any of them.
+    for line in lines {
+        if line?.is_empty() {
+            break;
+        }
+    }
+
+    let (status, body) = match request_line.split_whitespace().collect::<Vec<_>>()[..] {
+        ["GET", "/metrics" | "/", _] => {
+            ("200 OK", metrics.lock().expect("metrics lock should not be poisoned").clone())
+        },
+        ["GET", ..] => ("404 Not Found", "not found, try /metrics\n".to_string()),
+        _ => ("405 Method Not Allowed", "only GET is supported\n".to_string()),
+    };
+    write!(
+        writer,
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}",
+        body.len(),
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Read;
+
+    fn get(address: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(stream, "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn serves_metrics() {
+        let metrics = Arc::new(Mutex::new("composer_active_voices 3\n".to_string()));
+        let address = spawn_server("127.0.0.1:0", Arc::clone(&metrics)).unwrap();
+
+        let response = get(address, "/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.ends_with("\r\n\r\ncomposer_active_voices 3\n"), "{response}");
+
+        assert!(get(address, "/other").starts_with("HTTP/1.1 404"));
+    }
+}
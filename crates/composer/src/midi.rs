@@ -0,0 +1,393 @@
+//! Export of played voices as MIDI notes, to a Standard MIDI File and to a virtual MIDI port, so
+//! that real synthesizers and DAWs can be used as the sound engine.
+
+use crate::{
+    config::check_kind,
+    dashboard::{report, report_error},
+    jukebox::Voice,
+};
+use composer_api::util::current_timestamp;
+use eyre::{ensure, Context, Result};
+use serde::Deserialize;
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    fs::File,
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::PathBuf,
+    sync::mpsc::{self, RecvTimeoutError, Sender},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// The `[midi]` section of the sound configuration, see `sounds.toml`.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct MidiConfig {
+    #[serde(default = "default_note_length_ms")]
+    note_length_ms: u64,
+    /// Channel and note of each event kind, kinds not listed are not exported.
+    kinds: HashMap<String, NoteConfig>,
+}
+
+fn default_note_length_ms() -> u64 {
+    100
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct NoteConfig {
+    /// MIDI channel from 1 to 16.
+    channel: u8,
+    /// MIDI note number, 60 is the middle C.
+    note: u8,
+}
+
+/// Validated [MidiConfig].
+#[derive(Debug)]
+pub(crate) struct MidiMapping {
+    note_length: Duration,
+    /// Zero-based channel and note number by event kind.
+    kinds: HashMap<String, (u8, u8)>,
+}
+
+impl MidiMapping {
+    pub(crate) fn new(config: MidiConfig) -> Result<Self> {
+        let mut kinds = HashMap::new();
+        for (kind, NoteConfig { channel, note }) in config.kinds {
+            check_kind(&kind)?;
+            ensure!((1..=16).contains(&channel), "MIDI channel of {kind} must be from 1 to 16");
+            ensure!(note <= 127, "MIDI note of {kind} must be from 0 to 127");
+            kinds.insert(kind, (channel - 1, note));
+        }
+        Ok(Self { note_length: Duration::from_millis(config.note_length_ms), kinds })
+    }
+
+    /// Note on and note off messages for a voice, `None` if its kind is not mapped. The note is
+    /// shifted by the pitch of the voice and its velocity follows the gain.
+    fn messages(&self, voice: &Voice) -> Option<([u8; 3], [u8; 3])> {
+        let &(channel, note) = self.kinds.get(voice.kind)?;
+        let semitones = (voice.rate.log2() * 12.0).round() as i32;
+        let note = (note as i32 + semitones).clamp(0, 127) as u8;
+        let velocity = (voice.gain * 100.0).round().clamp(1.0, 127.0) as u8;
+        Some(([0x90 | channel, note, velocity], [0x80 | channel, note, 0]))
+    }
+}
+
+/// Sends voices to the MIDI outputs enabled on the command line.
+pub(crate) struct MidiExport {
+    file: Option<MidiFile>,
+    port: Option<Sender<(Duration, [u8; 3])>>,
+    play_delay: Duration,
+}
+
+/// Name of the virtual MIDI port synthesizers can connect to.
+const PORT_NAME: &str = "acoustic profiler";
+
+impl MidiExport {
+    /// Write notes to the Standard MIDI File at `path` if given. Create a virtual MIDI port if
+    /// `port` is set, or print a warning if the platform does not support that.
+    pub(crate) fn new(path: Option<PathBuf>, port: bool, play_delay: Duration) -> Result<Self> {
+        let port = port
+            .then(|| match spawn_port() {
+                Ok(port_tx) => {
+                    println!("Sending MIDI notes to virtual port {PORT_NAME:?}");
+                    Some(port_tx)
+                },
+                Err(err) => {
+                    report(format!(
+                        "MIDI port not available, not sending live MIDI notes. {err:#}"
+                    ));
+                    None
+                },
+            })
+            .flatten();
+        let file = path.map(MidiFile::new).transpose()?;
+        Ok(Self { file, port, play_delay })
+    }
+
+    pub(crate) fn export(&mut self, mapping: &MidiMapping, voice: &Voice) -> Result<()> {
+        let Some((note_on, note_off)) = mapping.messages(voice) else {
+            return Ok(());
+        };
+
+        let start = voice.timestamp + self.play_delay;
+        let end = start + mapping.note_length;
+        if let Some(port_tx) = &self.port {
+            // The port thread only stops if the port is broken, nothing to do then.
+            let _ = port_tx.send((start, note_on));
+            let _ = port_tx.send((end, note_off));
+        }
+        if let Some(file) = &mut self.file {
+            file.add(start, note_on)?;
+            file.add(end, note_off)?;
+        }
+        Ok(())
+    }
+
+    /// Complete the MIDI file once in a while so that it is valid even if we are killed.
+    pub(crate) fn write_if_due(&mut self) -> Result<()> {
+        match &mut self.file {
+            Some(file)
+                if file.changed
+                    && file.written_at.is_none_or(|at| at.elapsed() >= MidiFile::WRITE_EVERY) =>
+            {
+                file.write_end()
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Standard MIDI File with a single track, time 0 is the first message. Messages are streamed to
+/// the file as delta times, the end of the track and its length are brought up to date once in a
+/// while and when dropped.
+struct MidiFile {
+    path: PathBuf,
+    file: BufWriter<File>,
+    /// Messages not written yet and their UNIX timestamps, see [Self::REORDER].
+    pending: BinaryHeap<Reverse<(Duration, [u8; 3])>>,
+    /// The latest timestamp added.
+    latest: Duration,
+    /// Timestamp of time 0 of the track.
+    start: Option<Duration>,
+    /// Tick of the last message written.
+    tick: u64,
+    /// Size of the track written so far, without its end.
+    track_bytes: u32,
+    /// Whether the track reached the size limit of the format and no more messages are written.
+    full: bool,
+    /// Whether messages were written since the end of the track was.
+    changed: bool,
+    written_at: Option<Instant>,
+}
+
+impl MidiFile {
+    /// Messages are written this long after the latest added one, so that note offs of earlier
+    /// notes are written before note ons of later ones.
+    const REORDER: Duration = Duration::from_secs(1);
+    const WRITE_EVERY: Duration = Duration::from_secs(5);
+
+    fn new(path: PathBuf) -> Result<Self> {
+        let file = File::create(&path).with_context(|| format!("creating {path:?}"))?;
+        let mut file = BufWriter::new(file);
+        file.write_all(&header(0))?;
+        let mut tempo = vec![0x00, 0xff, 0x51, 0x03];
+        tempo.extend_from_slice(&MICROSECONDS_PER_QUARTER.to_be_bytes()[1..]);
+        file.write_all(&tempo)?;
+        Ok(Self {
+            path,
+            file,
+            pending: BinaryHeap::new(),
+            latest: Duration::ZERO,
+            start: None,
+            tick: 0,
+            track_bytes: tempo.len() as u32,
+            full: false,
+            changed: false,
+            written_at: None,
+        })
+    }
+
+    fn add(&mut self, timestamp: Duration, message: [u8; 3]) -> Result<()> {
+        self.pending.push(Reverse((timestamp, message)));
+        self.latest = self.latest.max(timestamp);
+        self.write_until(self.latest.saturating_sub(Self::REORDER))
+    }
+
+    /// Write pending messages up to `until` in the order of their timestamps. Note offs sort
+    /// before note ons at the same time, so that repeated notes are not cut.
+    fn write_until(&mut self, until: Duration) -> Result<()> {
+        while let Some(&Reverse((timestamp, message))) = self.pending.peek() {
+            if timestamp > until {
+                break;
+            }
+            self.pending.pop();
+            let start = *self.start.get_or_insert(timestamp);
+            // Messages added later than the reordering allows are moved to the last one.
+            let tick = (timestamp.saturating_sub(start).as_millis() as u64).max(self.tick);
+            let mut event = Vec::new();
+            let mut delta = tick - self.tick;
+            // Longer delta times than the format allows are bridged by empty text events.
+            while delta > MAX_DELTA as u64 {
+                write_variable_length(&mut event, MAX_DELTA);
+                event.extend_from_slice(&[0xff, 0x01, 0x00]);
+                delta -= MAX_DELTA as u64;
+            }
+            write_variable_length(&mut event, delta as u32);
+            event.extend_from_slice(&message);
+
+            let track_bytes = self.track_bytes as u64 + event.len() as u64;
+            if track_bytes + END_OF_TRACK.len() as u64 > MAX_TRACK_BYTES as u64 {
+                self.full = true;
+                continue;
+            }
+            self.file.write_all(&event)?;
+            self.track_bytes = track_bytes as u32;
+            self.tick = tick;
+            self.changed = true;
+        }
+        Ok(())
+    }
+
+    /// Append the end of the track and update its length, the next messages overwrite the end.
+    fn write_end(&mut self) -> Result<()> {
+        let track_bytes = self.track_bytes + END_OF_TRACK.len() as u32;
+        self.file.write_all(&END_OF_TRACK)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&header(track_bytes))?;
+        self.file.seek(SeekFrom::End(-(END_OF_TRACK.len() as i64)))?;
+        self.file.flush().with_context(|| format!("writing {:?}", self.path))?;
+        self.changed = false;
+        self.written_at = Some(Instant::now());
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.write_until(Duration::MAX)?;
+        if self.full {
+            report(format!("MIDI file {:?} reached the 4 GiB limit and was cut.", self.path));
+        }
+        self.write_end()
+    }
+}
+
+impl Drop for MidiFile {
+    fn drop(&mut self) {
+        if let Err(err) = self.finish() {
+            report_error("Could not write MIDI file.", &err);
+        }
+    }
+}
+
+/// Ticks per quarter note, together with the tempo of 120 BPM makes 1 tick per millisecond.
+const TICKS_PER_QUARTER: u16 = 500;
+const MICROSECONDS_PER_QUARTER: u32 = 500_000;
+/// The largest delta time a variable-length quantity can hold.
+const MAX_DELTA: u32 = 0x0fff_ffff;
+const END_OF_TRACK: [u8; 4] = [0x00, 0xff, 0x2f, 0x00];
+/// Size of the [header()] in bytes.
+const HEADER_BYTES: u32 = 22;
+/// The length of the track is 32-bit.
+const MAX_TRACK_BYTES: u32 = u32::MAX - HEADER_BYTES;
+
+/// Header of a Standard MIDI File with a single track of `track_bytes`, which follows it.
+fn header(track_bytes: u32) -> Vec<u8> {
+    let mut header = b"MThd".to_vec();
+    header.extend_from_slice(&6u32.to_be_bytes());
+    // Format 0, one track.
+    header.extend_from_slice(&[0, 0, 0, 1]);
+    header.extend_from_slice(&TICKS_PER_QUARTER.to_be_bytes());
+    header.extend_from_slice(b"MTrk");
+    header.extend_from_slice(&track_bytes.to_be_bytes());
+    header
+}
+
+/// Write the variable-length quantity used for delta times, 7 bits per byte, most significant
+/// first, all bytes but the last one have the top bit set.
+fn write_variable_length(out: &mut Vec<u8>, value: u32) {
+    let mut bytes = vec![(value & 0x7f) as u8];
+    let mut value = value >> 7;
+    while value > 0 {
+        bytes.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    out.extend(bytes.iter().rev());
+}
+
+/// Create a virtual MIDI port and spawn a thread that sends messages to it at their timestamps.
+#[cfg(unix)]
+fn spawn_port() -> Result<Sender<(Duration, [u8; 3])>> {
+    use midir::{os::unix::VirtualOutput, MidiOutput};
+
+    let output = MidiOutput::new(PORT_NAME)?;
+    let mut connection =
+        output.create_virtual(PORT_NAME).map_err(|err| eyre::eyre!("creating port: {err}"))?;
+
+    let (message_tx, message_rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut queue: BinaryHeap<Reverse<(Duration, [u8; 3])>> = BinaryHeap::new();
+        loop {
+            let received = match queue.peek() {
+                Some(Reverse((due, _))) => {
+                    message_rx.recv_timeout(due.saturating_sub(current_timestamp()))
+                },
+                None => message_rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            match received {
+                Ok(message) => queue.push(Reverse(message)),
+                Err(RecvTimeoutError::Timeout) => {},
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+
+            let now = current_timestamp();
+            while queue.peek().is_some_and(|Reverse((due, _))| *due <= now) {
+                let Reverse((_, message)) = queue.pop().expect("queue should not be empty");
+                if let Err(err) = connection.send(&message) {
+                    report(format!("Could not send MIDI message, stopping. {err}"));
+                    return;
+                }
+            }
+        }
+    });
+    Ok(message_tx)
+}
+
+#[cfg(not(unix))]
+fn spawn_port() -> Result<Sender<(Duration, [u8; 3])>> {
+    eyre::bail!("virtual MIDI ports are not supported on this platform")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn maps_voices_to_notes() {
+        let kinds = [("TestTick".to_string(), NoteConfig { channel: 10, note: 42 })];
+        let config = MidiConfig { note_length_ms: 50, kinds: kinds.into() };
+        let mapping = MidiMapping::new(config).unwrap();
+
+        let mut voice = Voice::new("TestTick", "click".parse().unwrap(), Duration::ZERO);
+        voice.gain = 0.5;
+        voice.rate = 2.0;
+        assert_eq!(mapping.messages(&voice), Some(([0x99, 54, 50], [0x89, 54, 0])));
+
+        voice.kind = "StdoutWrite";
+        assert_eq!(mapping.messages(&voice), None);
+
+        let kinds = [("TestTick".to_string(), NoteConfig { channel: 17, note: 42 })];
+        assert!(MidiMapping::new(MidiConfig { note_length_ms: 50, kinds: kinds.into() }).is_err());
+    }
+
+    #[test]
+    fn writes_standard_midi_file() {
+        let path = std::env::temp_dir().join(format!("composer-{}.mid", std::process::id()));
+        let mut file = MidiFile::new(path.clone()).unwrap();
+        let start = Duration::from_secs(1000);
+        // Added out of order, and the last note off a few days later.
+        file.add(start + Duration::from_millis(200), [0x80, 60, 0]).unwrap();
+        file.add(start, [0x90, 60, 100]).unwrap();
+        file.write_end().unwrap();
+        assert_eq!(fs::read(&path).unwrap()[14..22], *b"MTrk\0\0\0\x0b");
+        file.add(start + Duration::from_secs(4 * 86_400), [0x80, 60, 0]).unwrap();
+        drop(file);
+
+        let data = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(&data[..14], b"MThd\0\0\0\x06\0\0\0\x01\x01\xf4");
+        assert_eq!(&data[14..22], b"MTrk\0\0\0\x22");
+        assert_eq!(
+            &data[22..],
+            [
+                0x00, 0xff, 0x51, 0x03, 0x07, 0xa1, 0x20, // tempo
+                0x00, 0x90, 60, 100, // note on
+                0x81, 0x48, 0x80, 60, 0, // note off after 200 ticks
+                0xff, 0xff, 0xff, 0x7f, 0xff, 0x01, 0x00, // 74 hours, empty text
+                0xa4, 0xe5, 0xde, 0x39, 0x80, 60, 0, // note off after the rest of the 4 days
+                0x00, 0xff, 0x2f, 0x00, // end of track
+            ]
+        );
+    }
+}
@@ -0,0 +1,527 @@
+//! Real-time safe mixing of voices: samples are decoded up front, voices come as plain
+//! [VoiceDescriptor]s and play from preallocated slots, so that rendering never allocates or
+//! frees memory. Used by the audio thread and by offline renders.
+
+use crate::effects::{Chain, VoiceFilters};
+use rodio::Source;
+use std::{cmp::Reverse, collections::BinaryHeap, sync::Arc, time::Duration};
+
+/// Most output channels a voice can be panned to, see [crate::spatial].
+pub(crate) const MAX_CHANNELS: usize = 64;
+/// Most frames rendered at once, longer buffers are rendered in blocks of this size.
+pub(crate) const BLOCK_FRAMES: usize = 256;
+/// Voices cut short by their duration fade out over this time rather than click.
+const FADE_OUT: Duration = Duration::from_millis(5);
+
+/// A decoded sample file, mixed down to mono.
+pub(crate) struct SampleData {
+    frames: Box<[f32]>,
+    sample_rate: u32,
+}
+
+impl SampleData {
+    pub(crate) fn new(source: impl Source<Item = f32>) -> Self {
+        let channels = source.channels().max(1) as usize;
+        let sample_rate = source.sample_rate();
+        let samples: Vec<f32> = source.collect();
+        let frames = samples
+            .chunks(channels)
+            .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+            .collect();
+        Self { frames, sample_rate }
+    }
+}
+
+/// All samples of a sound configuration, voices refer to them by index.
+pub(crate) type SampleBank = Arc<[SampleData]>;
+
+/// Everything needed to play a voice, see [crate::jukebox::Voice]. Plain data that can be
+/// passed to the audio thread without allocations.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct VoiceDescriptor {
+    /// Index of the sample in the [SampleBank].
+    pub(crate) sample: usize,
+    /// UNIX timestamp to start playing at.
+    pub(crate) timestamp: Duration,
+    /// Seconds of the start of the sample to skip.
+    pub(crate) offset: f32,
+    /// Play at most this many seconds, infinity to play the whole sample. Voices cut short fade
+    /// out over their last [FADE_OUT].
+    pub(crate) duration: f32,
+    pub(crate) gain: f32,
+    /// Playback speed multiplier, also changes the pitch.
+    pub(crate) rate: f32,
+    /// Gain of each output channel.
+    pub(crate) channel_gains: [f32; MAX_CHANNELS],
+    pub(crate) low_pass_hz: Option<f32>,
+    pub(crate) high_pass_hz: Option<f32>,
+    /// Event kind of the voice, it plays through the bus of the kind in the [Chain] if any.
+    pub(crate) kind: &'static str,
+}
+
+impl Default for VoiceDescriptor {
+    fn default() -> Self {
+        Self {
+            sample: 0,
+            timestamp: Duration::ZERO,
+            offset: 0.0,
+            duration: f32::INFINITY,
+            gain: 1.0,
+            rate: 1.0,
+            channel_gains: [0.0; MAX_CHANNELS],
+            low_pass_hz: None,
+            high_pass_hz: None,
+            kind: "",
+        }
+    }
+}
+
+/// A voice scheduled or playing.
+#[derive(Default, Clone, Copy)]
+struct Slot {
+    descriptor: VoiceDescriptor,
+    start_frame: u64,
+    /// Generation of the [SampleBank] of the voice.
+    generation: u64,
+    /// Index of the bus of the kind of the voice in the current [Chain], `None` to play dry.
+    bus: Option<usize>,
+    /// Position in the sample, in its frames.
+    position: f64,
+    /// Advance of the position per output frame.
+    step: f64,
+    /// Position where the voice ends.
+    end: f64,
+    /// Length of the fade out before the end in positions, 0 for none.
+    fade: f64,
+    filters: VoiceFilters,
+}
+
+/// Mixes voices at the frames they start at. Voices wait in a priority queue keyed by the frame
+/// they start at, unlike delaying each source by silence waiting voices cost nothing per sample.
+pub(crate) struct Mixer {
+    sample_rate: u32,
+    channels: usize,
+    /// Number of frames rendered so far, i.e. the position of the next frame.
+    frame: u64,
+    slots: Box<[Slot]>,
+    /// Indices of slots not in use.
+    free: Vec<usize>,
+    /// Start frames, order of arrival and slots of scheduled voices, earliest first.
+    pending: BinaryHeap<Reverse<(u64, u64, usize)>>,
+    sequence: u64,
+    /// Indices of slots of voices playing.
+    playing: Vec<usize>,
+    samples: SampleBank,
+    generation: u64,
+    /// The previous bank, kept until its voices finish.
+    retired: Option<SampleBank>,
+    chain: Box<Chain>,
+    dry: Vec<f32>,
+    send: Vec<f32>,
+}
+
+impl Mixer {
+    /// A mixer with room for `capacity` voices scheduled or playing at once.
+    pub(crate) fn new(
+        sample_rate: u32,
+        channels: u16,
+        capacity: usize,
+        samples: SampleBank,
+        chain: Box<Chain>,
+    ) -> Self {
+        let channels = (channels as usize).clamp(1, MAX_CHANNELS);
+        Self {
+            sample_rate,
+            channels,
+            frame: 0,
+            slots: vec![Slot::default(); capacity].into(),
+            free: (0..capacity).rev().collect(),
+            pending: BinaryHeap::with_capacity(capacity),
+            sequence: 0,
+            playing: Vec::with_capacity(capacity),
+            samples,
+            generation: 0,
+            retired: None,
+            chain,
+            dry: vec![0.0; BLOCK_FRAMES * channels],
+            send: vec![0.0; BLOCK_FRAMES * channels],
+        }
+    }
+
+    /// Position of the next frame to render.
+    pub(crate) fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    /// Number of voices scheduled or playing.
+    pub(crate) fn voices(&self) -> usize {
+        self.slots.len() - self.free.len()
+    }
+
+    /// Replace the samples for voices scheduled from now on. Voices of the current samples play
+    /// on, and the samples are returned by [Self::collect_garbage()] once they finish. Returns
+    /// the samples replaced before, cutting off their voices if any still play.
+    pub(crate) fn set_samples(&mut self, samples: SampleBank) -> Option<SampleBank> {
+        let previous = self.generation.checked_sub(1);
+        let Self { slots, free, playing, pending, .. } = self;
+        let mut keep = |index: usize| {
+            let stale = Some(slots[index].generation) == previous;
+            if stale {
+                free.push(index);
+            }
+            !stale
+        };
+        playing.retain(|&index| keep(index));
+        pending.retain(|Reverse((_, _, index))| keep(*index));
+
+        let retired = self.retired.replace(std::mem::replace(&mut self.samples, samples));
+        self.generation += 1;
+        retired
+    }
+
+    /// Replace the effects, returning the current ones. Echoes and reverb ring on where their
+    /// configuration stays the same and are cut off otherwise, voices playing move to the buses
+    /// of their kinds in the new effects.
+    pub(crate) fn set_chain(&mut self, mut chain: Box<Chain>) -> Box<Chain> {
+        chain.carry_over(&mut self.chain);
+        let replaced = std::mem::replace(&mut self.chain, chain);
+        for &index in &self.playing {
+            let slot = &mut self.slots[index];
+            slot.bus = self.chain.bus(slot.descriptor.kind);
+        }
+        replaced
+    }
+
+    pub(crate) fn chain(&self) -> &Chain {
+        &self.chain
+    }
+
+    /// Replaced samples no voices play anymore, to be dropped outside of the audio thread.
+    pub(crate) fn collect_garbage(&mut self) -> Option<SampleBank> {
+        self.retired.as_ref()?;
+        let previous = self.generation.checked_sub(1);
+        let mut used =
+            self.playing.iter().chain(self.pending.iter().map(|Reverse((_, _, index))| index));
+        if used.any(|&index| Some(self.slots[index].generation) == previous) {
+            return None;
+        }
+        self.retired.take()
+    }
+
+    /// Schedule a voice to start at `start_frame`, or at the next frame if it is in the past.
+    /// Returns false if there is no free slot for it.
+    pub(crate) fn schedule(&mut self, descriptor: VoiceDescriptor, start_frame: u64) -> bool {
+        let Some(index) = self.free.pop() else {
+            return false;
+        };
+        self.slots[index] =
+            Slot { descriptor, start_frame, generation: self.generation, ..Default::default() };
+        self.sequence += 1;
+        self.pending.push(Reverse((start_frame, self.sequence, index)));
+        true
+    }
+
+    /// Whether there are no voices scheduled or playing.
+    pub(crate) fn is_idle(&self) -> bool {
+        self.pending.is_empty() && self.playing.is_empty()
+    }
+
+    /// Render the next frames of the mix to interleaved `data_out`.
+    pub(crate) fn render(&mut self, data_out: &mut [f32]) {
+        for block in data_out.chunks_mut(BLOCK_FRAMES * self.channels) {
+            self.render_block(block);
+        }
+    }
+
+    fn render_block(&mut self, out: &mut [f32]) {
+        let channels = self.channels;
+        let frames = out.len() / channels;
+        let block_end = self.frame + frames as u64;
+
+        while let Some(&Reverse((start_frame, _, index))) = self.pending.peek() {
+            if start_frame >= block_end {
+                break;
+            }
+            self.pending.pop();
+            if self.start(index) {
+                self.playing.push(index);
+            } else {
+                self.free.push(index);
+            }
+        }
+
+        let (dry, send) = (&mut self.dry[..out.len()], &mut self.send[..out.len()]);
+        dry.fill(0.0);
+        send.fill(0.0);
+        let mut position = 0;
+        while position < self.playing.len() {
+            let index = self.playing[position];
+            let slot = &mut self.slots[index];
+            let samples = if slot.generation == self.generation {
+                &self.samples
+            } else {
+                self.retired.as_ref().expect("voices of retired samples should have them")
+            };
+            let bus = slot.bus.and_then(|bus| self.chain.buses.get_mut(bus));
+            let target = match bus {
+                Some(bus) => &mut bus.buffer[..out.len()],
+                None => &mut *dry,
+            };
+            let first = slot.start_frame.saturating_sub(self.frame) as usize;
+            if render_voice(slot, &samples[slot.descriptor.sample], target, first, channels) {
+                self.playing.swap_remove(position);
+                self.free.push(index);
+            } else {
+                position += 1;
+            }
+        }
+
+        for bus in &mut self.chain.buses {
+            bus.mix_into(dry, send);
+        }
+        for (index, out) in out.iter_mut().enumerate() {
+            *out = self.chain.master.process(index % channels, dry[index], send[index]);
+        }
+        self.frame = block_end;
+    }
+
+    /// Set up the playback of the voice in a slot, false if it can't play.
+    fn start(&mut self, index: usize) -> bool {
+        let slot = &mut self.slots[index];
+        let samples = if slot.generation == self.generation {
+            &self.samples
+        } else {
+            match &self.retired {
+                Some(retired) => retired,
+                None => return false,
+            }
+        };
+        let Some(sample) = samples.get(slot.descriptor.sample) else {
+            return false;
+        };
+
+        let VoiceDescriptor { offset, duration, rate, low_pass_hz, high_pass_hz, .. } =
+            slot.descriptor;
+        slot.step = rate as f64 * sample.sample_rate as f64 / self.sample_rate as f64;
+        slot.position = offset as f64 * sample.sample_rate as f64;
+        let end = slot.position + duration as f64 * self.sample_rate as f64 * slot.step;
+        slot.end = end.min(sample.frames.len() as f64);
+        slot.fade = match end < sample.frames.len() as f64 {
+            true => (FADE_OUT.as_secs_f64() * self.sample_rate as f64 * slot.step)
+                .min(slot.end - slot.position),
+            false => 0.0,
+        };
+        slot.filters = VoiceFilters::new(low_pass_hz, high_pass_hz, self.sample_rate);
+        slot.bus = self.chain.bus(slot.descriptor.kind);
+        true
+    }
+}
+
+/// Mix the voice of `slot` into interleaved `target` from its frame `first` on. Returns true if
+/// the voice ended.
+fn render_voice(
+    slot: &mut Slot,
+    sample: &SampleData,
+    target: &mut [f32],
+    first: usize,
+    channels: usize,
+) -> bool {
+    let gains = &slot.descriptor.channel_gains[..channels];
+    for frame in target.chunks_exact_mut(channels).skip(first) {
+        if slot.position >= slot.end {
+            return true;
+        }
+        // Linear interpolation, good enough for short percussive samples.
+        let index = slot.position as usize;
+        let fraction = (slot.position - index as f64) as f32;
+        let current = sample.frames[index];
+        let next = sample.frames.get(index + 1).copied().unwrap_or(0.0);
+        let mut gain = slot.descriptor.gain;
+        if slot.fade > 0.0 {
+            gain *= ((slot.end - slot.position) / slot.fade).min(1.0) as f32;
+        }
+        let value = slot.filters.process((current + (next - current) * fraction) * gain);
+        for (out, gain) in frame.iter_mut().zip(gains) {
+            *out += value * gain;
+        }
+        slot.position += slot.step;
+    }
+    slot.position >= slot.end
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::effects::{Effects, EffectsConfig};
+    use rodio::{buffer::SamplesBuffer, source::SineWave};
+    use std::time::Instant;
+
+    fn with_effects(effects: &str) -> Mixer {
+        let effects = Effects::new(toml::from_str::<EffectsConfig>(effects).unwrap()).unwrap();
+        let sine = SineWave::new(10.0).take_duration(Duration::from_secs(1));
+        let samples =
+            [SampleData::new(SamplesBuffer::new(1, 1000, vec![1.0, 1.0])), SampleData::new(sine)];
+        Mixer::new(1000, 2, 16, samples.into(), Box::new(effects.chain(1000, 2, BLOCK_FRAMES)))
+    }
+
+    fn voice(sample: usize) -> VoiceDescriptor {
+        let mut channel_gains = [0.0; MAX_CHANNELS];
+        channel_gains[..2].copy_from_slice(&[0.5, 0.25]);
+        VoiceDescriptor { sample, channel_gains, ..Default::default() }
+    }
+
+    /// Render `frames` frames of the left channel.
+    fn render(mixer: &mut Mixer, frames: usize) -> Vec<f32> {
+        let mut data = vec![0.0; frames * 2];
+        mixer.render(&mut data);
+        data.into_iter().step_by(2).collect()
+    }
+
+    #[test]
+    fn mixes_voices_at_their_frame() {
+        let mut mixer = with_effects("");
+        assert!(mixer.schedule(voice(0), 7));
+        assert!(mixer.schedule(VoiceDescriptor { gain: 0.5, ..voice(0) }, 2));
+        assert!(mixer.schedule(VoiceDescriptor { rate: 0.5, ..voice(0) }, 2));
+        assert_eq!(mixer.voices(), 3);
+
+        // Rendered in two buffers, the first voice starts in the latter.
+        let mut left = render(&mut mixer, 5);
+        left.extend(render(&mut mixer, 5));
+        assert_eq!(left, [0.0, 0.0, 0.75, 0.75, 0.5, 0.25, 0.0, 0.5, 0.5, 0.0]);
+        assert_eq!(mixer.voices(), 0);
+
+        // Samples replaced while a voice is scheduled are kept until it finishes.
+        assert!(mixer.schedule(voice(0), 12));
+        assert!(mixer.set_samples(Arc::new([])).is_none());
+        assert!(mixer.collect_garbage().is_none());
+        assert_eq!(render(&mut mixer, 5), [0.0, 0.0, 0.5, 0.5, 0.0]);
+        assert!(mixer.collect_garbage().is_some());
+        assert!(mixer.schedule(voice(0), 15));
+        assert_eq!(render(&mut mixer, 5), [0.0; 5]);
+        assert!(mixer.is_idle());
+    }
+
+    #[test]
+    fn fades_out_voices_cut_short() {
+        let render_voice = |descriptor| {
+            let mut mixer = with_effects("");
+            mixer.schedule(descriptor, 0);
+            render(&mut mixer, 110)
+        };
+        let full = render_voice(voice(1));
+        let cut = render_voice(VoiceDescriptor { duration: 0.1, ..voice(1) });
+
+        // Linearly over the last 5 frames.
+        assert_eq!(cut[..95], full[..95]);
+        for frame in 95..100 {
+            let expected = full[frame] * (100 - frame) as f32 / 5.0;
+            assert!((cut[frame] - expected).abs() < 1e-5, "{frame}: {}", cut[frame]);
+        }
+        assert!(cut[100..].iter().all(|sample| sample.abs() < 1e-6), "{cut:?}");
+    }
+
+    #[test]
+    fn filters_and_echoes_voices() {
+        let peak = |left: Vec<f32>| left.iter().fold(0f32, |peak, sample| peak.max(sample.abs()));
+        let mut mixer = with_effects("");
+        mixer.schedule(VoiceDescriptor { low_pass_hz: Some(100.0), ..voice(1) }, 0);
+        assert!(peak(render(&mut mixer, 1000)) > 0.45);
+        mixer.schedule(VoiceDescriptor { high_pass_hz: Some(100.0), ..voice(1) }, 1000);
+        assert!(peak(render(&mut mixer, 1000)[100..].to_vec()) < 0.05);
+
+        let mut mixer =
+            with_effects("kinds.TestTick = { echo = { delay_ms = 100, feedback = 0.5 } }");
+        mixer.schedule(VoiceDescriptor { duration: 0.0005, kind: "TestTick", ..voice(0) }, 0);
+        let left = render(&mut mixer, 1000);
+        // Echoes every 100 frames.
+        assert_eq!((left[0], left[1]), (0.5, 0.0));
+        assert_eq!((left[100], left[200], left[201]), (0.25, 0.125, 0.0));
+        assert_eq!(mixer.chain.tail(), 2 * 1000);
+
+        // A reload adding a kind before it moves the bus, the voice follows its kind.
+        let effects = "kinds.StderrWrite = {}
+            kinds.TestTick = { echo = { delay_ms = 100, feedback = 0.5 } }";
+        let effects = Effects::new(toml::from_str::<EffectsConfig>(effects).unwrap()).unwrap();
+        let echo = VoiceDescriptor { duration: 0.5, kind: "TestTick", ..voice(1) };
+        mixer.schedule(echo, 1000);
+        render(&mut mixer, 10);
+        mixer.set_chain(Box::new(effects.chain(1000, 2, BLOCK_FRAMES)));
+        assert_eq!(mixer.slots[mixer.playing[0]].bus, Some(1));
+        mixer.schedule(echo, 2000);
+        render(&mut mixer, 1000);
+        assert_eq!(mixer.slots[mixer.playing[0]].bus, Some(1));
+
+        // Echoes of unchanged kinds ring on over a reload, others are cut off.
+        let echo_after_reload = |effects: &str| {
+            let mut mixer =
+                with_effects("kinds.TestTick = { echo = { delay_ms = 100, feedback = 0.5 } }");
+            mixer.schedule(VoiceDescriptor { duration: 0.0005, kind: "TestTick", ..voice(0) }, 0);
+            render(&mut mixer, 150);
+            let effects = Effects::new(toml::from_str::<EffectsConfig>(effects).unwrap()).unwrap();
+            mixer.set_chain(Box::new(effects.chain(1000, 2, BLOCK_FRAMES)));
+            render(&mut mixer, 100)[50]
+        };
+        let same = "kinds.TestTick = { echo = { delay_ms = 100, feedback = 0.5 } }
+            kinds.StderrWrite = { reverb_send = 0.5 }";
+        assert_eq!(echo_after_reload(same), 0.125);
+        let longer = "kinds.TestTick = { echo = { delay_ms = 150, feedback = 0.5 } }";
+        assert_eq!(echo_after_reload(longer), 0.0);
+    }
+
+    /// Not a test but a benchmark of how many voices per second the audio thread can mix before
+    /// a buffer takes longer to render than to play, i.e. before an xrun. Run it with
+    /// `cargo test --release -p composer benchmark_mixer -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn benchmark_mixer() {
+        const SAMPLE_RATE: u32 = 48_000;
+        const BUFFER_FRAMES: u64 = 512;
+        let buffer_duration = Duration::from_secs_f64(BUFFER_FRAMES as f64 / SAMPLE_RATE as f64);
+        // Voices start 200 ms after they are scheduled, like with the default play delay.
+        let delay_frames = SAMPLE_RATE as u64 / 5;
+
+        let config = crate::config::SoundConfig::load(std::path::Path::new("sounds.toml")).unwrap();
+        let (mut jukebox, effects) = (config.jukebox, config.effects);
+        let voice =
+            crate::jukebox::Voice::new("TestTick", "click".parse().unwrap(), Duration::ZERO);
+        let descriptor = jukebox.descriptor(&voice, &effects, None).unwrap();
+
+        for voices_per_second in [1_000, 2_000, 5_000, 10_000, 20_000, 50_000, 100_000] {
+            let chain = Box::new(effects.chain(SAMPLE_RATE, 2, BLOCK_FRAMES));
+            // As many slots as the audio output has, voices beyond them are dropped like there.
+            let capacity = crate::audio_output::AudioOutput::VOICES;
+            let mut mixer = Mixer::new(SAMPLE_RATE, 2, capacity, jukebox.bank(), chain);
+            let mut data = vec![0.0; BUFFER_FRAMES as usize * 2];
+            let (mut voices, mut dropped, mut xruns, mut worst, mut total) =
+                (0u64, 0u64, 0, Duration::ZERO, Duration::ZERO);
+            let buffers = 5 * SAMPLE_RATE as u64 / BUFFER_FRAMES;
+            // Five seconds of audio.
+            for buffer in 1..=buffers {
+                let started = Instant::now();
+                // Voices received during the last buffer, evenly spread.
+                while voices * SAMPLE_RATE as u64 / voices_per_second < buffer * BUFFER_FRAMES {
+                    let start_frame = voices * SAMPLE_RATE as u64 / voices_per_second;
+                    dropped += u64::from(!mixer.schedule(descriptor, start_frame + delay_frames));
+                    voices += 1;
+                }
+                mixer.render(&mut data);
+                let elapsed = started.elapsed();
+                worst = worst.max(elapsed);
+                total += elapsed;
+                xruns += usize::from(elapsed > buffer_duration);
+            }
+            println!(
+                "{voices_per_second:>6} voices/s: buffers of {buffer_duration:?} took {:?} on \
+                 average, {worst:?} at worst, {xruns} xruns, {} voices mixed at the end, {dropped} \
+                 dropped",
+                total / buffers as u32,
+                mixer.playing.len(),
+            );
+            // Tolerate rare xruns caused by the rest of the system rather than by the mixing.
+            if xruns as u64 * 100 > buffers {
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,182 @@
+//! Musical mode of the composer: samples are pitched to notes of a scale, so that many event
+//! kinds playing at once stay consonant.
+
+use crate::{
+    config::{check_kind, check_label},
+    jukebox::label,
+};
+use composer_api::EventKind;
+use eyre::{bail, ensure, Result};
+use serde::Deserialize;
+use std::{collections::HashMap, str::FromStr};
+
+/// The `[music]` section of the sound configuration, see `sounds.toml`.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct MusicConfig {
+    key: String,
+    scale: Scale,
+    /// Scale degree of each event kind or `<kind>.<label>`, 0 is the tonic.
+    #[serde(default)]
+    degrees: HashMap<String, i32>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Scale {
+    Chromatic,
+    Major,
+    Minor,
+    Pentatonic,
+}
+
+impl Scale {
+    /// Semitones of the degrees of the scale above its tonic, within one octave.
+    fn intervals(self) -> &'static [i32] {
+        match self {
+            Self::Chromatic => &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11],
+            Self::Major => &[0, 2, 4, 5, 7, 9, 11],
+            Self::Minor => &[0, 2, 3, 5, 7, 8, 10],
+            Self::Pentatonic => &[0, 2, 4, 7, 9],
+        }
+    }
+
+    /// Semitones of a scale degree above the tonic, degrees outside of the first octave are
+    /// allowed, including negative ones.
+    fn semitones(self, degree: i32) -> i32 {
+        let intervals = self.intervals();
+        let length = intervals.len() as i32;
+        12 * degree.div_euclid(length) + intervals[degree.rem_euclid(length) as usize]
+    }
+}
+
+/// Configured degrees are at most this many octaves from the tonic, beyond samples turn into
+/// clicks or rumble.
+const MAX_OCTAVES: u32 = 5;
+
+/// Key of the music as semitones from C, within -6 and 5 so that samples are not pitched more
+/// than half an octave, e.g. `D` or `F#` or `Bb`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Key(i32);
+
+impl FromStr for Key {
+    type Err = eyre::Report;
+
+    fn from_str(key: &str) -> Result<Self> {
+        let mut chars = key.chars();
+        let mut semitones: i32 = match chars.next() {
+            Some('C') => 0,
+            Some('D') => 2,
+            Some('E') => 4,
+            Some('F') => 5,
+            Some('G') => 7,
+            Some('A') => 9,
+            Some('B') => 11,
+            _ => bail!("key should be a note from C to B, got {key:?}"),
+        };
+        semitones += match chars.as_str() {
+            "" => 0,
+            "#" => 1,
+            "b" => -1,
+            accidental => bail!("unknown accidental {accidental:?} in key {key:?}"),
+        };
+        Ok(Self((semitones + 6).rem_euclid(12) - 6))
+    }
+}
+
+/// Validated [MusicConfig], assigns playback rates to events.
+#[derive(Debug)]
+pub(crate) struct Music {
+    key: Key,
+    scale: Scale,
+    degrees: HashMap<String, i32>,
+}
+
+impl Music {
+    pub(crate) fn new(config: MusicConfig) -> Result<Self> {
+        let max_degree = MAX_OCTAVES * config.scale.intervals().len() as u32;
+        for (kind, degree) in &config.degrees {
+            ensure!(
+                degree.unsigned_abs() <= max_degree,
+                "degree {degree} of {kind} is more than {MAX_OCTAVES} octaves from the tonic, \
+                    should be from -{max_degree} to {max_degree}"
+            );
+            match kind.split_once('.') {
+                Some((name, label)) => {
+                    check_kind(name)?;
+                    check_label(name, label)?;
+                },
+                None => check_kind(kind)?,
+            }
+        }
+        Ok(Self { key: config.key.parse()?, scale: config.scale, degrees: config.degrees })
+    }
+
+    /// Playback rate of the sample for an event, so that it sounds at the event's note.
+    ///
+    /// The note is the scale degree configured for the event's `<kind>.<label>`, or its kind, or
+    /// the tonic. Events with a length are lowered by one degree for every 4x of their length,
+    /// so bigger writes sound deeper.
+    pub(crate) fn rate(&self, kind: &EventKind) -> f32 {
+        let name = kind.name();
+        let labelled = label(kind).and_then(|label| self.degrees.get(&format!("{name}.{label}")));
+        let mut degree = labelled.or_else(|| self.degrees.get(name)).copied().unwrap_or(0);
+
+        if let EventKind::StdoutWrite { length } | EventKind::StderrWrite { length } = kind {
+            degree -= ((*length).max(1) as f32).log(4.0).round() as i32;
+        }
+
+        let semitones = self.key.0 + self.scale.semitones(degree);
+        (semitones as f32 / 12.0).exp2()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use composer_api::LogLevel;
+
+    #[test]
+    fn maps_degrees_to_semitones() {
+        assert_eq!(Scale::Major.semitones(0), 0);
+        assert_eq!(Scale::Major.semitones(2), 4);
+        assert_eq!(Scale::Major.semitones(7), 12);
+        assert_eq!(Scale::Pentatonic.semitones(-1), -3);
+        assert_eq!(Scale::Minor.semitones(9), 15);
+
+        assert_eq!("D".parse::<Key>().unwrap(), Key(2));
+        assert_eq!("Bb".parse::<Key>().unwrap(), Key(-2));
+        assert_eq!("F#".parse::<Key>().unwrap(), Key(-6));
+        assert!("H".parse::<Key>().is_err());
+        assert!("Cx".parse::<Key>().is_err());
+    }
+
+    #[test]
+    fn assigns_notes_to_events() {
+        let degrees = [("TestTick".to_string(), 2), ("Log.Error".to_string(), 4)];
+        let config =
+            MusicConfig { key: "C".to_string(), scale: Scale::Pentatonic, degrees: degrees.into() };
+        let music = Music::new(config).unwrap();
+        let semitones = |kind| (music.rate(&kind).log2() * 12.0).round() as i32;
+
+        assert_eq!(semitones(EventKind::TestTick), 4);
+        assert_eq!(semitones(EventKind::Log { level: LogLevel::Error }), 9);
+        assert_eq!(semitones(EventKind::Log { level: LogLevel::Info }), 0);
+        assert_eq!(semitones(EventKind::StdoutWrite { length: 1 }), 0);
+        assert_eq!(semitones(EventKind::StdoutWrite { length: 4096 }), -15);
+    }
+
+    #[test]
+    fn rejects_degrees_out_of_range() {
+        let music = |scale, degree| {
+            let degrees = [("TestTick".to_string(), degree)];
+            Music::new(MusicConfig { key: "C".to_string(), scale, degrees: degrees.into() })
+        };
+        assert!(music(Scale::Pentatonic, 25).is_ok());
+        assert!(music(Scale::Pentatonic, -25).is_ok());
+        assert!(music(Scale::Pentatonic, 26).is_err());
+        assert!(music(Scale::Chromatic, 61).is_err());
+        assert!(music(Scale::Major, i32::MIN).is_err());
+        assert!(music(Scale::Major, i32::MAX).is_err());
+    }
+}
@@ -0,0 +1,103 @@
+//! Forwarding of played voices as Open Sound Control messages over UDP, so that external sound
+//! engines like SuperCollider, Max or Pure Data can render the events.
+
+use crate::jukebox::Voice;
+use eyre::{eyre, Context, Result};
+use std::{
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    time::Duration,
+};
+
+/// Seconds from the NTP epoch (1900) used by OSC time tags to the UNIX epoch.
+const NTP_UNIX_OFFSET_S: u64 = 2_208_988_800;
+
+/// Sends a bundle with a single message per voice to the given address:
+///
+/// `/profiler/<kind> ,ffiss <gain> <rate> <length> <label> <sample>`
+///
+/// `length` is 0 and `label` is empty for events that don't have them. The time tag of the
+/// bundle is the time the voice should sound at, i.e. its timestamp plus the play delay.
+pub(crate) struct OscExport {
+    socket: UdpSocket,
+    target: SocketAddr,
+    play_delay: Duration,
+}
+
+impl OscExport {
+    pub(crate) fn new(address: &str, play_delay: Duration) -> Result<Self> {
+        let target = address
+            .to_socket_addrs()
+            .with_context(|| format!("resolving {address}"))?
+            .next()
+            .ok_or_else(|| eyre!("{address} does not resolve to any address"))?;
+        let wildcard = if target.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = UdpSocket::bind(wildcard)?;
+        Ok(Self { socket, target, play_delay })
+    }
+
+    pub(crate) fn export(&self, voice: &Voice) -> Result<()> {
+        let bundle = encode_bundle(voice.timestamp + self.play_delay, &encode_message(voice));
+        self.socket
+            .send_to(&bundle, self.target)
+            .with_context(|| format!("sending to {}", self.target))?;
+        Ok(())
+    }
+}
+
+fn encode_message(voice: &Voice) -> Vec<u8> {
+    let mut message = Vec::new();
+    write_string(&mut message, &format!("/profiler/{}", voice.kind));
+    write_string(&mut message, ",ffiss");
+    message.extend_from_slice(&voice.gain.to_be_bytes());
+    message.extend_from_slice(&voice.rate.to_be_bytes());
+    let length = voice.length.map_or(0, |length| length.min(i32::MAX as usize) as i32);
+    message.extend_from_slice(&length.to_be_bytes());
+    write_string(&mut message, voice.label.as_deref().unwrap_or_default());
+    write_string(&mut message, voice.sample.name());
+    message
+}
+
+/// Wrap a message into a bundle to be executed at the UNIX `timestamp`.
+fn encode_bundle(timestamp: Duration, message: &[u8]) -> Vec<u8> {
+    let mut bundle = Vec::new();
+    write_string(&mut bundle, "#bundle");
+    let seconds = timestamp.as_secs() + NTP_UNIX_OFFSET_S;
+    let fraction = ((timestamp.subsec_nanos() as u64) << 32) / 1_000_000_000;
+    bundle.extend_from_slice(&(seconds << 32 | fraction).to_be_bytes());
+    bundle.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    bundle.extend_from_slice(message);
+    bundle
+}
+
+/// Write an OSC string: null-terminated and padded with nulls to a multiple of 4 bytes.
+fn write_string(out: &mut Vec<u8>, string: &str) {
+    out.extend_from_slice(string.as_bytes());
+    out.resize((out.len() / 4 + 1) * 4, 0);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn encodes_voices_as_bundles() {
+        let mut voice = Voice::new("Log", "click".parse().unwrap(), Duration::from_millis(1500));
+        voice.label = Some("Error".to_string());
+        voice.gain = 0.5;
+
+        let export = OscExport::new("127.0.0.1:0", Duration::from_millis(500)).unwrap();
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let export = OscExport { target: receiver.local_addr().unwrap(), ..export };
+        export.export(&voice).unwrap();
+
+        let mut bundle = [0; 256];
+        let length = receiver.recv(&mut bundle).unwrap();
+        let message =
+            b"/profiler/Log\0\0\0,ffiss\0\0\x3f\0\0\0\x3f\x80\0\0\0\0\0\0Error\0\0\0click\0\0\0";
+        assert_eq!(&bundle[..8], b"#bundle\0");
+        // 2 seconds after the UNIX epoch.
+        assert_eq!(&bundle[8..16], &[0x83, 0xaa, 0x7e, 0x82, 0, 0, 0, 0]);
+        assert_eq!(&bundle[16..20], &(message.len() as u32).to_be_bytes());
+        assert_eq!(&bundle[20..length], message);
+    }
+}
@@ -0,0 +1,234 @@
+//! The core of the composer: events of received packets are mapped to voices, adjusted by
+//! controls, aggregated and scheduled to a [Sink], independently of how voices are rendered.
+
+use crate::{
+    aggregator::Aggregate,
+    config,
+    controls::Controls,
+    jukebox::{Sample, Voice},
+    music::Music,
+    spatial::Spatial,
+};
+use composer_api::{capture::Record, util::current_timestamp};
+use std::time::Duration;
+
+/// Renders scheduled voices, e.g. plays them on the sound card or exports them.
+pub(crate) trait Sink {
+    /// Render the voice at its timestamp plus the play delay.
+    fn play(&mut self, voice: Voice);
+}
+
+/// How to pick the sample to play for events.
+#[derive(Clone, Copy)]
+pub(crate) enum Mapping<'a> {
+    /// Look up the sample by event kind.
+    Kinds(&'a config::Mapping),
+    /// Play the same sample for all events.
+    All(&'a Sample),
+}
+
+pub(crate) struct Pipeline {
+    aggregator: Box<dyn Aggregate>,
+    /// Voices ready to be scheduled.
+    voices: Vec<Voice>,
+}
+
+impl Pipeline {
+    pub(crate) fn new(aggregator: Box<dyn Aggregate>) -> Self {
+        Self { aggregator, voices: Vec::new() }
+    }
+
+    /// Map events of the received packet to voices, pitch them by `music`, place them by
+    /// `spatial`, adjust them by `controls` and pass them through the aggregator.
+    pub(crate) fn process(
+        &mut self,
+        record: Record,
+        mapping: Mapping,
+        music: Option<&Music>,
+        spatial: Option<&Spatial>,
+        controls: &Controls,
+    ) {
+        let direction = spatial.and_then(|spatial| spatial.direction(&record.source));
+        for event in record.packet.events {
+            let kind = event.kind.name();
+            let sample = match mapping {
+                Mapping::Kinds(mapping) => mapping.sample_for(kind),
+                Mapping::All(sample) => Some(sample),
+            };
+            let Some(sample) = sample else {
+                continue;
+            };
+
+            let timestamp = event.timestamp.unwrap_or_else(current_timestamp);
+            let mut voice = Voice::for_event(&event.kind, sample.clone(), timestamp);
+            if let Some(music) = music {
+                // Replaces the pitch derived from the length of writes, music keeps them in scale.
+                voice.rate = music.rate(&event.kind);
+            }
+            voice.direction = direction;
+            voice.weight *= event.weight;
+            // Log statistics of periods without any records.
+            if voice.weight == 0.0 {
+                continue;
+            }
+            if let Some(voice) = controls.adjust(kind, &record.source, voice) {
+                self.aggregator.process(kind, voice, &mut self.voices);
+            }
+        }
+    }
+
+    /// Flush voices aggregated until `now` and pass all voices ready to be scheduled to `sink`.
+    pub(crate) fn schedule(&mut self, now: Duration, sink: &mut impl Sink) {
+        self.aggregator.flush_expired(now, &mut self.voices);
+        for voice in self.voices.drain(..) {
+            sink.play(voice);
+        }
+    }
+
+    pub(crate) fn aggregator(&mut self) -> &mut dyn Aggregate {
+        self.aggregator.as_mut()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        aggregator::Aggregator,
+        config::test::load_from,
+        listener::{self, Incoming},
+        Message,
+    };
+    use composer_api::{Client, Encoding, Event, EventKind, LogLevel, LogStats, Packet};
+    use std::sync::mpsc;
+
+    /// Sound configuration of the tests, independent of the shipped one.
+    const CONFIG: &str = r#"
+        sample_dir = "SAMPLE_DIR"
+        [samples]
+        click = "click.wav"
+        clack = "clack.wav"
+        [mapping]
+        TestTick = "clack"
+        StdoutWrite = "click"
+        StderrWrite = "click"
+        FileSystemRead = "click"
+        FileSystemWrite = "click"
+        Log = "click"
+        LogStats = "clack"
+        [music]
+        key = "C"
+        scale = "pentatonic"
+        degrees = { StdoutWrite = 2 }
+    "#;
+
+    /// Records voices instead of playing them.
+    #[derive(Default)]
+    struct RecordingSink {
+        voices: Vec<Voice>,
+    }
+
+    impl Sink for RecordingSink {
+        fn play(&mut self, voice: Voice) {
+            self.voices.push(voice);
+        }
+    }
+
+    /// Send `packets` to a composer listening on an ephemeral UDP port, run them through the
+    /// pipeline with the [CONFIG] and return the scheduled voices.
+    fn compose(packets: &[Packet], encoding: Encoding, pipeline: &mut Pipeline) -> Vec<Voice> {
+        let config = load_from(CONFIG).unwrap();
+        let (message_tx, message_rx) = mpsc::channel();
+        let spec = "udp://127.0.0.1:0".parse().unwrap();
+        let name = listener::spawn(0, &spec, None, message_tx).unwrap();
+        let client =
+            Client::new(name.trim_start_matches("udp://")).unwrap().with_encoding(encoding);
+
+        let mut sink = RecordingSink::default();
+        for packet in packets {
+            client.send(packet).unwrap();
+            let Message::Incoming(Incoming { source, arrival, result, .. }) =
+                message_rx.recv_timeout(Duration::from_secs(5)).unwrap()
+            else {
+                panic!("listener should only send incoming datagrams");
+            };
+            let record = Record { arrival, source, packet: result.unwrap().0 };
+            let mapping = Mapping::Kinds(&config.mapping);
+            let (music, spatial) = (config.music.as_ref(), config.spatial.as_ref());
+            pipeline.process(record, mapping, music, spatial, &Controls::default());
+            pipeline.schedule(arrival, &mut sink);
+        }
+        pipeline.schedule(Duration::MAX, &mut sink);
+        sink.voices
+    }
+
+    fn aggregator() -> Pipeline {
+        Pipeline::new(Box::new(Aggregator::new(Duration::from_millis(10), 20)))
+    }
+
+    #[test]
+    fn maps_events_to_sounds() {
+        let timestamp = Duration::from_secs(1000);
+        let packets = [test_probe::every_kind(timestamp, 4096)];
+        let voices = compose(&packets, Encoding::Json, &mut aggregator());
+
+        let played: Vec<_> = voices.iter().map(|voice| (voice.kind, voice.sample.name())).collect();
+        assert_eq!(
+            played,
+            [
+                ("TestTick", "clack"),
+                ("StdoutWrite", "click"),
+                ("StderrWrite", "click"),
+                ("FileSystemRead", "click"),
+                ("FileSystemWrite", "click"),
+                ("Log", "click"),
+            ]
+        );
+        assert_eq!(voices[5].label.as_deref(), Some("Error"));
+        assert!(voices.iter().all(|voice| voice.timestamp == timestamp));
+
+        let stdout = &voices[1];
+        assert_eq!(stdout.length, Some(4096));
+        assert!(stdout.pan < 0.0 && stdout.truncate.is_some() && stdout.low_pass_hz.is_some());
+        // Pitched by the musical mode, the big write lower than the tonic.
+        assert!(stdout.rate < 1.0);
+    }
+
+    #[test]
+    fn weighs_log_statistics_by_records() {
+        let stats = LogStats { error_records: 3, info_records: 2, ..Default::default() };
+        let packets = [Packet::new(vec![
+            Event::new(EventKind::Log { level: LogLevel::Warn }),
+            Event::new(EventKind::LogStats(stats)),
+            Event::new(EventKind::LogStats(LogStats::default())),
+        ])];
+        let voices = compose(&packets, Encoding::Bincode, &mut aggregator());
+
+        let played: Vec<_> =
+            voices.iter().map(|voice| (voice.kind, voice.label.as_deref(), voice.weight)).collect();
+        assert_eq!(played, [("Log", Some("Warn"), 1.0), ("LogStats", None, 5.0)]);
+    }
+
+    #[test]
+    fn aggregates_bursts() {
+        let start = Duration::from_secs(1000);
+        let packets: Vec<_> =
+            test_probe::bursts(start, Duration::from_millis(50), 50).take(6).collect();
+        let mut pipeline = aggregator();
+        let voices = compose(&packets, Encoding::Bincode, &mut pipeline);
+
+        // 300 events 1 ms apart, 10 per window are under the threshold and played as they are.
+        assert_eq!(voices.len(), 300);
+        assert_eq!(voices[299].timestamp, start + Duration::from_millis(299));
+        assert_eq!(pipeline.aggregator().fetch_merged(), 0);
+
+        let packets: Vec<_> =
+            test_probe::bursts(start, Duration::from_millis(10), 50).take(3).collect();
+        let voices = compose(&packets, Encoding::Bincode, &mut pipeline);
+        // 50 events per 10 ms window are over the threshold, the 30 above it are merged into one
+        // louder voice.
+        assert_eq!(voices.len(), 3 * 21);
+        assert_eq!(voices.iter().filter(|voice| voice.gain > 1.0).count(), 3);
+        assert_eq!(pipeline.aggregator().fetch_merged(), 3 * 29);
+    }
+}
@@ -0,0 +1,140 @@
+use crate::{aggregator::Aggregate, jukebox::Voice};
+use std::{collections::HashMap, time::Duration};
+
+/// Pipeline stage for the rhythmic mode, trading timing precision for a listenable rhythm.
+///
+/// Time is split into slots of a beat grid, given by tempo and the number of slots per beat. All
+/// events of a kind in a slot make a single drum-machine-style hit at the end of the slot, with
+/// velocity (gain) growing with their count, or their total weight for sampled events.
+pub(crate) struct Quantizer {
+    slot: Duration,
+    /// Slots still collecting events, by event kind.
+    slots: HashMap<&'static str, Slot>,
+    /// Index of the first slot that is not closed yet. Late events are moved to it.
+    open_from: u128,
+    merged: usize,
+}
+
+/// Velocity of a hit grows by this much with every doubling of the event count...
+const GAIN_PER_DOUBLING: f32 = 0.25;
+/// ...starting at this gain for a single event...
+const MIN_GAIN: f32 = 0.5;
+/// ...up to this one.
+const MAX_GAIN: f32 = 2.0;
+
+struct Slot {
+    index: u128,
+    /// The last voice in the slot, the number of voices in it and their total weight.
+    voice: Voice,
+    count: usize,
+    weight: f32,
+}
+
+impl Quantizer {
+    /// Shortest grid slot, beyond any tempo that makes musical sense.
+    pub(crate) const MIN_SLOT: Duration = Duration::from_millis(1);
+
+    /// Length of a slot of a grid with `tempo_bpm` beats per minute split into `subdivision`
+    /// slots each, `None` if it is not at least [Self::MIN_SLOT] long.
+    pub(crate) fn slot(tempo_bpm: f64, subdivision: u32) -> Option<Duration> {
+        let slot = 60.0 / tempo_bpm / subdivision as f64;
+        (tempo_bpm > 0.0 && slot.is_finite() && slot >= Self::MIN_SLOT.as_secs_f64())
+            .then(|| Duration::from_secs_f64(slot))
+    }
+
+    /// Create a quantizer for a grid with `tempo_bpm` beats per minute split into `subdivision`
+    /// slots each.
+    pub(crate) fn new(tempo_bpm: f64, subdivision: u32) -> Self {
+        let slot = Self::slot(tempo_bpm, subdivision).expect("grid slots should be long enough");
+        Self { slot, slots: HashMap::new(), open_from: 0, merged: 0 }
+    }
+
+    fn close(&mut self, kind: &'static str) -> Option<Voice> {
+        let slot = self.slots.remove(kind)?;
+        self.merged += slot.count - 1;
+
+        let end = Duration::from_nanos(((slot.index + 1) * self.slot.as_nanos()) as u64);
+        let velocity = (slot.weight.max(1.0).log2() * GAIN_PER_DOUBLING + MIN_GAIN).min(MAX_GAIN);
+        let gain = slot.voice.gain * velocity;
+        Some(Voice { timestamp: end, gain, weight: slot.weight, ..slot.voice })
+    }
+}
+
+impl Aggregate for Quantizer {
+    fn process(&mut self, kind: &'static str, voice: Voice, out: &mut Vec<Voice>) {
+        let index = (voice.timestamp.as_nanos() / self.slot.as_nanos()).max(self.open_from);
+        if self.slots.get(kind).is_some_and(|slot| slot.index != index) {
+            out.extend(self.close(kind));
+        }
+
+        match self.slots.get_mut(kind) {
+            Some(slot) => {
+                slot.weight += voice.weight;
+                slot.voice = voice;
+                slot.count += 1;
+            },
+            None => {
+                let weight = voice.weight;
+                self.slots.insert(kind, Slot { index, voice, count: 1, weight });
+            },
+        }
+    }
+
+    /// Close slots that ended before `now` and push their hits into `out`.
+    fn flush_expired(&mut self, now: Duration, out: &mut Vec<Voice>) {
+        let current_index = now.as_nanos() / self.slot.as_nanos();
+        let expired: Vec<_> = self
+            .slots
+            .iter()
+            .filter(|(_, slot)| slot.index < current_index)
+            .map(|(&kind, _)| kind)
+            .collect();
+
+        for kind in expired {
+            out.extend(self.close(kind));
+        }
+        self.open_from = self.open_from.max(current_index);
+    }
+
+    fn fetch_merged(&mut self) -> usize {
+        std::mem::take(&mut self.merged)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn voice(timestamp_ms: u64) -> Voice {
+        Voice::new("TestTick", "click".parse().unwrap(), Duration::from_millis(timestamp_ms))
+    }
+
+    #[test]
+    fn quantizes_events_to_grid() {
+        // 120 BPM in sixteenths, slots are 125 ms long.
+        let mut quantizer = Quantizer::new(120.0, 4);
+        let mut out = Vec::new();
+        for timestamp_ms in [1010, 1020, 1030] {
+            quantizer.process("TestTick", voice(timestamp_ms), &mut out);
+        }
+        // A sampled event standing for 13 makes 16 in total, two doublings of velocity.
+        quantizer.process("TestTick", Voice { weight: 13.0, ..voice(1040) }, &mut out);
+        quantizer.process("StdoutWrite", voice(1100), &mut out);
+        quantizer.flush_expired(Duration::from_millis(1110), &mut out);
+        assert!(out.is_empty());
+
+        quantizer.flush_expired(Duration::from_millis(1130), &mut out);
+        let hits: Vec<_> =
+            out.iter().map(|voice| (voice.timestamp.as_millis(), voice.gain)).collect();
+        assert_eq!(hits.len(), 2);
+        assert!(hits.contains(&(1125, 1.5)));
+        assert!(hits.contains(&(1125, 0.5)));
+        assert_eq!(quantizer.fetch_merged(), 3);
+
+        // A late event for a closed slot is moved to the current one.
+        out.clear();
+        quantizer.process("TestTick", voice(1050), &mut out);
+        quantizer.flush_expired(Duration::from_millis(1250), &mut out);
+        assert_eq!(out[0].timestamp, Duration::from_millis(1250));
+    }
+}
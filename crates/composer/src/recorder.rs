@@ -0,0 +1,218 @@
+use crate::dashboard::report;
+use composer_api::{
+    capture::{CaptureWriter, Record, MAGIC},
+    util::current_timestamp,
+};
+use eyre::{Context, Result};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufWriter, Read},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+/// Appends received packets to a capture file. When the file grows over `max_bytes` or gets older
+/// than `max_age`, it is rotated: renamed to `<path>.<UNIX timestamp>` and a fresh file is started.
+pub(crate) struct Recorder {
+    path: PathBuf,
+    max_bytes: Option<u64>,
+    max_age: Option<Duration>,
+    writer: CaptureWriter<BufWriter<File>>,
+    bytes: u64,
+    opened: Instant,
+    last_flush: Instant,
+}
+
+impl Recorder {
+    const FLUSH_EVERY: Duration = Duration::from_secs(1);
+
+    pub(crate) fn new(
+        path: PathBuf,
+        max_bytes: Option<u64>,
+        max_age: Option<Duration>,
+    ) -> Result<Self> {
+        // Records can't be appended to a file of another format version or with a truncated
+        // header, move it away.
+        let mut header = Vec::new();
+        if let Ok(file) = File::open(&path) {
+            file.take(MAGIC.len() as u64)
+                .read_to_end(&mut header)
+                .with_context(|| format!("reading capture file {path:?}"))?;
+        }
+        if !header.is_empty() && header != MAGIC {
+            Self::move_away(&path)?;
+        }
+
+        let (writer, bytes) = Self::open(&path)?;
+        let now = Instant::now();
+        Ok(Self { path, max_bytes, max_age, writer, bytes, opened: now, last_flush: now })
+    }
+
+    /// Open `path` for appending, writing the header if the file is new.
+    fn open(path: &PathBuf) -> Result<(CaptureWriter<BufWriter<File>>, u64)> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("opening capture file {path:?}"))?;
+        let bytes = file.metadata()?.len();
+        let writer = BufWriter::new(file);
+        match bytes {
+            0 => Ok((CaptureWriter::new(writer)?, MAGIC.len() as u64)),
+            _ => Ok((CaptureWriter::append(writer), bytes)),
+        }
+    }
+
+    pub(crate) fn record(&mut self, record: &Record) -> Result<()> {
+        self.bytes += self.writer.write(record)? as u64;
+
+        if self.max_bytes.is_some_and(|max_bytes| self.bytes >= max_bytes)
+            || self.max_age.is_some_and(|max_age| self.opened.elapsed() >= max_age)
+        {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+
+    /// Flush buffered records to the file if we haven't done so recently.
+    pub(crate) fn flush_if_due(&mut self) -> Result<()> {
+        if self.last_flush.elapsed() >= Self::FLUSH_EVERY {
+            self.writer.flush()?;
+            self.last_flush = Instant::now();
+        }
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Self::move_away(&self.path)?;
+        (self.writer, self.bytes) = Self::open(&self.path)?;
+        self.opened = Instant::now();
+        Ok(())
+    }
+
+    /// Rename the file at `path` to `<path>.<UNIX timestamp>`, or to `<path>.<UNIX timestamp>.<n>`
+    /// if a file rotated within the same second already has that name.
+    fn move_away(path: &PathBuf) -> Result<PathBuf> {
+        let mut base = path.clone().into_os_string();
+        base.push(format!(".{}", current_timestamp().as_secs()));
+        let mut rotated = PathBuf::from(&base);
+        for n in 1.. {
+            if !rotated.try_exists()? {
+                break;
+            }
+            let mut numbered = base.clone();
+            numbered.push(format!(".{n}"));
+            rotated = numbered.into();
+        }
+        fs::rename(path, &rotated)
+            .with_context(|| format!("rotating capture file to {rotated:?}"))?;
+        Ok(rotated)
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        if let Err(err) = self.writer.flush() {
+            report(format!("Could not flush capture file: {err:?}"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use composer_api::{capture::CaptureReader, Event, EventKind, Packet};
+    use std::{io::BufReader, path::Path, thread::sleep};
+
+    /// A fresh directory for the capture files of test `name`.
+    fn directory(name: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("composer-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir(&directory).unwrap();
+        directory
+    }
+
+    fn record(secs: u64) -> Record {
+        Record {
+            arrival: Duration::from_secs(secs),
+            source: "udp://127.0.0.1:1234".to_string(),
+            packet: Packet::from_event(Event::new(EventKind::TestTick)),
+        }
+    }
+
+    fn read(path: &PathBuf) -> Vec<Record> {
+        let reader = CaptureReader::new(BufReader::new(File::open(path).unwrap())).unwrap();
+        reader.collect::<Result<_>>().unwrap()
+    }
+
+    /// Records of the capture file `path` and of its rotated files, by file name, and remove them.
+    fn read_all(path: &Path) -> Vec<Vec<Record>> {
+        let directory = path.parent().unwrap();
+        let mut paths: Vec<_> =
+            fs::read_dir(directory).unwrap().map(|entry| entry.unwrap().path()).collect();
+        paths.sort();
+        let records = paths.iter().map(read).collect();
+        fs::remove_dir_all(directory).unwrap();
+        records
+    }
+
+    #[test]
+    fn rotates_by_size() {
+        let path = directory("rotates-by-size").join("capture.acap");
+        let mut recorder = Recorder::new(path.clone(), Some(1), None).unwrap();
+        for secs in 1..=3 {
+            recorder.record(&record(secs)).unwrap();
+        }
+        drop(recorder);
+        // Every record is over the limit, the current file is left with just the header.
+        assert_eq!(read_all(&path), [vec![], vec![record(1)], vec![record(2)], vec![record(3)]]);
+    }
+
+    #[test]
+    fn rotates_by_age() {
+        let path = directory("rotates-by-age").join("capture.acap");
+        let mut recorder =
+            Recorder::new(path.clone(), None, Some(Duration::from_millis(50))).unwrap();
+        recorder.record(&record(1)).unwrap();
+        recorder.record(&record(2)).unwrap();
+        sleep(Duration::from_millis(60));
+        recorder.record(&record(3)).unwrap();
+        recorder.record(&record(4)).unwrap();
+        drop(recorder);
+        assert_eq!(read_all(&path), [vec![record(4)], vec![record(1), record(2), record(3)]]);
+    }
+
+    #[test]
+    fn moves_away_files_without_header() {
+        let path = directory("moves-away").join("capture.acap");
+        fs::write(&path, &MAGIC[..3]).unwrap();
+        let mut recorder = Recorder::new(path.clone(), None, None).unwrap();
+        recorder.record(&record(1)).unwrap();
+        drop(recorder);
+        let rotated = fs::read_dir(path.parent().unwrap())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|rotated| *rotated != path)
+            .unwrap();
+        assert_eq!(fs::read(rotated).unwrap(), &MAGIC[..3]);
+        assert_eq!(read(&path), [record(1)]);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn rotates_without_overwriting() {
+        let path = std::env::temp_dir().join(format!("composer-{}.acap", std::process::id()));
+        let mut rotated = Vec::new();
+        for content in ["first", "second", "third"] {
+            fs::write(&path, content).unwrap();
+            rotated.push(Recorder::move_away(&path).unwrap());
+        }
+        let contents: Vec<_> =
+            rotated.iter().map(|path| fs::read_to_string(path).unwrap()).collect();
+        rotated.iter().for_each(|path| fs::remove_file(path).unwrap());
+        // Rotated within a second, the later files are numbered rather than overwrite the first.
+        assert_eq!(contents, ["first", "second", "third"]);
+    }
+}
@@ -0,0 +1,283 @@
+//! Offline rendering of played voices to a WAV file with any number of channels, e.g. to check a
+//! spatial speaker layout without having the speakers.
+
+use crate::{
+    dashboard::{report, report_error},
+    effects::Effects,
+    mixer::{Mixer, SampleBank, VoiceDescriptor, BLOCK_FRAMES},
+};
+use eyre::{Context, Result};
+use std::{
+    fs::File,
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// Renders voices with the same [Mixer] as the audio output, time 0 of the file is the first
+/// voice. Rendered blocks are streamed to the file, whose header is brought up to date once in a
+/// while and when dropped, so that the file is complete even if we are killed.
+pub(crate) struct WavRender {
+    path: PathBuf,
+    file: BufWriter<File>,
+    channels: u16,
+    mixer: Mixer,
+    /// Timestamp of the first frame.
+    start: Option<Duration>,
+    /// Interleaved samples of the block being rendered.
+    block: Vec<f32>,
+    /// Size of the sample data written so far.
+    data_bytes: u32,
+    /// Whether the file reached the size limit of the WAV format and no more data is written.
+    full: bool,
+    /// Number of voices too far from the current time or over the capacity of the mixer.
+    skipped: usize,
+    /// Whether data was written since the header was last updated.
+    changed: bool,
+    written_at: Option<Instant>,
+}
+
+impl WavRender {
+    /// Room for voices within the lookahead, more than the audio output as there is no hurry.
+    const CAPACITY: usize = 16 * 1024;
+    /// Voices are mixed this long after the latest added one starts, so that voices added out of
+    /// order still start at their frame.
+    const LOOKAHEAD: Duration = Duration::from_secs(1);
+    /// Voices whose timestamp is further than this from the current time are dropped, so that a
+    /// probe with a skewed clock doesn't fill the file with hours of silence.
+    const MAX_CLOCK_SKEW: Duration = Duration::from_secs(10);
+    pub(crate) const SAMPLE_RATE: u32 = 48_000;
+    /// Rendered after the last voice for the reverb to ring out, on top of echoes.
+    const TAIL: Duration = Duration::from_secs(1);
+    const WRITE_EVERY: Duration = Duration::from_secs(5);
+
+    pub(crate) fn new(path: PathBuf, channels: u16) -> Result<Self> {
+        let file = File::create(&path).with_context(|| format!("creating {path:?}"))?;
+        let mut file = BufWriter::new(file);
+        file.write_all(&header(channels, Self::SAMPLE_RATE, 0))?;
+        let chain = Effects::default().chain(Self::SAMPLE_RATE, channels as usize, BLOCK_FRAMES);
+        let mixer =
+            Mixer::new(Self::SAMPLE_RATE, channels, Self::CAPACITY, Arc::new([]), Box::new(chain));
+        Ok(Self {
+            path,
+            file,
+            channels,
+            mixer,
+            start: None,
+            block: Vec::with_capacity(BLOCK_FRAMES * channels as usize),
+            data_bytes: 0,
+            full: false,
+            skipped: 0,
+            changed: false,
+            written_at: None,
+        })
+    }
+
+    /// Replace the samples voices added from now on refer to, see
+    /// [crate::jukebox::Jukebox::bank()].
+    pub(crate) fn set_samples(&mut self, samples: SampleBank) {
+        self.mixer.set_samples(samples);
+    }
+
+    /// Replace the effects of event kinds and of the mix.
+    pub(crate) fn set_effects(&mut self, effects: &Effects) {
+        let chain = effects.chain(Self::SAMPLE_RATE, self.channels as usize, BLOCK_FRAMES);
+        self.mixer.set_chain(Box::new(chain));
+    }
+
+    /// Mix the voice at its timestamp, unless it is too far from `now` (the duration since UNIX
+    /// epoch).
+    pub(crate) fn add(&mut self, descriptor: VoiceDescriptor, now: Duration) -> Result<()> {
+        if descriptor.timestamp.abs_diff(now) > Self::MAX_CLOCK_SKEW {
+            self.skipped += 1;
+            return Ok(());
+        }
+        let start = *self.start.get_or_insert(descriptor.timestamp);
+        // Voices before the first one are rare, they are moved to the start.
+        let start_frame = self.frame_at(descriptor.timestamp.saturating_sub(start));
+        if !self.mixer.schedule(descriptor, start_frame) {
+            self.skipped += 1;
+        }
+        let until = start_frame.saturating_sub(self.frame_at(Self::LOOKAHEAD));
+        self.render_until(until)?;
+        self.mixer.collect_garbage();
+        Ok(())
+    }
+
+    fn frame_at(&self, time: Duration) -> u64 {
+        (time.as_secs_f64() * Self::SAMPLE_RATE as f64) as u64
+    }
+
+    /// Render blocks up to `frame` and append them to the file.
+    fn render_until(&mut self, frame: u64) -> Result<()> {
+        let channels = self.channels as usize;
+        while self.mixer.frame() < frame {
+            let frames = (frame - self.mixer.frame()).min(BLOCK_FRAMES as u64) as usize;
+            self.block.resize(frames * channels, 0.0);
+            self.mixer.render(&mut self.block);
+
+            let bytes = self.block.len() as u32 * 4;
+            if self.data_bytes.checked_add(bytes).is_none_or(|total| total > MAX_DATA_BYTES) {
+                // Keep mixing to free the voices, but the file can't grow anymore.
+                self.full = true;
+                continue;
+            }
+            for sample in &self.block {
+                self.file.write_all(&sample.to_le_bytes())?;
+            }
+            self.data_bytes += bytes;
+            self.changed = true;
+        }
+        Ok(())
+    }
+
+    /// Bring the header of the file up to date once in a while so that it is complete even if
+    /// we are killed. Voices within the lookahead are written later.
+    pub(crate) fn write_if_due(&mut self) -> Result<()> {
+        if self.changed && self.written_at.is_none_or(|at| at.elapsed() >= Self::WRITE_EVERY) {
+            self.write_header()?;
+        }
+        Ok(())
+    }
+
+    fn write_header(&mut self) -> Result<()> {
+        let header = header(self.channels, Self::SAMPLE_RATE, self.data_bytes);
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&header)?;
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.flush().with_context(|| format!("writing {:?}", self.path))?;
+        self.changed = false;
+        self.written_at = Some(Instant::now());
+        Ok(())
+    }
+
+    /// Render until all voices and effects ring out and complete the file.
+    fn finish(&mut self) -> Result<()> {
+        while !self.mixer.is_idle() {
+            self.render_until(self.mixer.frame() + BLOCK_FRAMES as u64)?;
+        }
+        if self.start.is_some() {
+            let tail = self.mixer.chain().tail() as u64 / self.channels as u64;
+            self.render_until(self.mixer.frame() + tail + self.frame_at(Self::TAIL))?;
+        }
+        if self.full {
+            report(format!(
+                "Rendered WAV file {:?} reached the 4 GiB limit and was cut.",
+                self.path
+            ));
+        }
+        if self.skipped > 0 {
+            report(format!(
+                "Skipped rendering {} voices too far from the current time or too many at once.",
+                self.skipped
+            ));
+        }
+        self.write_header()
+    }
+}
+
+impl Drop for WavRender {
+    fn drop(&mut self) {
+        if let Err(err) = self.finish() {
+            report_error("Could not write rendered WAV file.", &err);
+        }
+    }
+}
+
+/// Sub-format GUID of 32-bit float samples in `WAVE_FORMAT_EXTENSIBLE`.
+const KSDATAFORMAT_SUBTYPE_IEEE_FLOAT: [u8; 16] =
+    *b"\x03\x00\x00\x00\x00\x00\x10\x00\x80\x00\x00\xaa\x00\x38\x9b\x71";
+
+/// Size of the [header()] in bytes.
+const HEADER_BYTES: u32 = 80;
+/// Sizes in a WAV file are 32-bit, including the RIFF chunk with the header.
+const MAX_DATA_BYTES: u32 = u32::MAX - HEADER_BYTES;
+
+/// Header of a WAV file of 32-bit float samples followed by `data_bytes` of interleaved samples.
+/// The extensible format is used, as it is the one that supports more than two channels.
+fn header(channels: u16, sample_rate: u32, data_bytes: u32) -> Vec<u8> {
+    let block_align = channels * 4;
+    let frames = data_bytes / block_align as u32;
+
+    let mut format = Vec::new();
+    format.extend_from_slice(&0xfffeu16.to_le_bytes());
+    format.extend_from_slice(&channels.to_le_bytes());
+    format.extend_from_slice(&sample_rate.to_le_bytes());
+    format.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+    format.extend_from_slice(&block_align.to_le_bytes());
+    format.extend_from_slice(&32u16.to_le_bytes());
+    // Size of the extension, valid bits per sample, no speaker positions.
+    format.extend_from_slice(&22u16.to_le_bytes());
+    format.extend_from_slice(&32u16.to_le_bytes());
+    format.extend_from_slice(&0u32.to_le_bytes());
+    format.extend_from_slice(&KSDATAFORMAT_SUBTYPE_IEEE_FLOAT);
+
+    let mut wav = b"RIFF".to_vec();
+    wav.extend_from_slice(&(HEADER_BYTES - 8 + data_bytes).to_le_bytes());
+    wav.extend_from_slice(b"WAVE");
+    for (id, chunk) in [(b"fmt ", &format[..]), (b"fact", &frames.to_le_bytes())] {
+        wav.extend_from_slice(id);
+        wav.extend_from_slice(&(chunk.len() as u32).to_le_bytes());
+        wav.extend_from_slice(chunk);
+    }
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_bytes.to_le_bytes());
+    debug_assert_eq!(wav.len(), HEADER_BYTES as usize);
+    wav
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mixer::{SampleData, MAX_CHANNELS};
+    use rodio::buffer::SamplesBuffer;
+    use std::fs;
+
+    #[test]
+    fn renders_multichannel_wav() {
+        let path = std::env::temp_dir().join(format!("composer-render-{}.wav", std::process::id()));
+        let mut render = WavRender::new(path.clone(), 4).unwrap();
+        let start = Duration::from_secs(1000);
+        // Two voices of two frames on the third channel 10 ms apart, 48 kHz and 24 kHz.
+        let sample =
+            |sample_rate| SampleData::new(SamplesBuffer::new(1, sample_rate, vec![0.5; 2]));
+        render.set_samples(Arc::new([sample(48_000), sample(24_000)]));
+        let mut channel_gains = [0.0; MAX_CHANNELS];
+        channel_gains[2] = 1.0;
+        let voice = |sample, timestamp| VoiceDescriptor {
+            sample,
+            timestamp,
+            channel_gains,
+            ..Default::default()
+        };
+        render.add(voice(0, start), start).unwrap();
+        let later = start + Duration::from_millis(10);
+        render.add(voice(1, later), later).unwrap();
+        // A probe with its clock a day ahead doesn't make the file a day long.
+        render.add(voice(0, later + Duration::from_secs(86_400)), later).unwrap();
+        assert_eq!(render.skipped, 1);
+        drop(render);
+
+        let wav = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(&wav[..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes(wav[4..8].try_into().unwrap()) as usize, wav.len() - 8);
+        assert_eq!(&wav[8..16], b"WAVEfmt ");
+        assert_eq!(u16::from_le_bytes([wav[22], wav[23]]), 4);
+        assert_eq!(u32::from_le_bytes(wav[24..28].try_into().unwrap()), 48_000);
+        let data_bytes = u32::from_le_bytes(wav[76..80].try_into().unwrap()) as usize;
+        assert_eq!(data_bytes, wav.len() - HEADER_BYTES as usize);
+
+        let data = wav.windows(4).position(|window| window == b"data").unwrap() + 8;
+        let samples: Vec<f32> = wav[data..]
+            .chunks(4)
+            .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+            .collect();
+        // Rendered in blocks until the voices end, and then a second for the reverb to ring out.
+        assert_eq!(samples.len(), (2 * BLOCK_FRAMES + 48_000) * 4);
+        // The second voice is 480 frames later and resampled to four frames.
+        let loud: Vec<_> = (0..samples.len()).filter(|&index| samples[index].abs() > 0.1).collect();
+        assert_eq!(loud, [2, 6, 480 * 4 + 2, 481 * 4 + 2, 482 * 4 + 2, 483 * 4 + 2]);
+    }
+}
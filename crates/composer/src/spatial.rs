@@ -0,0 +1,278 @@
+//! Spatial panning of voices to more than two speakers placed around the listeners, so that
+//! sources (hosts, racks...) can be told apart by their direction.
+
+use crate::listener::source_ip;
+use eyre::{bail, ensure, Result};
+use serde::Deserialize;
+use std::{collections::BTreeMap, f32::consts::PI, fmt, net::IpAddr, str::FromStr};
+
+/// The `[spatial]` section of the sound configuration, see `sounds.toml`.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SpatialConfig {
+    /// Azimuths of the speakers in degrees clockwise from the front, in the order of output
+    /// channels.
+    speakers: Vec<f32>,
+    panning: Panning,
+    /// Directions of sources in degrees, by their address (`10.0.1.7`), network (`10.0.1.0/24`)
+    /// or address prefix ending with a separator (`10.0.1.`).
+    #[serde(default)]
+    sources: BTreeMap<String, f32>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Panning {
+    /// Vector base amplitude panning: each voice plays from the pair of adjacent speakers around
+    /// its direction. Sharp, works with any layout.
+    Vbap,
+    /// First-order ambisonics with an in-phase decoder: each voice plays from all speakers, the
+    /// more the closer they are to its direction. Smooth, needs speakers spread evenly.
+    Ambisonics,
+}
+
+/// Validated [SpatialConfig].
+#[derive(Debug)]
+pub(crate) struct Spatial {
+    /// Azimuths of the speakers in radians.
+    speakers: Vec<f32>,
+    panning: Panning,
+    /// Source addresses and their azimuths in radians, most specific first.
+    sources: Vec<(Sources, f32)>,
+}
+
+/// Sources of datagrams, placed at a direction here and targeted by control commands.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Sources {
+    Address(IpAddr),
+    /// Addresses whose first bits match the given number of bits of the address.
+    Network(IpAddr, u8),
+    /// Addresses starting with whole groups like `10.0.1.` or `fd00:1:`.
+    Prefix(String),
+}
+
+impl Sources {
+    /// Whether `source` like `udp://10.0.1.7:1234` is one of these. Sources without an IP
+    /// address never are.
+    pub(crate) fn contains(&self, source: &str) -> bool {
+        source_ip(source).is_some_and(|ip| self.matches(ip))
+    }
+
+    fn matches(&self, ip: IpAddr) -> bool {
+        match (self, ip) {
+            (Self::Address(address), ip) => *address == ip,
+            (Self::Network(IpAddr::V4(network), bits), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - *bits as u32).unwrap_or(0);
+                u32::from(*network) & mask == u32::from(ip) & mask
+            },
+            (Self::Network(IpAddr::V6(network), bits), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - *bits as u32).unwrap_or(0);
+                u128::from(*network) & mask == u128::from(ip) & mask
+            },
+            (Self::Network(..), _) => false,
+            (Self::Prefix(prefix), ip) => ip.to_string().starts_with(prefix.as_str()),
+        }
+    }
+
+    /// Sort key, the more specific sources go first.
+    fn specificity(&self) -> std::cmp::Reverse<(u8, usize)> {
+        std::cmp::Reverse(match self {
+            Self::Address(_) => (2, 0),
+            Self::Network(_, bits) => (1, *bits as usize),
+            Self::Prefix(prefix) => (0, prefix.len()),
+        })
+    }
+}
+
+impl FromStr for Sources {
+    type Err = eyre::Report;
+
+    fn from_str(sources: &str) -> Result<Self> {
+        if let Some((address, bits)) = sources.split_once('/') {
+            let address: IpAddr = address.parse()?;
+            let bits: u8 = bits.parse()?;
+            let max_bits = if address.is_ipv4() { 32 } else { 128 };
+            ensure!(bits <= max_bits, "network {sources} has more than {max_bits} bits");
+            return Ok(Self::Network(address, bits));
+        }
+        if let Ok(address) = sources.parse() {
+            return Ok(Self::Address(address));
+        }
+        if sources.ends_with(['.', ':']) {
+            return Ok(Self::Prefix(sources.to_string()));
+        }
+        bail!("source {sources:?} should be an address, a network or a prefix ending with . or :")
+    }
+}
+
+impl fmt::Display for Sources {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Address(address) => write!(f, "{address}"),
+            Self::Network(address, bits) => write!(f, "{address}/{bits}"),
+            Self::Prefix(prefix) => write!(f, "{prefix}"),
+        }
+    }
+}
+
+impl Spatial {
+    pub(crate) fn new(config: SpatialConfig) -> Result<Self> {
+        ensure!(!config.speakers.is_empty(), "spatial layout needs at least one speaker");
+        ensure!(config.speakers.len() <= 64, "spatial layout supports at most 64 speakers");
+        for degrees in &config.speakers {
+            ensure!(degrees.is_finite(), "speaker azimuth {degrees} should be a finite number");
+        }
+        let mut sources = config
+            .sources
+            .into_iter()
+            .map(|(sources, degrees)| {
+                ensure!(degrees.is_finite(), "direction of {sources} should be a finite number");
+                Ok((sources.parse()?, degrees.to_radians()))
+            })
+            .collect::<Result<Vec<(Sources, f32)>>>()?;
+        // The most specific sources win, e.g. a host over its rack.
+        sources.sort_by_key(|(sources, _)| sources.specificity());
+        Ok(Self {
+            speakers: config.speakers.iter().map(|degrees| degrees.to_radians()).collect(),
+            panning: config.panning,
+            sources,
+        })
+    }
+
+    /// Number of output channels, one per speaker.
+    pub(crate) fn channels(&self) -> u16 {
+        self.speakers.len() as u16
+    }
+
+    /// Azimuth in radians assigned to a source like `udp://10.0.1.7:1234`, if any.
+    pub(crate) fn direction(&self, source: &str) -> Option<f32> {
+        let ip = source_ip(source)?;
+        self.sources.iter().find(|(sources, _)| sources.matches(ip)).map(|(_, azimuth)| *azimuth)
+    }
+
+    /// Gain of each speaker for a voice coming from `azimuth` in radians, with total power 1.
+    pub(crate) fn gains(&self, azimuth: f32) -> Vec<f32> {
+        let mut gains = match self.panning {
+            Panning::Vbap => self.vbap(azimuth),
+            // In-phase decoding of the horizontal first-order components, never negative.
+            Panning::Ambisonics => {
+                self.speakers.iter().map(|speaker| 1.0 + (azimuth - speaker).cos()).collect()
+            },
+        };
+        let power = gains.iter().map(|gain| gain * gain).sum::<f32>().sqrt();
+        if power > 0.0 {
+            gains.iter_mut().for_each(|gain| *gain /= power);
+        }
+        gains
+    }
+
+    fn vbap(&self, azimuth: f32) -> Vec<f32> {
+        let mut gains = vec![0.0; self.speakers.len()];
+        let mut order: Vec<usize> = (0..self.speakers.len()).collect();
+        order.sort_by(|&a, &b| normalize(self.speakers[a]).total_cmp(&normalize(self.speakers[b])));
+
+        // Find the pair of adjacent speakers the direction lies between, that is the pair whose
+        // gains are both non-negative. Pairs spanning more than a half circle have none.
+        let target = unit(azimuth);
+        for (index, &first) in order.iter().enumerate() {
+            let second = order[(index + 1) % order.len()];
+            let (a, b) = (unit(self.speakers[first]), unit(self.speakers[second]));
+            let determinant = a.0 * b.1 - a.1 * b.0;
+            if first == second || determinant.abs() < 1e-6 {
+                continue;
+            }
+            let first_gain = (target.0 * b.1 - target.1 * b.0) / determinant;
+            let second_gain = (a.0 * target.1 - a.1 * target.0) / determinant;
+            if first_gain >= -1e-6 && second_gain >= -1e-6 {
+                gains[first] = first_gain.max(0.0);
+                gains[second] = second_gain.max(0.0);
+                return gains;
+            }
+        }
+
+        // Outside of all pairs (or a single speaker), play from the closest speaker.
+        let closest = (0..self.speakers.len())
+            .max_by(|&a, &b| {
+                (azimuth - self.speakers[a]).cos().total_cmp(&(azimuth - self.speakers[b]).cos())
+            })
+            .expect("there should be at least one speaker");
+        gains[closest] = 1.0;
+        gains
+    }
+}
+
+/// Unit vector pointing to `azimuth` as (right, front).
+fn unit(azimuth: f32) -> (f32, f32) {
+    (azimuth.sin(), azimuth.cos())
+}
+
+/// Azimuth within 0 and 2π.
+fn normalize(azimuth: f32) -> f32 {
+    azimuth.rem_euclid(2.0 * PI)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::f32::consts::FRAC_1_SQRT_2 as HALF;
+
+    fn spatial(speakers: &[f32], panning: Panning) -> Spatial {
+        let sources = [
+            ("10.0.1.".to_string(), 90.0),
+            ("10.0.1.5".to_string(), -90.0),
+            ("10.0.8.0/21".to_string(), 180.0),
+            ("fd00::/16".to_string(), 0.0),
+        ];
+        let config =
+            SpatialConfig { speakers: speakers.to_vec(), panning, sources: sources.into() };
+        Spatial::new(config).unwrap()
+    }
+
+    fn assert_gains(actual: Vec<f32>, expected: &[f32]) {
+        let close =
+            actual.iter().zip(expected).all(|(actual, expected)| (actual - expected).abs() < 1e-3);
+        assert!(close && actual.len() == expected.len(), "{actual:?} vs {expected:?}");
+    }
+
+    #[test]
+    fn pans_between_speakers() {
+        let quad = spatial(&[-45.0, 45.0, -135.0, 135.0], Panning::Vbap);
+        assert_eq!(quad.channels(), 4);
+        assert_gains(quad.gains(45f32.to_radians()), &[0.0, 1.0, 0.0, 0.0]);
+        assert_gains(quad.gains(90f32.to_radians()), &[0.0, HALF, 0.0, HALF]);
+        assert_gains(quad.gains(180f32.to_radians()), &[0.0, 0.0, HALF, HALF]);
+        assert_gains(quad.gains(0.0), &[HALF, HALF, 0.0, 0.0]);
+
+        // No pair of a stereo layout covers the back, the closest speaker plays.
+        let stereo = spatial(&[-30.0, 30.0], Panning::Vbap);
+        assert_gains(stereo.gains(100f32.to_radians()), &[0.0, 1.0]);
+
+        let ring = spatial(&[0.0, 90.0, 180.0, 270.0], Panning::Ambisonics);
+        let gains = ring.gains(90f32.to_radians());
+        assert!(gains[1] > gains[0] && gains[0] == gains[2] && gains[3].abs() < 1e-6, "{gains:?}");
+
+        assert_eq!(quad.direction("udp://10.0.1.7:1234"), Some(90f32.to_radians()));
+        assert_eq!(quad.direction("udp://10.0.1.5:1234"), Some(-90f32.to_radians()));
+        assert_eq!(quad.direction("udp://10.0.2.1:1234"), None);
+        // Addresses are matched as a whole, not as strings.
+        assert_eq!(quad.direction("udp://110.0.1.7:1234"), None);
+        assert_eq!(quad.direction("udp://10.0.1.50:1234"), Some(90f32.to_radians()));
+        assert_eq!(quad.direction("tcp://10.0.15.255:1234"), Some(PI));
+        assert_eq!(quad.direction("tcp://10.0.16.1:1234"), None);
+        assert_eq!(quad.direction("udp://[fd00::1]:1234"), Some(0.0));
+        assert_eq!(quad.direction("udp://[::ffff:10.0.1.5]:1234"), Some(-90f32.to_radians()));
+        assert_eq!(quad.direction("unix:///tmp/composer.sock"), None);
+
+        let config = |source: &str, speaker, direction| SpatialConfig {
+            speakers: vec![speaker],
+            panning: Panning::Vbap,
+            sources: [(source.to_string(), direction)].into(),
+        };
+        assert!(Spatial::new(config("10.0.1", 0.0, 0.0)).is_err());
+        assert!(Spatial::new(config("10.0.0.0/33", 0.0, 0.0)).is_err());
+        for angle in [f32::NAN, f32::INFINITY] {
+            assert!(Spatial::new(config("10.0.1.", angle, 0.0)).is_err());
+            assert!(Spatial::new(config("10.0.1.", 0.0, angle)).is_err());
+        }
+    }
+}
@@ -0,0 +1,149 @@
+//! Bounded lock-free single-producer single-consumer queue, to pass values to and from the audio
+//! thread without locking or allocating.
+
+use std::{
+    cell::{Cell, UnsafeCell},
+    marker::PhantomData,
+    mem::MaybeUninit,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+/// Create a queue holding at most `capacity` values.
+pub(crate) fn channel<T: Send>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    assert!(capacity > 0, "capacity should be positive");
+    let ring = Arc::new(Ring {
+        slots: (0..capacity).map(|_| UnsafeCell::new(MaybeUninit::uninit())).collect(),
+        head: AtomicUsize::new(0),
+        tail: AtomicUsize::new(0),
+    });
+    (Producer { ring: Arc::clone(&ring), _not_sync: PhantomData }, Consumer { ring })
+}
+
+struct Ring<T> {
+    slots: Box<[UnsafeCell<MaybeUninit<T>>]>,
+    /// Number of values popped so far, only written by the consumer.
+    head: AtomicUsize,
+    /// Number of values pushed so far, only written by the producer.
+    tail: AtomicUsize,
+}
+
+// SAFETY: slots from `head` to `tail` are only accessed by the consumer and the others only by the
+// producer. Each side publishes its accesses by a release store of its counter.
+unsafe impl<T: Send> Sync for Ring<T> {}
+
+impl<T> Ring<T> {
+    fn slot(&self, position: usize) -> *mut MaybeUninit<T> {
+        self.slots[position % self.slots.len()].get()
+    }
+}
+
+impl<T> Drop for Ring<T> {
+    fn drop(&mut self) {
+        let (head, tail) = (*self.head.get_mut(), *self.tail.get_mut());
+        for offset in 0..tail.wrapping_sub(head) {
+            // SAFETY: values from `head` to `tail` were pushed and not popped.
+            unsafe { (*self.slot(head.wrapping_add(offset))).assume_init_drop() };
+        }
+    }
+}
+
+/// The sending side. Can be moved to another thread but not shared, as there is a single
+/// producer.
+pub(crate) struct Producer<T> {
+    ring: Arc<Ring<T>>,
+    _not_sync: PhantomData<Cell<()>>,
+}
+
+impl<T> Producer<T> {
+    /// Push `value` to the queue, or give it back if the queue is full.
+    pub(crate) fn push(&self, value: T) -> Result<(), T> {
+        let tail = self.ring.tail.load(Ordering::Relaxed);
+        if self.len(tail) == self.ring.slots.len() {
+            return Err(value);
+        }
+        // SAFETY: the slot is not between `head` and `tail`, so the consumer does not touch it.
+        unsafe { (*self.ring.slot(tail)).write(value) };
+        self.ring.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+
+    pub(crate) fn is_full(&self) -> bool {
+        self.len(self.ring.tail.load(Ordering::Relaxed)) == self.ring.slots.len()
+    }
+
+    fn len(&self, tail: usize) -> usize {
+        tail.wrapping_sub(self.ring.head.load(Ordering::Acquire))
+    }
+}
+
+/// The receiving side.
+pub(crate) struct Consumer<T> {
+    ring: Arc<Ring<T>>,
+}
+
+impl<T> Consumer<T> {
+    /// The next value, without removing it from the queue.
+    pub(crate) fn peek(&mut self) -> Option<&T> {
+        let head = self.ring.head.load(Ordering::Relaxed);
+        if head == self.ring.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        // SAFETY: the value was pushed and can't be popped while borrowed.
+        Some(unsafe { (*self.ring.slot(head)).assume_init_ref() })
+    }
+
+    pub(crate) fn pop(&mut self) -> Option<T> {
+        let head = self.ring.head.load(Ordering::Relaxed);
+        if head == self.ring.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        // SAFETY: the value was pushed, and the producer does not reuse the slot until `head`
+        // moves past it.
+        let value = unsafe { (*self.ring.slot(head)).assume_init_read() };
+        self.ring.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn passes_values_between_threads() {
+        let (producer, mut consumer) = channel(4);
+        for value in 0..4 {
+            producer.push(Box::new(value)).unwrap();
+        }
+        assert!(producer.is_full());
+        assert_eq!(producer.push(Box::new(4)), Err(Box::new(4)));
+        assert_eq!(consumer.peek(), Some(&Box::new(0)));
+        assert_eq!(consumer.pop(), Some(Box::new(0)));
+
+        let sender = thread::spawn(move || {
+            for value in 4..10_000 {
+                let mut value = Box::new(value);
+                while let Err(rejected) = producer.push(value) {
+                    value = rejected;
+                    thread::yield_now();
+                }
+            }
+        });
+        let mut expected = 1;
+        while expected < 10_000 {
+            match consumer.pop() {
+                Some(value) => {
+                    assert_eq!(*value, expected);
+                    expected += 1;
+                },
+                None => thread::yield_now(),
+            }
+        }
+        sender.join().unwrap();
+        assert_eq!(consumer.pop(), None);
+    }
+}
@@ -0,0 +1,430 @@
+use crate::{
+    aggregator::Aggregate,
+    audio_output::AudioOutput,
+    histogram::{Histogram, Summary},
+    listener::source_ip,
+};
+use composer_api::capture::Record;
+use serde::{Serialize, Serializer};
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Write},
+    time::{Duration, Instant},
+};
+
+/// Counters of received datagrams and events, reported periodically.
+pub(crate) struct Stats {
+    since: Instant,
+    current: Snapshot,
+    last: Option<Snapshot>,
+    /// Network latency of events from each source host in the current period.
+    latencies: BTreeMap<String, Histogram>,
+    /// Sums of all reported snapshots, `period` is the total time covered.
+    totals: Snapshot,
+}
+
+/// Counters over one reporting period, serialized as a JSON object by [Snapshot::to_json()].
+#[derive(Debug, Clone, Default, Serialize)]
+pub(crate) struct Snapshot {
+    #[serde(rename = "period_s", serialize_with = "as_seconds")]
+    pub(crate) period: Duration,
+    pub(crate) listeners: Vec<ListenerStats>,
+    /// Number of events of each kind.
+    pub(crate) kinds: BTreeMap<&'static str, usize>,
+    /// Number of events from each source host, see [source_host()].
+    pub(crate) sources: BTreeMap<String, usize>,
+    pub(crate) too_early_plays: u64,
+    /// Voices not played on their own because the aggregation stage merged them.
+    pub(crate) merged_voices: usize,
+    /// Voices not played at all because there were too many at once for the audio output.
+    pub(crate) dropped_voices: usize,
+    /// Voices scheduled or playing at the end of the period.
+    pub(crate) active_voices: usize,
+    /// Mean time from event timestamps to their playback.
+    #[serde(rename = "effective_delay_s", serialize_with = "as_seconds")]
+    pub(crate) effective_delay: Duration,
+    /// Time from arrival of voices to the audio thread to the playback of the buffer they are
+    /// scheduled in. Negative values are late voices. Tells whether `--delay-ms` is sufficient.
+    pub(crate) scheduling_slack: Option<Summary>,
+    /// Time from event timestamps to their arrival, by source host. Large or negative values mean
+    /// slow network or a probe with a bad clock.
+    pub(crate) network_latency: BTreeMap<String, Summary>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub(crate) struct ListenerStats {
+    pub(crate) name: String,
+    pub(crate) datagrams: usize,
+    pub(crate) total_bytes: usize,
+    pub(crate) rejected: usize,
+    pub(crate) decode_errors: usize,
+}
+
+impl Stats {
+    const REPORT_EVERY: Duration = Duration::from_secs(1);
+
+    pub(crate) fn new(listener_names: impl IntoIterator<Item = String>) -> Self {
+        let listeners =
+            listener_names.into_iter().map(|name| ListenerStats { name, ..Default::default() });
+        let current = Snapshot { listeners: listeners.collect(), ..Default::default() };
+        let totals = current.clone();
+        Self { since: Instant::now(), current, last: None, latencies: BTreeMap::new(), totals }
+    }
+
+    /// The summary line of the most recent report, or the report as JSON.
+    pub(crate) fn last_report(&self, json: bool) -> String {
+        match &self.last {
+            Some(snapshot) if json => snapshot.to_json(),
+            Some(snapshot) => snapshot.to_string(),
+            None => "no stats reported yet".to_string(),
+        }
+    }
+
+    pub(crate) fn record_packet(
+        &mut self,
+        listener: usize,
+        record: &Record,
+        bytes_received: usize,
+    ) {
+        let listener = &mut self.current.listeners[listener];
+        listener.datagrams += 1;
+        listener.total_bytes += bytes_received;
+
+        for event in &record.packet.events {
+            *self.current.kinds.entry(event.kind.name()).or_default() += 1;
+        }
+        let host = source_host(&record.source);
+        let mut timestamps =
+            record.packet.events.iter().filter_map(|event| event.timestamp).peekable();
+        if timestamps.peek().is_some() {
+            let latency = self.latencies.entry(host.clone()).or_default();
+            timestamps.for_each(|timestamp| latency.record(record.arrival, timestamp));
+        }
+        *self.current.sources.entry(host).or_default() += record.packet.events.len();
+    }
+
+    pub(crate) fn record_rejected(&mut self, listener: usize) {
+        self.current.listeners[listener].rejected += 1;
+    }
+
+    pub(crate) fn record_decode_error(&mut self, listener: usize) {
+        self.current.listeners[listener].decode_errors += 1;
+    }
+
+    /// Once per reporting period, finish the current snapshot and return it.
+    pub(crate) fn report_if_due(
+        &mut self,
+        audio_output: Option<&AudioOutput>,
+        aggregator: &mut dyn Aggregate,
+    ) -> Option<&Snapshot> {
+        let elapsed = self.since.elapsed();
+        if elapsed < Self::REPORT_EVERY {
+            return None;
+        }
+
+        let listeners = self
+            .current
+            .listeners
+            .iter()
+            .map(|listener| ListenerStats { name: listener.name.clone(), ..Default::default() });
+        let next = Snapshot { listeners: listeners.collect(), ..Default::default() };
+        let mut snapshot = Snapshot {
+            period: elapsed,
+            merged_voices: aggregator.fetch_merged(),
+            network_latency: std::mem::take(&mut self.latencies)
+                .into_iter()
+                .filter_map(|(source, latency)| Some((source, latency.summary()?)))
+                .collect(),
+            ..std::mem::replace(&mut self.current, next)
+        };
+        if let Some(audio_output) = audio_output {
+            snapshot.too_early_plays = audio_output.fetch_too_early_plays();
+            snapshot.dropped_voices = audio_output.fetch_dropped_voices() as usize;
+            snapshot.active_voices = audio_output.meters().active_voices();
+            snapshot.effective_delay = audio_output.fetch_effective_delay();
+            snapshot.scheduling_slack = audio_output.fetch_slack().summary();
+        }
+        self.since = Instant::now();
+        self.totals.accumulate(&snapshot);
+        self.last = Some(snapshot);
+        self.last.as_ref()
+    }
+
+    /// Totals since the start in the Prometheus text exposition format.
+    pub(crate) fn prometheus(&self) -> String {
+        prometheus(&self.totals)
+    }
+}
+
+impl Snapshot {
+    /// Print the summary line, and a line per listener if there are more of them.
+    pub(crate) fn print(&self) {
+        println!("{self}");
+        if self.listeners.len() > 1 {
+            for listener in &self.listeners {
+                println!(
+                    "  {}: {} events ({} bytes), {} rejected, {} invalid.",
+                    listener.name,
+                    listener.datagrams,
+                    listener.total_bytes,
+                    listener.rejected,
+                    listener.decode_errors,
+                );
+            }
+        }
+        if let Some(slack) = &self.scheduling_slack {
+            println!("  Scheduling slack: {slack}.");
+        }
+        for (source, latency) in &self.network_latency {
+            println!("  Network latency from {source}: {latency}.");
+        }
+    }
+
+    /// The snapshot as a single line of JSON.
+    pub(crate) fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("snapshot should be serializable")
+    }
+
+    /// Add counters of `other` to ours, take its gauges.
+    fn accumulate(&mut self, other: &Snapshot) {
+        self.period += other.period;
+        for (listener, other) in self.listeners.iter_mut().zip(&other.listeners) {
+            listener.datagrams += other.datagrams;
+            listener.total_bytes += other.total_bytes;
+            listener.rejected += other.rejected;
+            listener.decode_errors += other.decode_errors;
+        }
+        for (&kind, count) in &other.kinds {
+            *self.kinds.entry(kind).or_default() += count;
+        }
+        for (source, count) in &other.sources {
+            *self.sources.entry(source.clone()).or_default() += count;
+        }
+        self.too_early_plays += other.too_early_plays;
+        self.merged_voices += other.merged_voices;
+        self.dropped_voices += other.dropped_voices;
+        self.active_voices = other.active_voices;
+        self.effective_delay = other.effective_delay;
+        self.scheduling_slack = other.scheduling_slack;
+        self.network_latency.clone_from(&other.network_latency);
+    }
+}
+
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sum =
+            |count: fn(&ListenerStats) -> usize| self.listeners.iter().map(count).sum::<usize>();
+        write!(
+            f,
+            "Received {} events ({} bytes) in last {:.2?}, {} too early plays, {} \
+             unauthenticated datagrams rejected.",
+            sum(|listener| listener.datagrams),
+            sum(|listener| listener.total_bytes),
+            self.period,
+            self.too_early_plays,
+            sum(|listener| listener.rejected),
+        )
+    }
+}
+
+/// Host of a source address like `udp://10.0.1.7:1234`, so that the totals of probes are not
+/// split by the ephemeral ports they send from and don't grow with every restart of a probe.
+/// Unix socket sources are kept as they are.
+fn source_host(source: &str) -> String {
+    source_ip(source).map_or_else(|| source.to_string(), |ip| ip.to_string())
+}
+
+fn as_seconds<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
+
+fn prometheus(totals: &Snapshot) -> String {
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, samples: Vec<(String, String)>| {
+        // Writing to a String cannot fail.
+        let _ = writeln!(out, "# HELP composer_{name} {help}\n# TYPE composer_{name} {kind}");
+        for (labels, value) in samples {
+            let _ = writeln!(out, "composer_{name}{labels} {value}");
+        }
+    };
+    let per_listener = |count: fn(&ListenerStats) -> usize| {
+        let samples = totals.listeners.iter().map(|listener| {
+            (format!("{{listener=\"{}\"}}", escape(&listener.name)), count(listener).to_string())
+        });
+        samples.collect()
+    };
+    let single = |value: String| vec![(String::new(), value)];
+
+    metric(
+        "datagrams_total",
+        "counter",
+        "Datagrams received.",
+        per_listener(|listener| listener.datagrams),
+    );
+    metric(
+        "received_bytes_total",
+        "counter",
+        "Bytes received.",
+        per_listener(|listener| listener.total_bytes),
+    );
+    metric(
+        "rejected_datagrams_total",
+        "counter",
+        "Datagrams rejected as not signed with the shared key.",
+        per_listener(|listener| listener.rejected),
+    );
+    metric(
+        "invalid_datagrams_total",
+        "counter",
+        "Datagrams that could not be decoded.",
+        per_listener(|listener| listener.decode_errors),
+    );
+    let kinds = totals
+        .kinds
+        .iter()
+        .map(|(kind, count)| (format!("{{kind=\"{}\"}}", escape(kind)), count.to_string()));
+    metric("events_total", "counter", "Events received by kind.", kinds.collect());
+    let sources = totals
+        .sources
+        .iter()
+        .map(|(source, count)| (format!("{{source=\"{}\"}}", escape(source)), count.to_string()));
+    metric("source_events_total", "counter", "Events received by source.", sources.collect());
+    metric(
+        "too_early_plays_total",
+        "counter",
+        "Voices that arrived too late to be played on time.",
+        single(totals.too_early_plays.to_string()),
+    );
+    metric(
+        "merged_voices_total",
+        "counter",
+        "Voices merged into aggregated ones.",
+        single(totals.merged_voices.to_string()),
+    );
+    metric(
+        "dropped_voices_total",
+        "counter",
+        "Voices not played because there were too many at once for the audio output.",
+        single(totals.dropped_voices.to_string()),
+    );
+    metric(
+        "active_voices",
+        "gauge",
+        "Voices scheduled or playing.",
+        single(totals.active_voices.to_string()),
+    );
+    metric(
+        "effective_delay_seconds",
+        "gauge",
+        "Mean time from event timestamps to their playback.",
+        single(totals.effective_delay.as_secs_f64().to_string()),
+    );
+    let quantiles = |labels: String, summary: &Summary| {
+        let quantiles = [
+            ("0.5", summary.p50_ms),
+            ("0.9", summary.p90_ms),
+            ("0.99", summary.p99_ms),
+            ("1", summary.max_ms),
+        ];
+        quantiles.map(|(quantile, millis)| {
+            (format!("{{{labels}quantile=\"{quantile}\"}}"), (millis / 1000.0).to_string())
+        })
+    };
+    metric(
+        "scheduling_slack_seconds",
+        "gauge",
+        "Quantiles of scheduling slack in the last period, negative for late voices.",
+        totals.scheduling_slack.iter().flat_map(|slack| quantiles(String::new(), slack)).collect(),
+    );
+    let latencies = totals.network_latency.iter().flat_map(|(source, latency)| {
+        quantiles(format!("source=\"{}\",", escape(source)), latency)
+    });
+    metric(
+        "network_latency_seconds",
+        "gauge",
+        "Quantiles of network latency by source in the last period.",
+        latencies.collect(),
+    );
+    out
+}
+
+/// Escape a Prometheus label value.
+fn escape(value: &str) -> String {
+    value.replace('\\', r"\\").replace('"', r#"\""#).replace('\n', r"\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use composer_api::{Event, EventKind, Packet};
+
+    fn snapshot() -> Snapshot {
+        Snapshot {
+            period: Duration::from_millis(1500),
+            listeners: vec![ListenerStats {
+                name: "udp://127.0.0.1:8888".to_string(),
+                datagrams: 3,
+                ..Default::default()
+            }],
+            kinds: [("TestTick", 4)].into(),
+            sources: [("127.0.0.1".to_string(), 4)].into(),
+            merged_voices: 5,
+            dropped_voices: 1,
+            effective_delay: Duration::from_millis(200),
+            network_latency: [("127.0.0.1".to_string(), summary())].into(),
+            ..Default::default()
+        }
+    }
+
+    fn summary() -> Summary {
+        Summary { count: 4, p50_ms: 2.0, p90_ms: 3.0, p99_ms: 4.0, max_ms: 5.0 }
+    }
+
+    #[test]
+    fn serializes_snapshot_as_json() {
+        let json: serde_json::Value = serde_json::from_str(&snapshot().to_json()).unwrap();
+        assert_eq!(json["period_s"], 1.5);
+        assert_eq!(json["listeners"][0]["datagrams"], 3);
+        assert_eq!(json["kinds"]["TestTick"], 4);
+        assert_eq!(json["sources"]["127.0.0.1"], 4);
+        assert_eq!(json["effective_delay_s"], 0.2);
+        assert_eq!(json["scheduling_slack"], serde_json::Value::Null);
+        assert_eq!(json["network_latency"]["127.0.0.1"]["p99_ms"], 4.0);
+    }
+
+    #[test]
+    fn counts_events_by_source_host() {
+        let mut stats = Stats::new(["udp://[::]:8888".to_string()]);
+        for source in ["udp://10.0.1.7:40001", "udp://10.0.1.7:40002", "unix:///tmp/composer.sock"]
+        {
+            let events = vec![Event::new(EventKind::TestTick); 2];
+            let packet = Packet::new(events);
+            let record = Record { arrival: Duration::ZERO, source: source.to_string(), packet };
+            stats.record_packet(0, &record, 10);
+        }
+        let sources = [("10.0.1.7".to_string(), 4), ("unix:///tmp/composer.sock".to_string(), 2)];
+        assert_eq!(stats.current.sources, sources.into());
+    }
+
+    #[test]
+    fn renders_prometheus_totals() {
+        let listener =
+            ListenerStats { name: "udp://127.0.0.1:8888".to_string(), ..Default::default() };
+        let mut totals = Snapshot { listeners: vec![listener], ..Default::default() };
+        totals.accumulate(&snapshot());
+        totals.accumulate(&snapshot());
+
+        let text = prometheus(&totals);
+        assert!(text.contains("composer_datagrams_total{listener=\"udp://127.0.0.1:8888\"} 6\n"));
+        assert!(text.contains("composer_events_total{kind=\"TestTick\"} 8\n"));
+        assert!(text.contains("composer_merged_voices_total 10\n"));
+        assert!(text.contains("composer_dropped_voices_total 2\n"));
+        assert!(text.contains("# TYPE composer_active_voices gauge\ncomposer_active_voices 0\n"));
+        assert!(text.contains("composer_effective_delay_seconds 0.2\n"));
+        assert!(text.contains(
+            "composer_network_latency_seconds{source=\"127.0.0.1\",quantile=\"0.99\"} \
+             0.004\n"
+        ));
+        assert_eq!(escape("a\"b\\c"), r#"a\"b\\c"#);
+    }
+}
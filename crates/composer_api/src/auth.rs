@@ -0,0 +1,131 @@
+//! Optional authentication of datagrams using HMAC-SHA256 with a pre-shared key.
+//!
+//! A signed datagram is the `0x03` header byte, followed by the 32-byte HMAC of the inner datagram,
+//! followed by the inner datagram itself (in any of the [`crate::Encoding`]s).
+
+use eyre::{ensure, Context, Result};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::{fmt, path::Path};
+
+/// Header byte of signed datagrams.
+pub(crate) const SIGNED_HEADER: u8 = 0x03;
+const TAG_LENGTH: usize = 32;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Secret shared between probes and the composer.
+#[derive(Clone)]
+pub struct SharedKey {
+    key: Vec<u8>,
+}
+
+impl SharedKey {
+    pub fn new(key: impl Into<Vec<u8>>) -> Result<Self> {
+        let key = key.into();
+        ensure!(!key.is_empty(), "shared key must not be empty");
+        Ok(Self { key })
+    }
+
+    /// Read key from a file. Trailing whitespace (like the final newline) is not part of the key.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut key = std::fs::read(path).with_context(|| format!("reading {path:?}"))?;
+        while key.last().is_some_and(u8::is_ascii_whitespace) {
+            key.pop();
+        }
+        Self::new(key).with_context(|| format!("loading key from {path:?}"))
+    }
+
+    fn mac(&self) -> HmacSha256 {
+        HmacSha256::new_from_slice(&self.key).expect("HMAC accepts keys of any length")
+    }
+
+    /// Wrap `datagram` into a signed datagram.
+    pub fn sign(&self, datagram: &[u8]) -> Vec<u8> {
+        let mut mac = self.mac();
+        mac.update(datagram);
+
+        let mut signed = Vec::with_capacity(1 + TAG_LENGTH + datagram.len());
+        signed.push(SIGNED_HEADER);
+        signed.extend(mac.finalize().into_bytes());
+        signed.extend(datagram);
+        signed
+    }
+
+    /// Check the signature of a signed datagram and return the inner datagram.
+    pub fn verify<'a>(&self, signed: &'a [u8]) -> Result<&'a [u8], Unauthenticated> {
+        let (tag, datagram) = split_signed(signed).ok_or(Unauthenticated::NotSigned)?;
+
+        let mut mac = self.mac();
+        mac.update(datagram);
+        mac.verify_slice(tag).map_err(|_| Unauthenticated::BadSignature)?;
+
+        Ok(datagram)
+    }
+}
+
+impl fmt::Debug for SharedKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedKey").finish_non_exhaustive()
+    }
+}
+
+/// Split a signed datagram into its tag and the inner datagram, if it is a signed one.
+pub(crate) fn split_signed(signed: &[u8]) -> Option<(&[u8], &[u8])> {
+    match signed.split_first() {
+        Some((&SIGNED_HEADER, rest)) if rest.len() >= TAG_LENGTH => Some(rest.split_at(TAG_LENGTH)),
+        _ => None,
+    }
+}
+
+/// Reason for rejecting a datagram by [`SharedKey::verify()`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unauthenticated {
+    NotSigned,
+    BadSignature,
+}
+
+impl fmt::Display for Unauthenticated {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotSigned => write!(f, "datagram is not signed"),
+            Self::BadSignature => write!(f, "datagram signature does not match"),
+        }
+    }
+}
+
+impl std::error::Error for Unauthenticated {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn accepts_correctly_signed_datagram() {
+        let key = SharedKey::new("secret").unwrap();
+        let signed = key.sign(b"hello");
+        assert_eq!(key.verify(&signed), Ok(&b"hello"[..]));
+    }
+
+    #[test]
+    fn rejects_tampered_or_foreign_datagrams() {
+        let key = SharedKey::new("secret").unwrap();
+        let mut signed = key.sign(b"hello");
+
+        let other_key = SharedKey::new("other").unwrap();
+        assert_eq!(other_key.verify(&signed), Err(Unauthenticated::BadSignature));
+
+        *signed.last_mut().unwrap() = b'!';
+        assert_eq!(key.verify(&signed), Err(Unauthenticated::BadSignature));
+
+        assert_eq!(key.verify(b"{\"kind\":\"TestTick\"}"), Err(Unauthenticated::NotSigned));
+    }
+
+    #[test]
+    fn decode_skips_signature() {
+        let key = SharedKey::new("secret").unwrap();
+        let signed = key.sign(b"{\"kind\":\"TestTick\"}");
+        assert_eq!(crate::encoding::decode(&signed).unwrap().events.len(), 1);
+    }
+}
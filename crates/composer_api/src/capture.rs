@@ -0,0 +1,185 @@
+//! Capture files of packets received by the composer, for archiving and later replay.
+//!
+//! A capture file starts with [`MAGIC`], followed by any number of records. Each record is its
+//! length as a big-endian `u32` followed by a bincode-serialized [`Record`]. Appending records to
+//! an existing capture file produces a valid capture file.
+//!
+//! Files of the previous version [`V1_MAGIC`], recorded before events had a weight, can still be
+//! read.
+
+use crate::{Event, EventKind, Packet};
+use eyre::{bail, ensure, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    io::{BufRead, ErrorKind, Write},
+    time::Duration,
+};
+
+/// Identifies capture files and their format version.
+pub const MAGIC: &[u8; 8] = b"ACAPv002";
+/// Identifies capture files whose events have no weight.
+pub const V1_MAGIC: &[u8; 8] = b"ACAPv001";
+
+/// Sanity limit of a single record size, protects against reading garbage.
+const MAX_RECORD_SIZE: usize = 1 << 24;
+
+/// A packet as received by the composer.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Record {
+    /// When the packet arrived, as the duration since UNIX epoch.
+    pub arrival: Duration,
+    /// Address the packet came from, like `udp://127.0.0.1:5432`.
+    pub source: String,
+    pub packet: Packet,
+}
+
+pub struct CaptureWriter<W> {
+    writer: W,
+}
+
+impl<W: Write> CaptureWriter<W> {
+    /// Start writing a new capture file.
+    pub fn new(mut writer: W) -> Result<Self> {
+        writer.write_all(MAGIC)?;
+        Ok(Self { writer })
+    }
+
+    /// Continue writing a capture file that already has the header.
+    pub fn append(writer: W) -> Self {
+        Self { writer }
+    }
+
+    /// Write a record, returns the number of bytes written. Fails without writing anything if
+    /// the record is too large to be read back.
+    pub fn write(&mut self, record: &Record) -> Result<usize> {
+        let data = bincode::serialize(record)?;
+        ensure!(data.len() <= MAX_RECORD_SIZE, "record of {} bytes is too large", data.len());
+        self.writer.write_all(&(data.len() as u32).to_be_bytes())?;
+        self.writer.write_all(&data)?;
+        Ok(4 + data.len())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        Ok(self.writer.flush()?)
+    }
+}
+
+/// Reads [`Record`]s from a capture file, in the order they were written.
+pub struct CaptureReader<R> {
+    reader: R,
+    v1: bool,
+}
+
+impl<R: BufRead> CaptureReader<R> {
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut magic = [0; MAGIC.len()];
+        reader.read_exact(&mut magic)?;
+        let v1 = match &magic {
+            MAGIC => false,
+            V1_MAGIC => true,
+            _ => bail!("not a capture file (or an unsupported version)"),
+        };
+        Ok(Self { reader, v1 })
+    }
+
+    /// Read the next record, returns `None` at the end of the file.
+    pub fn read(&mut self) -> Result<Option<Record>> {
+        let mut length = [0; 4];
+        match self.reader.read_exact(&mut length) {
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            result => result?,
+        }
+        let length = u32::from_be_bytes(length) as usize;
+        ensure!(length <= MAX_RECORD_SIZE, "record of {length} bytes is too large");
+
+        let mut data = vec![0; length];
+        self.reader.read_exact(&mut data)?;
+        match self.v1 {
+            true => Ok(Some(bincode::deserialize::<V1Record>(&data)?.into())),
+            false => Ok(Some(bincode::deserialize(&data)?)),
+        }
+    }
+}
+
+/// [`Record`] as written to [`V1_MAGIC`] files.
+#[derive(Deserialize)]
+struct V1Record {
+    arrival: Duration,
+    source: String,
+    /// The packet, a single-field struct, with events of a kind and an optional timestamp.
+    events: Vec<(EventKind, Option<Duration>)>,
+}
+
+impl From<V1Record> for Record {
+    fn from(V1Record { arrival, source, events }: V1Record) -> Self {
+        let events = events
+            .into_iter()
+            .map(|(kind, timestamp)| Event { kind, timestamp, weight: 1.0 })
+            .collect();
+        Self { arrival, source, packet: Packet::new(events) }
+    }
+}
+
+impl<R: BufRead> Iterator for CaptureReader<R> {
+    type Item = Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read().transpose()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reads_written_and_appended_records() {
+        let record = |secs| Record {
+            arrival: Duration::from_secs(secs),
+            source: "udp://127.0.0.1:1234".to_string(),
+            packet: Packet::from_event(Event::new(EventKind::TestTick)),
+        };
+
+        let mut data = Vec::new();
+        let mut writer = CaptureWriter::new(&mut data).unwrap();
+        writer.write(&record(1)).unwrap();
+        CaptureWriter::append(&mut data).write(&record(2)).unwrap();
+
+        let records: Vec<_> =
+            CaptureReader::new(&data[..]).unwrap().collect::<Result<_>>().unwrap();
+        assert_eq!(records, [record(1), record(2)]);
+
+        assert!(CaptureReader::new(&b"not a capture"[..]).is_err());
+    }
+
+    #[test]
+    fn rejects_records_too_large_to_read() {
+        let record = Record {
+            arrival: Duration::from_secs(1),
+            source: "x".repeat(MAX_RECORD_SIZE),
+            packet: Packet::from_event(Event::new(EventKind::TestTick)),
+        };
+        let mut data = Vec::new();
+        assert!(CaptureWriter::new(&mut data).unwrap().write(&record).is_err());
+        assert_eq!(data, MAGIC);
+    }
+
+    #[test]
+    fn reads_v1_records() {
+        let timestamp = Some(Duration::from_secs(2));
+        let v1 = (
+            Duration::from_secs(1),
+            "udp://127.0.0.1:1234",
+            vec![(EventKind::TestTick, timestamp)],
+        );
+        let data = bincode::serialize(&v1).unwrap();
+        let mut file = V1_MAGIC.to_vec();
+        file.extend((data.len() as u32).to_be_bytes());
+        file.extend(data);
+
+        let records: Vec<_> =
+            CaptureReader::new(&file[..]).unwrap().collect::<Result<_>>().unwrap();
+        let event = Event::with_timestamp(EventKind::TestTick, Duration::from_secs(2));
+        assert_eq!(records[0].packet, Packet::from_event(event));
+    }
+}
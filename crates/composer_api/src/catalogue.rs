@@ -0,0 +1,214 @@
+//! Machine-readable catalogue of [`EventKind`](crate::EventKind)s: their names, meaning, fields, how often they
+//! typically occur and which probes send them. Used to validate configurations and to document
+//! the kinds in user interfaces, see [`EventKind::info()`](crate::EventKind::info) and `composer --list-event-kinds`.
+//!
+//! The catalogue is generated together with the event kinds by the `event_kinds!` macro, so that
+//! it can't miss a kind or a field.
+
+use serde::Serialize;
+
+/// Description of an event kind.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct KindInfo {
+    /// Name of the kind, as returned by [`EventKind::name()`](crate::EventKind::name) and used in configurations.
+    pub name: &'static str,
+    pub description: &'static str,
+    pub fields: &'static [FieldInfo],
+    /// Order of magnitude of the rate of events of the kind from a busy source, per second.
+    pub typical_rate: f64,
+    /// Binaries of the probes that send events of the kind.
+    pub probes: &'static [&'static str],
+    /// Labels that tell events of the kind apart in the sound configuration, like `Error` in
+    /// `Log.Error`.
+    pub labels: &'static [&'static str],
+}
+
+/// Description of a field of an event kind.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct FieldInfo {
+    pub name: &'static str,
+    /// Rust type of the field.
+    #[serde(rename = "type")]
+    pub type_name: &'static str,
+    pub description: &'static str,
+}
+
+/// All event kinds, in the order of [`EventKind`](crate::EventKind) variants.
+pub static CATALOGUE: &[&KindInfo] = crate::KINDS;
+
+/// Look up an event kind by its name.
+pub fn find(name: &str) -> Option<&'static KindInfo> {
+    CATALOGUE.iter().copied().find(|info| info.name == name)
+}
+
+/// Names of all event kinds, comma-separated, for error messages.
+pub fn names() -> String {
+    CATALOGUE.iter().map(|info| info.name).collect::<Vec<_>>().join(", ")
+}
+
+/// Define the `EventKind` enum and its catalogue from a list of variants. Each variant is a unit
+/// variant, a struct variant whose fields are followed by `=> "<description>"` or a newtype
+/// variant of a struct defined by [`described_struct!`], followed by
+/// `=> { description: .., typical_rate: .., probes: [..] }` and optionally `labels: ..`, e.g. the
+/// names of an enum defined by [`labels!`]. The description is also the doc comment of the variant.
+macro_rules! event_kinds {
+    (@fields) => { &[] };
+    (@fields { $($field:ident: $type:ty => $field_description:literal),* }) => {
+        &[$($crate::catalogue::FieldInfo {
+            name: stringify!($field),
+            type_name: stringify!($type),
+            description: $field_description,
+        }),*]
+    };
+    (@fields ($inner:ty)) => { <$inner>::FIELDS };
+    (@labels) => { &[] };
+    (@labels $labels:expr) => { $labels };
+    ($(
+        $kind:ident
+        $({ $($field:ident: $type:ty => $field_description:literal),* $(,)? })?
+        $(($inner:ty))?
+        => {
+            description: $description:literal,
+            typical_rate: $typical_rate:literal,
+            probes: [$($probe:literal),* $(,)?]
+            $(, labels: $labels:expr)? $(,)?
+        },
+    )*) => {
+        #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+        pub enum EventKind {$(
+            #[doc = $description]
+            $kind
+            $({ $(#[doc = $field_description] $field: $type),* })?
+            $(($inner))?,
+        )*}
+
+        /// Descriptions of event kinds, named like them.
+        #[allow(non_upper_case_globals)]
+        mod kind_infos {
+            use super::*;
+            use $crate::catalogue::KindInfo;
+
+            $(pub(crate) const $kind: KindInfo = KindInfo {
+                name: stringify!($kind),
+                description: $description,
+                fields: $crate::catalogue::event_kinds!(
+                    @fields $({ $($field: $type => $field_description),* })? $(($inner))?
+                ),
+                typical_rate: $typical_rate,
+                probes: &[$($probe),*],
+                labels: $crate::catalogue::event_kinds!(@labels $($labels)?),
+            };)*
+        }
+
+        /// See [`catalogue::CATALOGUE`].
+        const KINDS: &[&catalogue::KindInfo] = &[$(&kind_infos::$kind),*];
+
+        impl EventKind {
+            /// Description of the event kind, see [`catalogue`].
+            pub fn info(&self) -> &'static catalogue::KindInfo {
+                match self {
+                    $(Self::$kind { .. } => &kind_infos::$kind,)*
+                }
+            }
+        }
+    };
+}
+pub(crate) use event_kinds;
+
+/// Define a struct carried by an event kind together with the descriptions of its fields, see
+/// [`event_kinds!`]. Fields are followed by `=> "<description>"`, which is also their doc comment.
+macro_rules! described_struct {
+    (
+        $(#[$meta:meta])*
+        pub struct $name:ident {
+            $($field:ident: $type:ty => $field_description:literal),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        pub struct $name {
+            $(#[doc = $field_description] pub $field: $type),*
+        }
+
+        impl $name {
+            /// Descriptions of the fields, see [`catalogue`].
+            pub const FIELDS: &'static [$crate::catalogue::FieldInfo] = &[$(
+                $crate::catalogue::FieldInfo {
+                    name: stringify!($field),
+                    type_name: stringify!($type),
+                    description: $field_description,
+                }
+            ),*];
+        }
+    };
+}
+pub(crate) use described_struct;
+
+/// Define an enum of unit variants whose names are labels of events in the sound configuration,
+/// see [`KindInfo::labels`].
+macro_rules! labels {
+    (
+        $(#[$meta:meta])*
+        pub enum $name:ident {
+            $($variant:ident),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        pub enum $name {
+            $($variant),*
+        }
+
+        impl $name {
+            /// Names of all variants, see [`catalogue`].
+            pub const NAMES: &'static [&'static str] = &[$(stringify!($variant)),*];
+
+            /// Name of the variant, used as the label of events.
+            pub fn name(&self) -> &'static str {
+                match self {
+                    $(Self::$variant => stringify!($variant)),*
+                }
+            }
+        }
+    };
+}
+pub(crate) use labels;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{EventKind, LogLevel, LogStats};
+
+    #[test]
+    fn describes_every_kind_once() {
+        let kinds = [
+            EventKind::TestTick,
+            EventKind::StdoutWrite { length: 1 },
+            EventKind::StderrWrite { length: 1 },
+            EventKind::FileSystemRead,
+            EventKind::FileSystemWrite,
+            EventKind::Log { level: LogLevel::Info },
+            EventKind::LogStats(LogStats::default()),
+        ];
+        let infos: Vec<_> = kinds.iter().map(EventKind::info).collect();
+        assert_eq!(infos, CATALOGUE);
+
+        // Fields are described by the names they are serialized with.
+        for kind in &kinds {
+            let json = serde_json::to_value(kind).unwrap();
+            let fields: Vec<_> = match json.as_object().and_then(|kind| kind.values().next()) {
+                Some(serde_json::Value::Object(fields)) => fields.keys().cloned().collect(),
+                _ => Vec::new(),
+            };
+            let mut described: Vec<_> = kind.info().fields.iter().map(|field| field.name).collect();
+            described.sort_unstable();
+            assert_eq!(fields, described, "{kind:?}");
+        }
+
+        assert_eq!(find("StdoutWrite").map(|info| info.typical_rate), Some(100.0));
+        assert_eq!(find("Log.Error"), None);
+        assert_eq!(find("Log").unwrap().labels, ["Error", "Warn", "Info", "Debug", "Trace"]);
+        assert_eq!(LogLevel::Warn.name(), "Warn");
+        assert!(find("TestTick").unwrap().labels.is_empty());
+        let json = serde_json::to_string(find("Log").unwrap()).unwrap();
+        assert!(json.contains(r#""fields":[{"name":"level","type":"LogLevel""#), "{json}");
+    }
+}
@@ -0,0 +1,206 @@
+//! Compact representation of [`Packet`]s for high-rate probes.
+//!
+//! Timestamps of events in a packet are typically monotonic and close to each other, so instead of
+//! a full `Option<Duration>` per event we store the first timestamp as a base and then only
+//! nanosecond differences to the previous timestamped event. Everything is serialized using
+//! bincode with variable-length integers, so small deltas and enum tags take a byte or two. The
+//! result can be optionally compressed.
+
+use crate::{is_valid_weight, Event, EventKind, Packet};
+use bincode::Options;
+use eyre::{bail, ensure, Result};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Upper bound for the decompressed size of a packet, protects against decompression bombs.
+const MAX_DECOMPRESSED_SIZE: usize = 1 << 20;
+
+/// Payload compression of [`crate::Encoding::Compact`] packets.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    Lz4,
+    Zstd,
+}
+
+impl Compression {
+    fn to_byte(self) -> u8 {
+        match self {
+            Self::None => 0,
+            Self::Lz4 => 1,
+            Self::Zstd => 2,
+        }
+    }
+
+    pub(crate) fn from_byte(byte: u8) -> Result<Self> {
+        match byte {
+            0 => Ok(Self::None),
+            1 => Ok(Self::Lz4),
+            2 => Ok(Self::Zstd),
+            _ => bail!("unknown compression {byte:#04x}"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct CompactPacket {
+    /// Timestamp of the first event that has one.
+    base_timestamp: Option<Duration>,
+    events: Vec<CompactEvent>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CompactEvent {
+    kind: EventKind,
+    /// Nanoseconds since the previous timestamped event (or the base timestamp). Signed so that
+    /// slightly out-of-order events are still representable.
+    timestamp_delta: Option<i64>,
+    /// The weight, `None` for the usual 1 so that it takes a single byte.
+    weight: Option<f32>,
+}
+
+impl From<&Packet> for CompactPacket {
+    fn from(packet: &Packet) -> Self {
+        let base_timestamp = packet.events.iter().find_map(|event| event.timestamp);
+        let mut previous = base_timestamp.unwrap_or_default();
+        let events = packet
+            .events
+            .iter()
+            .map(|event| {
+                let timestamp_delta = event.timestamp.map(|timestamp| {
+                    let delta = timestamp.as_nanos() as i128 - previous.as_nanos() as i128;
+                    previous = timestamp;
+                    delta as i64
+                });
+                let weight = (event.weight != 1.0).then_some(event.weight);
+                CompactEvent { kind: event.kind.clone(), timestamp_delta, weight }
+            })
+            .collect();
+
+        Self { base_timestamp, events }
+    }
+}
+
+impl TryFrom<CompactPacket> for Packet {
+    type Error = eyre::Report;
+
+    fn try_from(compact: CompactPacket) -> Result<Self> {
+        let mut previous = compact.base_timestamp.unwrap_or_default();
+        let events = compact
+            .events
+            .into_iter()
+            .map(|CompactEvent { kind, timestamp_delta, weight }| {
+                let timestamp = match timestamp_delta {
+                    Some(delta) => {
+                        let nanos = previous.as_nanos() as i128 + delta as i128;
+                        ensure!(nanos >= 0, "timestamp delta points before UNIX epoch");
+                        previous = Duration::new(
+                            (nanos / 1_000_000_000) as u64,
+                            (nanos % 1_000_000_000) as u32,
+                        );
+                        Some(previous)
+                    },
+                    None => None,
+                };
+                let weight = weight.unwrap_or(1.0);
+                ensure!(is_valid_weight(weight), "invalid event weight {weight}");
+                Ok(Event { kind, timestamp, weight })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Packet::new(events))
+    }
+}
+
+fn bincode_options() -> impl Options {
+    bincode::DefaultOptions::new().with_varint_encoding().with_limit(MAX_DECOMPRESSED_SIZE as u64)
+}
+
+/// Serialize `packet` into compact representation, prefixed by the compression byte.
+pub(crate) fn encode(packet: &Packet, compression: Compression, data: &mut Vec<u8>) -> Result<()> {
+    let body = bincode_options().serialize(&CompactPacket::from(packet))?;
+
+    data.push(compression.to_byte());
+    match compression {
+        Compression::None => data.extend(body),
+        Compression::Lz4 => data.extend(lz4_flex::compress_prepend_size(&body)),
+        Compression::Zstd => data.extend(zstd::bulk::compress(&body, 0)?),
+    }
+    Ok(())
+}
+
+/// Inverse of [`encode()`].
+pub(crate) fn decode(data: &[u8]) -> Result<Packet> {
+    let Some((&compression, payload)) = data.split_first() else {
+        bail!("missing compression byte");
+    };
+
+    let body = match Compression::from_byte(compression)? {
+        Compression::None => payload.to_vec(),
+        Compression::Lz4 => {
+            let size = payload.get(..4).map(|size| u32::from_le_bytes(size.try_into().unwrap()));
+            ensure!(
+                size.is_some_and(|size| size as usize <= MAX_DECOMPRESSED_SIZE),
+                "invalid LZ4 payload size"
+            );
+            lz4_flex::decompress_size_prepended(payload)?
+        },
+        Compression::Zstd => zstd::bulk::decompress(payload, MAX_DECOMPRESSED_SIZE)?,
+    };
+
+    let compact: CompactPacket = bincode_options().deserialize(&body)?;
+    compact.try_into()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn dense_packet() -> Packet {
+        let start = Duration::new(1_700_000_000, 123_456_789);
+        let events = (0..100u32)
+            .map(|i| match i % 10 {
+                9 => Event::new(EventKind::FileSystemRead).with_weight(10.0),
+                _ => Event::with_timestamp(
+                    EventKind::StdoutWrite { length: 1 },
+                    start + i * Duration::from_micros(50),
+                ),
+            })
+            .collect();
+        Packet::new(events)
+    }
+
+    #[test]
+    fn roundtrips_with_all_compressions() {
+        for compression in [Compression::None, Compression::Lz4, Compression::Zstd] {
+            let mut data = Vec::new();
+            encode(&dense_packet(), compression, &mut data).unwrap();
+            assert_eq!(decode(&data).unwrap(), dense_packet());
+        }
+    }
+
+    #[test]
+    fn is_much_smaller_than_bincode() {
+        let bincode_size = bincode::serialized_size(&dense_packet()).unwrap() as usize;
+        let mut data = Vec::new();
+        encode(&dense_packet(), Compression::None, &mut data).unwrap();
+        assert!(data.len() * 3 < bincode_size, "{} vs {bincode_size}", data.len());
+    }
+
+    #[test]
+    fn supports_out_of_order_timestamps() {
+        let packet = Packet::new(vec![
+            Event::with_timestamp(EventKind::TestTick, Duration::from_secs(10)),
+            Event::with_timestamp(EventKind::TestTick, Duration::from_secs(9)),
+        ]);
+        let mut data = Vec::new();
+        encode(&packet, Compression::None, &mut data).unwrap();
+        assert_eq!(decode(&data).unwrap(), packet);
+    }
+
+    #[test]
+    fn rejects_oversized_lz4_payload() {
+        assert!(decode(&[1, 0xff, 0xff, 0xff, 0xff, 0]).is_err());
+    }
+}
@@ -0,0 +1,295 @@
+//! Wire encodings of [`Packet`]s.
+//!
+//! The first byte of every datagram selects how the rest of it is encoded:
+//!
+//! - `0x00` followed by a bincode-serialized [`Packet`]. Used by Rust probes by default.
+//! - `0x01` followed by a MessagePack-serialized [`Packet`] with named fields, e.g.
+//!   `msgpack.packb({"events": [{"kind": "TestTick"}]})` in Python.
+//! - `0x02` followed by a [`Compression`] byte and a compact, delta-encoded representation of the
+//!   [`Packet`], see [`Encoding::Compact`].
+//! - `0x03` followed by a signature and an inner datagram in any of the above, see [`crate::auth`].
+//!   [`decode()`] skips the signature without checking it, use [`crate::auth::SharedKey::verify()`]
+//!   first when authentication is required.
+//! - `{`, `[` or whitespace: the whole datagram is JSON. It may contain one or more
+//!   newline-delimited values, each being a [`Packet`] (`{"events": [...]}`), a single [`Event`]
+//!   (`{"kind": "TestTick"}`) or an array of events. So a shell one-liner works too:
+//!   `echo '{"kind":"TestTick"}' | nc -u localhost 8888`.
+//!
+//! On stream transports (TCP, Unix sockets), a connection either carries newline-delimited JSON
+//! (when its first byte is `{`, `[` or whitespace), or datagrams in any of the above encodings,
+//! each prefixed by its length as a big-endian `u32`, see [`frame()`] and [`FrameReader`].
+//!
+//! In the self-describing formats (JSON and MessagePack), event kinds without fields are plain
+//! strings (`"TestTick"`), kinds with fields are single-key objects
+//! (`{"StdoutWrite": {"length": 42}}`), the optional `timestamp` is
+//! `{"secs": ..., "nanos": ...}` since the UNIX epoch and the optional `weight` of sampled events
+//! defaults to 1.
+
+pub use crate::compact::Compression;
+use crate::{auth, compact, Event, Packet};
+use eyre::{bail, ensure, eyre, Result};
+use serde::Deserialize;
+use std::{
+    io::{BufRead, ErrorKind, Read},
+    str::FromStr,
+};
+
+/// Header byte of bincode-encoded datagrams.
+const BINCODE_HEADER: u8 = 0x00;
+/// Header byte of MessagePack-encoded datagrams.
+const MESSAGE_PACK_HEADER: u8 = 0x01;
+/// Header byte of compact datagrams.
+const COMPACT_HEADER: u8 = 0x02;
+
+/// Maximum size of a datagram sent over a stream transport.
+pub const MAX_FRAME_SIZE: usize = 1 << 16;
+
+/// Encoding of a [`Packet`] on the wire.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Encoding {
+    #[default]
+    Bincode,
+    MessagePack,
+    Json,
+    /// Bincode with variable-length integers and timestamps delta-encoded relative to the first
+    /// event in the packet, optionally compressed. Fits many more events into a datagram, so it is
+    /// the best choice for probes producing dense streams of timestamped events.
+    Compact(Compression),
+}
+
+impl Encoding {
+    /// Serialize `packet` into a datagram including the header byte.
+    pub fn encode(self, packet: &Packet) -> Result<Vec<u8>> {
+        let data = match self {
+            Self::Bincode => {
+                let mut data = vec![BINCODE_HEADER];
+                bincode::serialize_into(&mut data, packet)?;
+                data
+            },
+            Self::MessagePack => {
+                let mut data = vec![MESSAGE_PACK_HEADER];
+                rmp_serde::encode::write_named(&mut data, packet)?;
+                data
+            },
+            Self::Json => serde_json::to_vec(packet)?,
+            Self::Compact(compression) => {
+                let mut data = vec![COMPACT_HEADER];
+                compact::encode(packet, compression, &mut data)?;
+                data
+            },
+        };
+        Ok(data)
+    }
+
+    /// Detect the encoding of a datagram from its first byte.
+    pub fn detect(data: &[u8]) -> Result<Self> {
+        match data.first() {
+            Some(&BINCODE_HEADER) => Ok(Self::Bincode),
+            Some(&MESSAGE_PACK_HEADER) => Ok(Self::MessagePack),
+            Some(&COMPACT_HEADER) => {
+                let compression = data.get(1).ok_or_else(|| eyre!("missing compression byte"))?;
+                Ok(Self::Compact(Compression::from_byte(*compression)?))
+            },
+            Some(b'{' | b'[') => Ok(Self::Json),
+            Some(byte) if byte.is_ascii_whitespace() => Ok(Self::Json),
+            Some(byte) => bail!("unknown encoding header byte {byte:#04x}"),
+            None => bail!("empty datagram"),
+        }
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = eyre::Report;
+
+    /// Parse `bincode`, `msgpack`, `json`, `compact`, `compact-lz4` or `compact-zstd`.
+    fn from_str(name: &str) -> Result<Self> {
+        match name {
+            "bincode" => Ok(Self::Bincode),
+            "msgpack" => Ok(Self::MessagePack),
+            "json" => Ok(Self::Json),
+            "compact" => Ok(Self::Compact(Compression::None)),
+            "compact-lz4" => Ok(Self::Compact(Compression::Lz4)),
+            "compact-zstd" => Ok(Self::Compact(Compression::Zstd)),
+            _ => bail!("unknown encoding {name:?}"),
+        }
+    }
+}
+
+/// Decode a datagram produced by [`Encoding::encode()`] or hand-written JSON.
+pub fn decode(data: &[u8]) -> Result<Packet> {
+    let data = match auth::split_signed(data) {
+        Some((_signature, inner)) => inner,
+        None => data,
+    };
+
+    let packet = match Encoding::detect(data)? {
+        Encoding::Bincode => bincode::deserialize(&data[1..])?,
+        Encoding::MessagePack => rmp_serde::from_slice(&data[1..])?,
+        Encoding::Json => decode_json(data)?,
+        Encoding::Compact(_) => compact::decode(&data[1..])?,
+    };
+    Ok(packet)
+}
+
+/// Any of the JSON values we accept, see the module documentation.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JsonMessage {
+    Packet(Packet),
+    Events(Vec<Event>),
+    Event(Event),
+}
+
+fn decode_json(data: &[u8]) -> Result<Packet> {
+    let mut events = Vec::new();
+    for message in serde_json::Deserializer::from_slice(data).into_iter::<JsonMessage>() {
+        match message.map_err(|e| eyre!("invalid JSON message: {e}"))? {
+            JsonMessage::Packet(packet) => events.extend(packet.events),
+            JsonMessage::Events(batch) => events.extend(batch),
+            JsonMessage::Event(event) => events.push(event),
+        }
+    }
+    Ok(Packet::new(events))
+}
+
+/// Prefix `datagram` by its length so that it can be sent over a stream transport.
+pub fn frame(datagram: &[u8]) -> Result<Vec<u8>> {
+    ensure!(datagram.len() <= MAX_FRAME_SIZE, "datagram too large for a frame");
+    let mut framed = Vec::with_capacity(4 + datagram.len());
+    framed.extend((datagram.len() as u32).to_be_bytes());
+    framed.extend(datagram);
+    Ok(framed)
+}
+
+/// Splits a stream transport connection into datagrams that can be passed to [`decode()`].
+pub struct FrameReader<R> {
+    reader: R,
+    /// Whether the connection carries newline-delimited JSON, detected from its first byte.
+    json_lines: Option<bool>,
+}
+
+impl<R: BufRead> FrameReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self { reader, json_lines: None }
+    }
+
+    /// Read the next datagram, returns `None` once the connection is closed.
+    pub fn read_frame(&mut self) -> Result<Option<Vec<u8>>> {
+        let json_lines = match self.json_lines {
+            Some(json_lines) => json_lines,
+            None => {
+                let Some(&first) = self.reader.fill_buf()?.first() else {
+                    return Ok(None);
+                };
+                *self.json_lines.insert(Encoding::detect(&[first])? == Encoding::Json)
+            },
+        };
+
+        if json_lines {
+            let mut line = Vec::new();
+            loop {
+                line.clear();
+                if (&mut self.reader).take(MAX_FRAME_SIZE as u64).read_until(b'\n', &mut line)? == 0
+                {
+                    return Ok(None);
+                }
+                if !line.trim_ascii().is_empty() {
+                    return Ok(Some(line));
+                }
+            }
+        }
+
+        let mut length = [0; 4];
+        match self.reader.read_exact(&mut length) {
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            result => result?,
+        }
+        let length = u32::from_be_bytes(length) as usize;
+        ensure!(length <= MAX_FRAME_SIZE, "frame of {length} bytes is too large");
+
+        let mut datagram = vec![0; length];
+        self.reader.read_exact(&mut datagram)?;
+        Ok(Some(datagram))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::EventKind;
+    use std::time::Duration;
+
+    fn sample_packet() -> Packet {
+        Packet::new(vec![
+            Event::new(EventKind::TestTick),
+            Event::with_timestamp(EventKind::StdoutWrite { length: 42 }, Duration::from_millis(5))
+                .with_weight(8.0),
+        ])
+    }
+
+    #[test]
+    fn roundtrips_all_encodings() {
+        for encoding in [
+            Encoding::Bincode,
+            Encoding::MessagePack,
+            Encoding::Json,
+            Encoding::Compact(Compression::Zstd),
+        ] {
+            let data = encoding.encode(&sample_packet()).unwrap();
+            assert_eq!(Encoding::detect(&data).unwrap(), encoding);
+            assert_eq!(decode(&data).unwrap(), sample_packet());
+        }
+    }
+
+    #[test]
+    fn decodes_hand_written_json() {
+        let packet = decode(b"{\"kind\":\"TestTick\"}\n").unwrap();
+        assert_eq!(packet, Packet::from_event(Event::new(EventKind::TestTick)));
+
+        let data = b"{\"events\": [{\"kind\": {\"StderrWrite\": {\"length\": 1}}}]}\n\
+            [{\"kind\": \"FileSystemRead\", \"timestamp\": {\"secs\": 1, \"nanos\": 0}, \
+            \"weight\": 100}]\n";
+        let packet = decode(data).unwrap();
+        assert_eq!(packet.events.len(), 2);
+        assert_eq!(packet.events[0].weight, 1.0);
+        assert_eq!(packet.events[1].timestamp, Some(Duration::from_secs(1)));
+        assert_eq!(packet.events[1].weight, 100.0);
+    }
+
+    #[test]
+    fn rejects_invalid_weights() {
+        for weight in [f32::NAN, f32::INFINITY, 0.0, -1.0] {
+            let packet = Packet::from_event(Event::new(EventKind::TestTick).with_weight(weight));
+            for encoding in
+                [Encoding::Bincode, Encoding::MessagePack, Encoding::Compact(Compression::None)]
+            {
+                let data = encoding.encode(&packet).unwrap();
+                assert!(decode(&data).is_err(), "{encoding:?} {weight}");
+            }
+        }
+        assert!(decode(b"{\"kind\": \"TestTick\", \"weight\": -2.5}").is_err());
+    }
+
+    #[test]
+    fn reads_framed_and_json_lines_streams() {
+        let mut stream = Vec::new();
+        for encoding in [Encoding::Bincode, Encoding::Compact(Compression::Lz4)] {
+            stream.extend(frame(&encoding.encode(&sample_packet()).unwrap()).unwrap());
+        }
+        let mut reader = FrameReader::new(&stream[..]);
+        assert_eq!(decode(&reader.read_frame().unwrap().unwrap()).unwrap(), sample_packet());
+        assert_eq!(decode(&reader.read_frame().unwrap().unwrap()).unwrap(), sample_packet());
+        assert_eq!(reader.read_frame().unwrap(), None);
+
+        let mut reader = FrameReader::new(&b"{\"kind\":\"TestTick\"}\n\n[]\n"[..]);
+        assert_eq!(reader.read_frame().unwrap().unwrap(), b"{\"kind\":\"TestTick\"}\n");
+        assert_eq!(reader.read_frame().unwrap().unwrap(), b"[]\n");
+        assert_eq!(reader.read_frame().unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_unknown_header() {
+        assert!(decode(&[0x42, 0x00]).is_err());
+        assert!(decode(&[]).is_err());
+    }
+}
@@ -1,7 +1,9 @@
 #![warn(clippy::all, clippy::clone_on_ref_ptr)]
 
+use auth::SharedKey;
+pub use encoding::Encoding;
 use eyre::{eyre, Result};
-use serde::{Deserialize, Serialize};
+use serde::{de, Deserialize, Deserializer, Serialize};
 use std::{
     net::{
         SocketAddr::{V4, V6},
@@ -10,12 +12,20 @@ use std::{
     time::{Duration, UNIX_EPOCH},
 };
 
+pub mod auth;
+pub mod capture;
+pub mod catalogue;
+mod compact;
+pub mod encoding;
+pub mod sampling;
 pub mod util;
 
 pub const DEFAULT_SERVER_ADDRESS: &str = "localhost:8888";
+/// Default address of the composer control channel, see `composer-ctl`.
+pub const DEFAULT_CONTROL_ADDRESS: &str = "localhost:8890";
 
 /// Composer expects `Packet` as the incoming probe data.
-#[derive(Serialize, Deserialize, Default, Debug)]
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
 pub struct Packet {
     /// List of events a probe collected during a specific time window. For a probe generating
     /// high-frequency events e.g. more than a hundred per second, it's recommended to buffer and
@@ -35,82 +45,141 @@ impl Packet {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Event {
     /// Type of the event.
     pub kind: EventKind,
 
     /// Optional timestamp of the event, as the duration since UNIX epoch.
+    #[serde(default)]
     pub timestamp: Option<Duration>,
+
+    /// Number of events this one stands for. 1 unless the probe sends only a sample of its
+    /// events, see [`sampling`]. Must be finite and positive, events with other weights fail to
+    /// decode.
+    #[serde(default = "default_weight", deserialize_with = "deserialize_weight")]
+    pub weight: f32,
+}
+
+fn default_weight() -> f32 {
+    1.0
+}
+
+/// Whether `weight` is usable as [`Event::weight`]. Others would make the gain of sounds NaN or
+/// infinite.
+pub(crate) fn is_valid_weight(weight: f32) -> bool {
+    weight.is_finite() && weight > 0.0
+}
+
+fn deserialize_weight<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f32, D::Error> {
+    let weight = f32::deserialize(deserializer)?;
+    match is_valid_weight(weight) {
+        true => Ok(weight),
+        false => Err(de::Error::custom(format!("invalid event weight {weight}"))),
+    }
 }
 
 impl Event {
     pub fn new(kind: EventKind) -> Self {
         let timestamp = None;
-        Self { kind, timestamp }
+        Self { kind, timestamp, weight: default_weight() }
     }
 
     pub fn with_current_timestamp(kind: EventKind) -> Self {
         let timestamp = Some(UNIX_EPOCH.elapsed().expect("Failed to calculate timestamp"));
-        Self { kind, timestamp }
+        Self { kind, timestamp, weight: default_weight() }
     }
 
     pub fn with_timestamp(kind: EventKind, timestamp: Duration) -> Self {
         let timestamp = Some(timestamp);
-        Self { kind, timestamp }
+        Self { kind, timestamp, weight: default_weight() }
+    }
+
+    /// Set the number of events this one stands for, should be finite and positive.
+    pub fn with_weight(self, weight: f32) -> Self {
+        Self { weight, ..self }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub enum EventKind {
-    TestTick,
-    /// A write() syscall invocation to stdout.
-    StdoutWrite {
-        length: usize,
+catalogue::event_kinds! {
+    TestTick => {
+        description: "A generic tick without any data, sent by test scenarios and by probes whose \
+            events have no dedicated kind yet, like captured network packets.",
+        typical_rate: 1000.0,
+        probes: ["test_probe", "pcap_probe"],
+    },
+    StdoutWrite { length: usize => "Number of bytes written." } => {
+        description: "A write() syscall invocation to stdout.",
+        typical_rate: 100.0,
+        probes: ["ptrace_probe", "dtrace_probe"],
+    },
+    StderrWrite { length: usize => "Number of bytes written." } => {
+        description: "A write() syscall invocation to stderr.",
+        typical_rate: 10.0,
+        probes: ["ptrace_probe", "dtrace_probe"],
     },
-    /// A write() syscall invocation to stderr.
-    StderrWrite {
-        length: usize,
+    FileSystemRead => {
+        description: "A read() syscall invocation.",
+        typical_rate: 1000.0,
+        probes: ["dtrace_probe"],
     },
-    /// A read() syscall invocation.
-    FileSystemRead,
-    /// A write() syscall invocation to a file other than stdout/stderr.
-    FileSystemWrite,
-    /// A log() invocation at a specified severity level.
-    Log {
-        level: LogLevel,
+    FileSystemWrite => {
+        description: "A write() syscall invocation to a file other than stdout/stderr.",
+        typical_rate: 100.0,
+        probes: ["dtrace_probe"],
     },
-    /// Logging events for a specific duration.
-    LogStats(LogStats),
+    Log { level: LogLevel => "One of Error, Warn, Info, Debug or Trace." } => {
+        description: "A log() invocation at a specified severity level, labelled by the level \
+            (e.g. `Log.Error`) in the sound configuration.",
+        typical_rate: 100.0,
+        probes: ["log_probe"],
+        labels: LogLevel::NAMES,
+    },
+    LogStats(LogStats) => {
+        description: "Numbers of log records of each level during a reporting period, sent \
+            instead of individual Log events by log_probe in the aggregated mode.",
+        typical_rate: 1.0,
+        probes: ["log_probe"],
+    },
+}
+
+impl EventKind {
+    /// Name of the event kind, without any of its fields.
+    pub fn name(&self) -> &'static str {
+        self.info().name
+    }
 }
 
 // FIXME: Duplicates the `log` crate definitions, but it's likely
 // still better than pulling the dependency.
-#[derive(Serialize, Deserialize, Debug)]
-pub enum LogLevel {
-    Error,
-    Warn,
-    Info,
-    Debug,
-    Trace,
+catalogue::labels! {
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub enum LogLevel {
+        Error,
+        Warn,
+        Info,
+        Debug,
+        Trace,
+    }
 }
 
-/// Logs are aggregated by type (better for very high frequency logging)
-#[derive(Serialize, Deserialize, Debug, Default)]
-pub struct LogStats {
-    // Duration covered by this report.
-    pub span: Duration,
-
-    // Number of records of each kind.
-    pub error_records: u32,
-    pub warn_records: u32,
-    pub info_records: u32,
-    pub debug_records: u32,
-    pub trace_records: u32,
+catalogue::described_struct! {
+    /// Logs are aggregated by type (better for very high frequency logging)
+    #[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+    pub struct LogStats {
+        span: Duration => "Duration covered by this report.",
+        error_records: u32 => "Number of Error records.",
+        warn_records: u32 => "Number of Warn records.",
+        info_records: u32 => "Number of Info records.",
+        debug_records: u32 => "Number of Debug records.",
+        trace_records: u32 => "Number of Trace records.",
+    }
 }
 
 pub struct Client {
     socket: UdpSocket,
+    encoding: Encoding,
+    key: Option<SharedKey>,
 }
 
 impl Client {
@@ -122,11 +191,24 @@ impl Client {
         let socket = UdpSocket::bind(Self::get_local_address(&server_address)?)?;
         socket.connect(server_address)?;
 
-        Ok(Self { socket })
+        Ok(Self { socket, encoding: Encoding::default(), key: None })
+    }
+
+    /// Use given wire encoding for sent packets instead of the default bincode.
+    pub fn with_encoding(self, encoding: Encoding) -> Self {
+        Self { encoding, ..self }
+    }
+
+    /// Sign sent packets using given key, for composers that only accept authenticated packets.
+    pub fn with_key(self, key: SharedKey) -> Self {
+        Self { key: Some(key), ..self }
     }
 
     pub fn send(&self, packet: &Packet) -> Result<()> {
-        let data = bincode::serialize(packet)?;
+        let mut data = self.encoding.encode(packet)?;
+        if let Some(key) = &self.key {
+            data = key.sign(&data);
+        }
         self.socket.send(&data)?;
         Ok(())
     }
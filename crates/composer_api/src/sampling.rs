@@ -0,0 +1,291 @@
+//! Probe-side sampling, for probes so hot (every syscall, every network packet) that even batching
+//! their events into packets is too much.
+//!
+//! A [`Sampler`] keeps only some of the events offered to it and multiplies the
+//! [`Event::weight`] of each kept one by the number of events it stands for. The weights of kept
+//! events add up to the weights of all offered ones (exactly or on average, depending on the
+//! strategy), so the composer can still make the sound as dense as the true activity.
+
+use crate::{util::Rng, Event};
+use eyre::{bail, Context, Result};
+use std::{str::FromStr, time::Duration};
+
+/// Keeps a part of a stream of events.
+pub trait Sampler: Send {
+    /// Offer an event that happened at `now` (as the duration since UNIX epoch), push the events
+    /// to send into `out`.
+    fn offer(&mut self, now: Duration, event: Event, out: &mut Vec<Event>);
+
+    /// Push events that were held back until `now` into `out`, should be called regularly.
+    fn flush(&mut self, now: Duration, out: &mut Vec<Event>);
+}
+
+/// Sampling strategy, parsed from a command-line argument by probes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Sampling {
+    /// Keep every n-th event, see [`OneIn`].
+    OneIn(u32),
+    /// Keep a random sample of at most `size` events per `interval`, see [`Reservoir`].
+    Reservoir { size: usize, interval: Duration },
+    /// Keep about `events_per_second` events, see [`Adaptive`].
+    Adaptive { events_per_second: f64 },
+}
+
+impl Sampling {
+    pub fn sampler(self) -> Box<dyn Sampler> {
+        match self {
+            Self::OneIn(n) => Box::new(OneIn::new(n)),
+            Self::Reservoir { size, interval } => Box::new(Reservoir::new(size, interval)),
+            Self::Adaptive { events_per_second } => Box::new(Adaptive::new(events_per_second)),
+        }
+    }
+}
+
+impl FromStr for Sampling {
+    type Err = eyre::Report;
+
+    /// Parse `one-in:<n>`, `reservoir:<size>:<interval ms>` or `adaptive:<events per second>`.
+    fn from_str(spec: &str) -> Result<Self> {
+        fn parse<T: FromStr>(spec: &str, value: &str) -> Result<T>
+        where
+            T::Err: std::error::Error + Send + Sync + 'static,
+        {
+            value.parse().with_context(|| format!("invalid number {value:?} in {spec:?}"))
+        }
+
+        let sampling = match spec.split(':').collect::<Vec<_>>()[..] {
+            ["one-in", n] => Self::OneIn(parse(spec, n)?),
+            ["reservoir", size, interval_ms] => Self::Reservoir {
+                size: parse(spec, size)?,
+                interval: Duration::from_millis(parse(spec, interval_ms)?),
+            },
+            ["adaptive", events_per_second] => {
+                Self::Adaptive { events_per_second: parse(spec, events_per_second)? }
+            },
+            _ => bail!("unknown sampling {spec:?}"),
+        };
+        match sampling {
+            Self::OneIn(0) | Self::Reservoir { size: 0, .. } => bail!("{spec:?} keeps no events"),
+            Self::Reservoir { interval, .. } if interval.is_zero() => {
+                bail!("{spec:?} needs a positive interval")
+            },
+            Self::Adaptive { events_per_second }
+                if !(events_per_second.is_finite() && events_per_second > 0.0) =>
+            {
+                bail!("{spec:?} needs a positive finite rate")
+            },
+            sampling => Ok(sampling),
+        }
+    }
+}
+
+/// Keeps every n-th event, which stands for the n events up to the next kept one. Cheapest, but
+/// periodic activity in step with `n` is kept all or nothing.
+pub struct OneIn {
+    n: u32,
+    /// Events offered since the last kept one.
+    skipped: u32,
+}
+
+impl OneIn {
+    pub fn new(n: u32) -> Self {
+        assert!(n > 0, "n must be positive");
+        Self { n, skipped: 0 }
+    }
+}
+
+impl Sampler for OneIn {
+    fn offer(&mut self, _now: Duration, event: Event, out: &mut Vec<Event>) {
+        if self.skipped == 0 {
+            out.push(Event { weight: event.weight * self.n as f32, ..event });
+        }
+        self.skipped = (self.skipped + 1) % self.n;
+    }
+
+    fn flush(&mut self, _now: Duration, _out: &mut Vec<Event>) {}
+}
+
+/// Keeps a uniformly random sample of at most `size` events of each `interval` (reservoir
+/// sampling). Kept events are sent in their original order once the interval ends, each standing
+/// for an equal share of all events of the interval.
+pub struct Reservoir {
+    size: usize,
+    interval: Duration,
+    /// Index of the current interval since UNIX epoch.
+    index: u128,
+    /// Number of events offered in the current interval.
+    seen: u64,
+    /// Kept events with their position in the interval.
+    kept: Vec<(u64, Event)>,
+    random: Rng,
+}
+
+impl Reservoir {
+    pub fn new(size: usize, interval: Duration) -> Self {
+        assert!(size > 0 && !interval.is_zero(), "size and interval must be positive");
+        // Not preallocated, `size` is only an upper bound that may be far from being reached.
+        let kept = Vec::new();
+        Self { size, interval, index: 0, seen: 0, kept, random: Rng::new(size as u64) }
+    }
+
+    fn close(&mut self, out: &mut Vec<Event>) {
+        let share = self.seen as f32 / self.kept.len().max(1) as f32;
+        self.kept.sort_unstable_by_key(|(position, _)| *position);
+        out.extend(
+            self.kept.drain(..).map(|(_, event)| Event { weight: event.weight * share, ..event }),
+        );
+        self.seen = 0;
+    }
+}
+
+impl Sampler for Reservoir {
+    fn offer(&mut self, now: Duration, event: Event, out: &mut Vec<Event>) {
+        let index = now.as_nanos() / self.interval.as_nanos();
+        if index != self.index {
+            self.close(out);
+            self.index = index;
+        }
+
+        // Algorithm R: the n-th event replaces a random kept one with probability size / n.
+        self.seen += 1;
+        if self.kept.len() < self.size {
+            self.kept.push((self.seen, event));
+        } else {
+            let slot = self.random.next_u64() % self.seen;
+            if let Some(kept) = self.kept.get_mut(slot as usize) {
+                *kept = (self.seen, event);
+            }
+        }
+    }
+
+    fn flush(&mut self, now: Duration, out: &mut Vec<Event>) {
+        if now.as_nanos() / self.interval.as_nanos() != self.index {
+            self.close(out);
+        }
+    }
+}
+
+/// Keeps about `events_per_second` events however many are offered. The rate of offered events
+/// is measured over short intervals and each interval keeps events with the probability that
+/// would have kept the target rate in the previous one. Kept events stand for one event divided
+/// by that probability, so the weights add up to the true count on average.
+pub struct Adaptive {
+    events_per_second: f64,
+    /// Index of the current interval since UNIX epoch.
+    index: u128,
+    /// Number of events offered in the current interval.
+    seen: u64,
+    probability: f64,
+    /// Probabilities of offered events accumulated since the last kept one. Keeping an event
+    /// whenever it reaches 1 spreads kept events evenly rather than randomly.
+    credit: f64,
+}
+
+impl Adaptive {
+    const INTERVAL: Duration = Duration::from_millis(100);
+
+    pub fn new(events_per_second: f64) -> Self {
+        assert!(events_per_second > 0.0, "rate must be positive");
+        Self { events_per_second, index: 0, seen: 0, probability: 1.0, credit: 0.0 }
+    }
+}
+
+impl Sampler for Adaptive {
+    fn offer(&mut self, now: Duration, event: Event, out: &mut Vec<Event>) {
+        let index = now.as_nanos() / Self::INTERVAL.as_nanos();
+        if index != self.index {
+            // After a quiet interval, start over keeping everything.
+            let target = self.events_per_second * Self::INTERVAL.as_secs_f64();
+            self.probability = match index == self.index + 1 {
+                true => (target / self.seen.max(1) as f64).min(1.0),
+                false => 1.0,
+            };
+            self.index = index;
+            self.seen = 0;
+        }
+
+        self.seen += 1;
+        self.credit += self.probability;
+        if self.credit >= 1.0 {
+            self.credit -= 1.0;
+            out.push(Event { weight: (event.weight as f64 / self.probability) as f32, ..event });
+        }
+    }
+
+    fn flush(&mut self, _now: Duration, _out: &mut Vec<Event>) {}
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::EventKind;
+
+    /// Offer 10 000 events evenly spread over a second, return the kept ones.
+    fn sample(sampling: &str) -> Vec<Event> {
+        let mut sampler = sampling.parse::<Sampling>().unwrap().sampler();
+        let start = Duration::from_secs(1000);
+        let mut out = Vec::new();
+        for i in 0..10_000 {
+            let now = start + i * Duration::from_micros(100);
+            sampler.offer(now, Event::with_timestamp(EventKind::TestTick, now), &mut out);
+        }
+        sampler.flush(start + Duration::from_secs(2), &mut out);
+        out
+    }
+
+    fn total_weight(events: &[Event]) -> f32 {
+        events.iter().map(|event| event.weight).sum()
+    }
+
+    #[test]
+    fn weights_add_up_to_offered_events() {
+        let kept = sample("one-in:100");
+        assert_eq!(kept.len(), 100);
+        assert_eq!(total_weight(&kept), 10_000.0);
+
+        let kept = sample("reservoir:50:100");
+        assert_eq!(kept.len(), 10 * 50);
+        assert_eq!(total_weight(&kept), 10_000.0);
+        assert!(kept.windows(2).all(|pair| pair[0].timestamp < pair[1].timestamp));
+
+        // The first interval of 100 ms keeps all of its 1000 events until the rate is known, the
+        // other 9 keep about 100 each.
+        let kept = sample("adaptive:1000");
+        assert!((1890..=1910).contains(&kept.len()), "{}", kept.len());
+        assert!((total_weight(&kept) - 10_000.0).abs() < 100.0, "{}", total_weight(&kept));
+
+        assert!("one-in:0".parse::<Sampling>().is_err());
+        assert!("reservoir:10".parse::<Sampling>().is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_numbers() {
+        assert_eq!(
+            "reservoir:10:1500".parse::<Sampling>().unwrap(),
+            Sampling::Reservoir { size: 10, interval: Duration::from_millis(1500) }
+        );
+        for spec in [
+            "one-in:2.7",
+            "one-in:-1",
+            "one-in:1e30",
+            "reservoir:10:inf",
+            "reservoir:10:1e300",
+            "reservoir:10:0",
+            "reservoir:1e30:1000",
+            "reservoir:-5:1000",
+            "adaptive:nan",
+            "adaptive:inf",
+            "adaptive:0",
+        ] {
+            assert!(spec.parse::<Sampling>().is_err(), "{spec}");
+        }
+
+        // Huge reservoirs are fine as long as few events are offered.
+        let mut sampler =
+            Sampling::Reservoir { size: usize::MAX, interval: Duration::from_secs(1) }.sampler();
+        let mut out = Vec::new();
+        sampler.offer(Duration::ZERO, Event::new(EventKind::TestTick), &mut out);
+        sampler.flush(Duration::from_secs(1), &mut out);
+        assert_eq!(out.len(), 1);
+    }
+}
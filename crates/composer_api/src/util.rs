@@ -4,3 +4,27 @@ use std::time::{Duration, SystemTime, UNIX_EPOCH};
 pub fn current_timestamp() -> Duration {
     SystemTime::now().duration_since(UNIX_EPOCH).expect("Unable to get current UNIX time")
 }
+
+/// SplitMix64, a small and fast pseudo-random generator good enough to pick events to keep or to
+/// jitter sounds. Not for anything security-related.
+#[derive(Debug, Clone)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniformly distributed number from -1.0 to 1.0.
+    pub fn symmetric(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 23) as f32 - 1.0
+    }
+}
@@ -1,5 +1,5 @@
 use clap::Parser;
-use composer_api::{Client, Event, EventKind, Packet};
+use composer_api::{encoding::Compression, Client, Encoding, Event, EventKind, Packet};
 use dtrace::{DTrace, ProgramStatus};
 use eyre::Result;
 use std::{
@@ -38,6 +38,8 @@ fn main() -> Result<()> {
         Some(address) => Client::new(address)?,
         None => Client::try_default()?,
     };
+    // Syscall probes produce dense streams of timestamped events, pack them tightly.
+    let client = client.with_encoding(Encoding::Compact(Compression::Lz4));
 
     let mut dtrace = DTrace::new()?;
 
@@ -3,7 +3,7 @@
 use std::time::Duration;
 
 use clap::{command, Parser};
-use composer_api::{Client, Event, EventKind, Packet};
+use composer_api::{encoding::Compression, Client, Encoding, Event, EventKind, Packet};
 use eyre::{eyre, Context, Result};
 use pcap::Capture;
 
@@ -23,7 +23,8 @@ fn main() -> Result<()> {
     let client = match args.address {
         Some(address) => Client::new(address),
         None => Client::try_default(),
-    }?;
+    }?
+    .with_encoding(Encoding::Compact(Compression::None));
 
     let device = pcap::Device::lookup()
         .context("cal list devices")?
@@ -0,0 +1,34 @@
+//! Event scenarios of the test probe as finite generators of packets, so that they can also be
+//! sent by tests of the composer.
+
+use composer_api::{Event, EventKind, LogLevel, Packet};
+use std::time::Duration;
+
+/// Packets of timestamped events evenly spaced in time from `start`, grouped into bursts of
+/// `events_per_burst` events, one packet per `burst_period`.
+pub fn bursts(
+    start: Duration,
+    burst_period: Duration,
+    events_per_burst: u32,
+) -> impl Iterator<Item = Packet> {
+    let event_period = burst_period / events_per_burst;
+    let mut events =
+        (0..).map(move |n| Event::with_timestamp(EventKind::TestTick, start + n * event_period));
+    std::iter::repeat_with(move || {
+        Packet::new(events.by_ref().take(events_per_burst as usize).collect())
+    })
+}
+
+/// A packet with an event of every kind but [EventKind::LogStats] timestamped `timestamp`, with
+/// writes of `write_length` bytes.
+pub fn every_kind(timestamp: Duration, write_length: usize) -> Packet {
+    let kinds = [
+        EventKind::TestTick,
+        EventKind::StdoutWrite { length: write_length },
+        EventKind::StderrWrite { length: write_length },
+        EventKind::FileSystemRead,
+        EventKind::FileSystemWrite,
+        EventKind::Log { level: LogLevel::Error },
+    ];
+    Packet::new(kinds.into_iter().map(|kind| Event::with_timestamp(kind, timestamp)).collect())
+}
@@ -1,13 +1,21 @@
 #![warn(clippy::all, clippy::clone_on_ref_ptr)]
 
 use clap::{Parser, Subcommand};
-use composer_api::{util::current_timestamp, Client, Event, EventKind, Packet};
-use eyre::Result;
+use composer_api::{
+    auth::SharedKey, catalogue, sampling::Sampling, util::current_timestamp, Client, Encoding,
+    Event, EventKind, Packet,
+};
+use eyre::{ensure, eyre, Result};
+use replay::{replay, ReplayOptions};
 use std::{
+    cell::RefCell,
+    path::PathBuf,
     thread::sleep,
     time::{Duration, Instant},
 };
 
+mod replay;
+
 #[derive(Clone, Subcommand, Debug)]
 enum Mode {
     /// Send events regularly with given frequency.
@@ -24,6 +32,32 @@ enum Mode {
         #[arg(short, long, default_value_t = 50)]
         events_per_burst: u32,
     },
+    /// Send an event of every kind regularly, with writes of growing length.
+    EveryKind {
+        /// Frequency of the packets to generate.
+        #[arg(short, long, default_value_t = 2.0)]
+        frequency: f64,
+    },
+    /// Re-send packets from a capture file recorded by the composer with their original timing.
+    Replay {
+        /// Capture file written by `composer --record`.
+        file: PathBuf,
+        /// Speed factor between 0.1 (ten times slower) and 10.
+        #[arg(short, long, default_value_t = 1.0)]
+        speed: f64,
+        /// Start replaying this many seconds into the capture.
+        #[arg(long, default_value = "0", value_parser = parse_seconds)]
+        seek_s: Duration,
+        /// Start over once the end of the capture is reached.
+        #[arg(long = "loop")]
+        repeat: bool,
+        /// Only replay events of this kind, can be given multiple times.
+        #[arg(long, value_parser = parse_kind)]
+        kind: Vec<String>,
+        /// Only replay packets whose source address contains this, can be given multiple times.
+        #[arg(long)]
+        source: Vec<String>,
+    },
 }
 
 #[derive(Parser)]
@@ -36,6 +70,34 @@ struct Args {
     /// Server address to receive events.
     #[arg(short, long)]
     address: Option<String>,
+
+    /// Sign packets with the pre-shared key stored in this file.
+    #[arg(short, long)]
+    key_file: Option<PathBuf>,
+
+    /// Wire encoding: bincode, msgpack, json, compact, compact-lz4 or compact-zstd.
+    #[arg(short, long, default_value = "bincode")]
+    encoding: Encoding,
+
+    /// Send only a weighted sample of the events: one-in:<n>, reservoir:<size>:<interval ms> or
+    /// adaptive:<events per second>.
+    #[arg(short, long)]
+    sampling: Option<Sampling>,
+}
+
+fn parse_seconds(seconds: &str) -> Result<Duration> {
+    let seconds: f64 = seconds.parse()?;
+    Duration::try_from_secs_f64(seconds)
+        .map_err(|_| eyre!("{seconds} s should be a finite number of 0 or more"))
+}
+
+fn parse_kind(name: &str) -> Result<String> {
+    ensure!(
+        catalogue::find(name).is_some(),
+        "unknown event kind {name:?}, expected one of {}",
+        catalogue::names()
+    );
+    Ok(name.to_string())
 }
 
 fn main() -> Result<()> {
@@ -45,10 +107,31 @@ fn main() -> Result<()> {
     let client = match args.address {
         Some(address) => Client::new(address),
         None => Client::try_default(),
-    }?;
+    }?
+    .with_encoding(args.encoding);
+    let client = match args.key_file {
+        Some(key_file) => client.with_key(SharedKey::from_file(key_file)?),
+        None => client,
+    };
 
+    let sampler = args.sampling.map(|sampling| RefCell::new(sampling.sampler()));
     let send = |packet: &Packet| {
-        if let Err(err) = client.send(packet) {
+        let result = match &sampler {
+            Some(sampler) => {
+                let (mut sampler, now) = (sampler.borrow_mut(), current_timestamp());
+                let mut events = Vec::new();
+                for event in &packet.events {
+                    sampler.offer(now, event.clone(), &mut events);
+                }
+                sampler.flush(now, &mut events);
+                match events.is_empty() {
+                    true => Ok(()),
+                    false => client.send(&Packet::new(events)),
+                }
+            },
+            None => client.send(packet),
+        };
+        if let Err(err) = result {
             eprintln!("Could not send packet {:?}", err)
         };
     };
@@ -59,6 +142,11 @@ fn main() -> Result<()> {
         Mode::Burst { burst_period_ms, events_per_burst } => {
             burst(Duration::from_millis(burst_period_ms), events_per_burst, send)
         },
+        Mode::EveryKind { frequency } => every_kind(frequency, send),
+        Mode::Replay { file, speed, seek_s, repeat, kind, source } => {
+            let options = ReplayOptions { speed, seek: seek_s, kinds: kind, sources: source };
+            replay(&file, &options, repeat, send)
+        },
     }
 }
 
@@ -93,21 +181,60 @@ fn rollercoaster(send: impl Fn(&Packet)) -> ! {
 }
 
 fn burst(burst_period: Duration, events_per_burst: u32, send: impl Fn(&Packet)) -> ! {
-    let event_period = burst_period / events_per_burst;
     // Delay events' timestamps by one burst period to ensure they're not set in future when sent out.
     let event_start = current_timestamp() - burst_period;
-    let mut event_generator =
-        (0..).map(|n| Event::with_timestamp(EventKind::TestTick, event_start + n * event_period));
+    let mut packets = test_probe::bursts(event_start, burst_period, events_per_burst);
 
     // Prevent drifting away from the given frequency by computing the sleep duration for each cycle.
     let start = Instant::now();
     for deadline in (1..).map(|i| start + i * burst_period) {
-        let events = event_generator.by_ref().take(events_per_burst as usize).collect();
-        let packet = Packet::new(events);
-        send(&packet);
+        send(&packets.next().expect("bursts should be endless"));
+
+        sleep(deadline.saturating_duration_since(Instant::now()));
+    }
+
+    unreachable!()
+}
+
+fn every_kind(frequency: f64, send: impl Fn(&Packet)) -> ! {
+    let start = Instant::now();
+    for i in 1.. {
+        // Write lengths from 1 byte to 64 KiB, then again.
+        let write_length = 1 << (i % 17);
+        send(&test_probe::every_kind(current_timestamp(), write_length));
 
+        let deadline = start + Duration::from_secs_f64(i as f64 / frequency);
         sleep(deadline.saturating_duration_since(Instant::now()));
     }
 
     unreachable!()
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, clap::Error> {
+        Args::try_parse_from([&["test_probe", "replay", "capture.acap"], args].concat())
+    }
+
+    #[test]
+    fn validates_replay_arguments() {
+        let Mode::Replay { seek_s, kind, .. } =
+            parse(&["--seek-s", "1.5", "--kind", "Log"]).unwrap().mode
+        else {
+            panic!("should parse the replay mode");
+        };
+        assert_eq!((seek_s, kind), (Duration::from_millis(1500), vec!["Log".to_string()]));
+
+        for args in [
+            ["--seek-s=-1"],
+            ["--seek-s=nan"],
+            ["--seek-s=inf"],
+            ["--seek-s=1e300"],
+            ["--kind=TestTik"],
+        ] {
+            assert!(parse(&args).is_err(), "{args:?}");
+        }
+    }
+}
@@ -0,0 +1,195 @@
+use composer_api::{capture::CaptureReader, util::current_timestamp, Event, Packet};
+use eyre::{ensure, Context, Result};
+use std::{
+    fs::File,
+    io::BufReader,
+    path::Path,
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+pub(crate) struct ReplayOptions {
+    /// Playback speed factor, 0.5 means twice as slow as recorded.
+    pub(crate) speed: f64,
+    /// Skip this much time from the beginning of the capture.
+    pub(crate) seek: Duration,
+    /// Only replay events of these kinds, or all if empty.
+    pub(crate) kinds: Vec<String>,
+    /// Only replay packets whose source contains one of these, or all if empty.
+    pub(crate) sources: Vec<String>,
+}
+
+impl ReplayOptions {
+    pub(crate) const SPEED_RANGE: std::ops::RangeInclusive<f64> = 0.1..=10.0;
+
+    fn keeps_source(&self, source: &str) -> bool {
+        self.sources.is_empty() || self.sources.iter().any(|filter| source.contains(filter))
+    }
+
+    fn keeps_event(&self, event: &Event) -> bool {
+        self.kinds.is_empty() || self.kinds.iter().any(|kind| kind == event.kind.name())
+    }
+}
+
+/// Re-send packets recorded by the composer in `file` with their original relative timing scaled
+/// by the speed factor. Event timestamps are shifted (and scaled) to the time of replay.
+pub(crate) fn replay(
+    file: &Path,
+    options: &ReplayOptions,
+    repeat: bool,
+    send: impl Fn(&Packet),
+) -> Result<()> {
+    ensure!(
+        ReplayOptions::SPEED_RANGE.contains(&options.speed),
+        "speed must be within {:?}",
+        ReplayOptions::SPEED_RANGE
+    );
+
+    loop {
+        let sent = replay_once(file, options, &send)?;
+        println!("Replayed {sent} packets from {file:?}.");
+        if !repeat || sent == 0 {
+            return Ok(());
+        }
+    }
+}
+
+/// Returns the number of packets sent.
+fn replay_once(file: &Path, options: &ReplayOptions, send: impl Fn(&Packet)) -> Result<usize> {
+    let reader = File::open(file).with_context(|| format!("opening {file:?}"))?;
+    let reader = CaptureReader::new(BufReader::new(reader))?;
+
+    let start = Instant::now();
+    let start_timestamp = current_timestamp();
+    // Point in the capture that corresponds to the start of the replay.
+    let mut origin = None;
+
+    let mut sent = 0;
+    for record in reader {
+        let record = record?;
+        let origin = *origin.get_or_insert(record.arrival + options.seek);
+        if record.arrival < origin || !options.keeps_source(&record.source) {
+            continue;
+        }
+
+        let events: Vec<_> = record
+            .packet
+            .events
+            .into_iter()
+            .filter(|event| options.keeps_event(event))
+            .map(|event| Event {
+                timestamp: event
+                    .timestamp
+                    .map(|timestamp| rescale(timestamp, origin, start_timestamp, options.speed)),
+                ..event
+            })
+            .collect();
+        if events.is_empty() {
+            continue;
+        }
+
+        let deadline = start + (record.arrival - origin).div_f64(options.speed);
+        sleep(deadline.saturating_duration_since(Instant::now()));
+        send(&Packet::new(events));
+        sent += 1;
+    }
+
+    Ok(sent)
+}
+
+/// Map `timestamp` of the capture to the time of replay, where `origin` in the capture
+/// corresponds to `start` of the replay and time passes `speed` times as fast.
+fn rescale(timestamp: Duration, origin: Duration, start: Duration, speed: f64) -> Duration {
+    if timestamp >= origin {
+        start + (timestamp - origin).div_f64(speed)
+    } else {
+        start.saturating_sub((origin - timestamp).div_f64(speed))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use composer_api::{
+        capture::{CaptureWriter, Record},
+        EventKind,
+    };
+    use std::cell::RefCell;
+
+    fn options(speed: f64) -> ReplayOptions {
+        ReplayOptions { speed, seek: Duration::ZERO, kinds: Vec::new(), sources: Vec::new() }
+    }
+
+    #[test]
+    fn rescales_timestamps() {
+        let (origin, start) = (Duration::from_secs(10), Duration::from_secs(100));
+        let rescale = |millis, speed| rescale(Duration::from_millis(millis), origin, start, speed);
+        assert_eq!(rescale(10_000, 1.0), start);
+        assert_eq!(rescale(10_500, 1.0), Duration::from_millis(100_500));
+        assert_eq!(rescale(10_500, 0.5), Duration::from_secs(101));
+        assert_eq!(rescale(11_000, 2.0), Duration::from_millis(100_500));
+        // Events stamped before they arrived stay before the start of the replay.
+        assert_eq!(rescale(9_800, 2.0), Duration::from_millis(99_900));
+        assert_eq!(rescale(0, 0.1), Duration::ZERO);
+    }
+
+    #[test]
+    fn seeks_filters_and_scales() {
+        let record = |millis, source: &str, kinds: &[EventKind]| Record {
+            arrival: Duration::from_millis(millis),
+            source: source.to_string(),
+            packet: Packet::new(
+                kinds
+                    .iter()
+                    .map(|kind| Event::with_timestamp(kind.clone(), Duration::from_millis(millis)))
+                    .collect(),
+            ),
+        };
+        let path = std::env::temp_dir().join(format!("test_probe-{}.acap", std::process::id()));
+        let mut writer = CaptureWriter::new(File::create(&path).unwrap()).unwrap();
+        for record in [
+            record(10_000, "udp://10.0.0.1:1", &[EventKind::TestTick]),
+            record(10_100, "udp://10.0.0.2:1", &[EventKind::TestTick]),
+            record(10_200, "udp://10.0.0.1:1", &[EventKind::TestTick, EventKind::FileSystemRead]),
+            record(10_300, "udp://10.0.0.1:1", &[EventKind::FileSystemRead]),
+            record(10_600, "udp://10.0.0.1:2", &[EventKind::TestTick]),
+        ] {
+            writer.write(&record).unwrap();
+        }
+        writer.flush().unwrap();
+
+        let options = ReplayOptions {
+            seek: Duration::from_millis(100),
+            kinds: vec!["TestTick".to_string()],
+            sources: vec!["10.0.0.1".to_string()],
+            ..options(2.0)
+        };
+        let sent = RefCell::new(Vec::new());
+        let start = Instant::now();
+        let count = replay_once(&path, &options, |packet: &Packet| {
+            sent.borrow_mut().push((start.elapsed(), packet.clone()))
+        });
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(count.unwrap(), 2);
+
+        let sent = sent.into_inner();
+        let kinds: Vec<_> = sent
+            .iter()
+            .flat_map(|(_, packet)| &packet.events)
+            .map(|event| event.kind.clone())
+            .collect();
+        assert_eq!(kinds, [EventKind::TestTick, EventKind::TestTick]);
+        // 100 ms and 500 ms after the seek point, at twice the speed.
+        assert!(sent[0].0 >= Duration::from_millis(50));
+        assert!(sent[1].0 >= Duration::from_millis(250));
+        let timestamp = |index: usize| sent[index].1.events[0].timestamp.unwrap();
+        assert_eq!(timestamp(1) - timestamp(0), Duration::from_millis(200));
+    }
+
+    #[test]
+    fn rejects_speed_out_of_range() {
+        for speed in [0.0, 0.05, 20.0] {
+            assert!(replay(Path::new("missing.acap"), &options(speed), false, |_| ()).is_err());
+        }
+    }
+}